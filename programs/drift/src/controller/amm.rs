@@ -675,6 +675,26 @@ pub fn update_pool_balances(
     Ok(pnl_to_settle_with_user)
 }
 
+/// Runs update_pool_balances against copies of the markets and returns the amount that would be settled
+pub fn simulate_pool_balances(
+    market: &PerpMarket,
+    spot_market: &SpotMarket,
+    user_quote_position: &SpotPosition,
+    user_unsettled_pnl: i128,
+    now: i64,
+) -> DriftResult<i128> {
+    let mut market = *market;
+    let mut spot_market = *spot_market;
+
+    update_pool_balances(
+        &mut market,
+        &mut spot_market,
+        user_quote_position,
+        user_unsettled_pnl,
+        now,
+    )
+}
+
 pub fn update_pnl_pool_and_user_balance(
     market: &mut PerpMarket,
     bank: &mut SpotMarket,
@@ -406,6 +406,66 @@ fn update_pool_balances_test_high_util_borrow() {
     assert_eq!(to_settle_with_user, -100);
 }
 
+#[test]
+fn simulate_pool_balances_matches_update_pool_balances() {
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 5122950819670000,
+            quote_asset_reserve: 488 * AMM_RESERVE_PRECISION,
+            sqrt_k: 500 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 50000,
+            base_asset_amount_with_amm: -122950819670000,
+            total_fee_minus_distributions: 1000 * QUOTE_PRECISION as i128,
+            curve_update_intensity: 100,
+            ..AMM::default()
+        },
+        ..PerpMarket::default()
+    };
+    let now = 33928058;
+
+    let mut spot_market = SpotMarket {
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        ..SpotMarket::default()
+    };
+    spot_market.deposit_balance = 10_u128.pow(19_u32);
+    spot_market.borrow_balance = 10_u128.pow(19_u32);
+    spot_market.deposit_token_twap = 10_u64.pow(16_u32);
+
+    let mut spot_position = SpotPosition::default();
+    update_spot_balances(
+        50,
+        &SpotBalanceType::Deposit,
+        &mut spot_market,
+        &mut spot_position,
+        false,
+    )
+    .unwrap();
+
+    for unsettled_pnl in [-100, 100, -25] {
+        let market_before = market;
+        let spot_market_before = spot_market;
+
+        let simulated =
+            simulate_pool_balances(&market, &spot_market, &spot_position, unsettled_pnl, now)
+                .unwrap();
+
+        // simulation doesn't touch the markets
+        assert_eq!(market, market_before);
+        assert_eq!(spot_market, spot_market_before);
+
+        let to_settle_with_user = update_pool_balances(
+            &mut market,
+            &mut spot_market,
+            &spot_position,
+            unsettled_pnl,
+            now,
+        )
+        .unwrap();
+        assert_eq!(simulated, to_settle_with_user);
+    }
+}
+
 #[test]
 fn update_pool_balances_test() {
     let mut market = PerpMarket {
@@ -28,6 +28,9 @@ use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::state::OracleGuardRails;
 use crate::state::user::User;
 
+#[cfg(test)]
+mod tests;
+
 pub fn settle_funding_payment(
     user: &mut User,
     user_key: &Pubkey,
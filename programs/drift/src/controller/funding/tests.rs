@@ -0,0 +1,60 @@
+mod settle_funding_payment {
+    use anchor_lang::prelude::Pubkey;
+
+    use crate::controller::funding::settle_funding_payment;
+    use crate::math::constants::{
+        BASE_PRECISION_I64, FUNDING_RATE_PRECISION_I128, FUNDING_RATE_PRECISION_I64,
+        QUOTE_PRECISION_I64,
+    };
+    use crate::state::perp_market::{PerpMarket, AMM};
+    use crate::state::user::{PerpPosition, User};
+    use crate::test_utils::get_positions;
+
+    #[test]
+    fn repeated_settlement_is_noop() {
+        let mut market = PerpMarket {
+            amm: AMM {
+                cumulative_funding_rate_long: 1010 * FUNDING_RATE_PRECISION_I128,
+                cumulative_funding_rate_short: -1010 * FUNDING_RATE_PRECISION_I128,
+                ..AMM::default()
+            },
+            ..PerpMarket::default()
+        };
+
+        let mut user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: 5 * BASE_PRECISION_I64,
+                quote_asset_amount: -500 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -500 * QUOTE_PRECISION_I64,
+                last_cumulative_funding_rate: 1000 * FUNDING_RATE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            ..User::default()
+        };
+
+        settle_funding_payment(&mut user, &Pubkey::default(), &mut market, 0).unwrap();
+
+        assert_eq!(
+            user.perp_positions[0].quote_asset_amount,
+            -550 * QUOTE_PRECISION_I64
+        );
+        assert_eq!(
+            user.perp_positions[0].last_cumulative_funding_rate,
+            1010 * FUNDING_RATE_PRECISION_I64
+        );
+        assert_eq!(user.cumulative_perp_funding, -50 * QUOTE_PRECISION_I64);
+
+        let user_after_first = user;
+        let market_after_first = market;
+
+        // same funding period, e.g. the market is included twice in one transaction
+        settle_funding_payment(&mut user, &Pubkey::default(), &mut market, 0).unwrap();
+
+        assert_eq!(user, user_after_first);
+        assert_eq!(
+            market.amm.net_unsettled_funding_pnl,
+            market_after_first.amm.net_unsettled_funding_pnl
+        );
+    }
+}
@@ -134,6 +134,7 @@ pub fn liquidate_perp(
         spot_market_map,
         oracle_map,
         MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .emit_oracle_invalid_records(*user_key, now)
             .track_market_margin_requirement(MarketIdentifier::perp(market_index))?,
     )?;
 
@@ -145,6 +146,8 @@ pub fn liquidate_perp(
         return Ok(());
     }
 
+    margin_calculation.emit_record(user_key, now);
+
     user.get_perp_position(market_index).map_err(|e| {
         msg!(
             "User does not have a position for perp market {}",
@@ -833,6 +836,7 @@ pub fn liquidate_spot(
         spot_market_map,
         oracle_map,
         MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .emit_oracle_invalid_records(*user_key, now)
             .track_market_margin_requirement(MarketIdentifier::spot(liability_market_index))?,
     )?;
 
@@ -844,6 +848,8 @@ pub fn liquidate_spot(
         return Ok(());
     }
 
+    margin_calculation.emit_record(user_key, now);
+
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
@@ -1367,7 +1373,8 @@ pub fn liquidate_borrow_for_perp_pnl(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio),
+        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .emit_oracle_invalid_records(*user_key, now),
     )?;
 
     if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
@@ -1378,6 +1385,8 @@ pub fn liquidate_borrow_for_perp_pnl(
         return Ok(());
     }
 
+    margin_calculation.emit_record(user_key, now);
+
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
@@ -1843,7 +1852,8 @@ pub fn liquidate_perp_pnl_for_deposit(
         perp_market_map,
         spot_market_map,
         oracle_map,
-        MarginContext::liquidation(liquidation_margin_buffer_ratio),
+        MarginContext::liquidation(liquidation_margin_buffer_ratio)
+            .emit_oracle_invalid_records(*user_key, now),
     )?;
 
     if !user.is_being_liquidated() && margin_calculation.meets_margin_requirement() {
@@ -1854,6 +1864,8 @@ pub fn liquidate_perp_pnl_for_deposit(
         return Ok(());
     }
 
+    margin_calculation.emit_record(user_key, now);
+
     let liquidation_id = user.enter_liquidation(slot)?;
     let mut margin_freed = 0_u64;
 
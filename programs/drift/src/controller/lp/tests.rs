@@ -1,5 +1,5 @@
 use crate::controller::lp::*;
-use crate::controller::pnl::settle_pnl;
+use crate::controller::pnl::{settle_pnl, SettlePnlOptions};
 use crate::state::perp_market::AMM;
 use crate::state::user::PerpPosition;
 use crate::BASE_PRECISION_I64;
@@ -573,6 +573,7 @@ pub fn test_lp_settle_pnl() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     );
 
     assert_eq!(result, Ok(()));
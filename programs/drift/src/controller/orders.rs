@@ -11,7 +11,7 @@ use crate::controller::funding::settle_funding_payment;
 use crate::controller::lp::burn_lp_shares;
 use crate::controller::position;
 use crate::controller::position::{
-    add_new_position, decrease_open_bids_and_asks, get_position_index, increase_open_bids_and_asks,
+    decrease_open_bids_and_asks, get_position_index, increase_open_bids_and_asks,
     update_lp_market_position, update_position_and_market, update_quote_asset_amount,
     PositionDirection,
 };
@@ -37,8 +37,8 @@ use crate::math::fulfillment::{
 };
 use crate::math::liquidation::validate_user_not_being_liquidated;
 use crate::math::matching::{
-    are_orders_same_market_but_different_sides, calculate_fill_for_matched_orders,
-    calculate_filler_multiplier_for_matched_orders, do_orders_cross, is_maker_for_taker,
+    are_orders_same_market_but_different_sides, calculate_filler_multiplier_for_matched_orders,
+    do_orders_cross, is_maker_for_taker, match_orders, RoundingMode,
 };
 use crate::math::oracle::{is_oracle_valid_for_action, DriftAction, OracleValidity};
 use crate::math::safe_math::SafeMath;
@@ -72,7 +72,7 @@ use crate::state::state::FeeStructure;
 use crate::state::state::*;
 use crate::state::traits::Size;
 use crate::state::user::{
-    AssetType, Order, OrderStatus, OrderTriggerCondition, OrderType, UserStats,
+    AssetType, CollateralBucket, Order, OrderStatus, OrderTriggerCondition, OrderType, UserStats,
 };
 use crate::state::user::{MarketType, User};
 use crate::state::user_map::{UserMap, UserStatsMap};
@@ -169,7 +169,7 @@ pub fn place_perp_order(
     )?;
 
     let position_index = get_position_index(&user.perp_positions, market_index)
-        .or_else(|_| add_new_position(&mut user.perp_positions, market_index))?;
+        .or_else(|_| user.add_perp_position(market_index))?;
 
     // Increment open orders for existing position
     let (existing_position_direction, order_base_asset_amount) = {
@@ -270,7 +270,8 @@ pub fn place_perp_order(
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        collateral_bucket: CollateralBucket::Cross,
+        padding: [0; 2],
     };
 
     let valid_oracle_price = Some(oracle_map.get_price_data(&market.amm.oracle)?.price);
@@ -1352,7 +1353,7 @@ fn get_maker_orders_info(
             let maker_order_price = *maker_order_price;
 
             let maker_order = &maker.orders[maker_order_index];
-            if !is_maker_for_taker(maker_order, taker_order, slot)? {
+            if !is_maker_for_taker(maker_order, maker_key, taker_order, taker_key, slot, now)? {
                 continue;
             }
 
@@ -2019,7 +2020,7 @@ pub fn fulfill_perp_order_with_amm(
     if let Some(filler) = filler.as_mut() {
         if filler_reward > 0 {
             let position_index = get_position_index(&filler.perp_positions, market.market_index)
-                .or_else(|_| add_new_position(&mut filler.perp_positions, market.market_index))?;
+                .or_else(|_| filler.add_perp_position(market.market_index))?;
 
             controller::position::update_quote_asset_amount(
                 &mut filler.perp_positions[position_index],
@@ -2169,12 +2170,15 @@ pub fn fulfill_perp_order_with_match(
         return Ok((0_u64, 0_u64, 0_u64));
     }
 
-    let (base_asset_amount, _) = calculate_fill_for_matched_orders(
+    let (base_asset_amount, _) = match_orders(
+        &maker.orders[maker_order_index],
+        &taker.orders[taker_order_index],
         maker_base_asset_amount,
         maker_price,
         taker_base_asset_amount,
         PERP_DECIMALS,
-        maker_direction,
+        0,
+        RoundingMode::TowardMaker,
     )?;
 
     if base_asset_amount == 0 {
@@ -2239,14 +2243,16 @@ pub fn fulfill_perp_order_with_match(
     let taker_base_asset_amount = taker.orders[taker_order_index]
         .get_base_asset_amount_unfilled(Some(taker_existing_position))?;
 
-    let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) =
-        calculate_fill_for_matched_orders(
-            maker_base_asset_amount,
-            maker_price,
-            taker_base_asset_amount,
-            PERP_DECIMALS,
-            maker_direction,
-        )?;
+    let (base_asset_amount_fulfilled_by_maker, quote_asset_amount) = match_orders(
+        &maker.orders[maker_order_index],
+        &taker.orders[taker_order_index],
+        maker_base_asset_amount,
+        maker_price,
+        taker_base_asset_amount,
+        PERP_DECIMALS,
+        0,
+        RoundingMode::TowardMaker,
+    )?;
 
     validate_fill_price(
         quote_asset_amount,
@@ -2383,9 +2389,8 @@ pub fn fulfill_perp_order_with_match(
     if let Some(filler) = filler {
         if filler_reward > 0 {
             let filler_position_index =
-                get_position_index(&filler.perp_positions, market.market_index).or_else(|_| {
-                    add_new_position(&mut filler.perp_positions, market.market_index)
-                })?;
+                get_position_index(&filler.perp_positions, market.market_index)
+                    .or_else(|_| filler.add_perp_position(market.market_index))?;
 
             controller::position::update_quote_asset_amount(
                 &mut filler.perp_positions[filler_position_index],
@@ -3239,7 +3244,8 @@ pub fn place_spot_order(
         auction_end_price,
         auction_duration,
         max_ts,
-        padding: [0; 3],
+        collateral_bucket: CollateralBucket::Cross,
+        padding: [0; 2],
     };
 
     validate_spot_order(
@@ -3700,7 +3706,7 @@ fn get_spot_maker_orders_info(
             let maker_order_price = *maker_order_price;
 
             let maker_order = &maker.orders[maker_order_index];
-            if !is_maker_for_taker(maker_order, taker_order, slot)? {
+            if !is_maker_for_taker(maker_order, maker_key, taker_order, taker_key, slot, now)? {
                 continue;
             }
 
@@ -4189,12 +4195,15 @@ pub fn fulfill_spot_order_with_match(
             maker_base_asset_amount
         };
 
-    let (base_asset_amount, quote_asset_amount) = calculate_fill_for_matched_orders(
+    let (base_asset_amount, quote_asset_amount) = match_orders(
+        &maker.orders[maker_order_index],
+        &taker.orders[taker_order_index],
         maker_base_asset_amount,
         maker_price,
         taker_base_asset_amount,
         base_market.decimals,
-        maker_direction,
+        0,
+        RoundingMode::TowardMaker,
     )?;
 
     if base_asset_amount == 0 {
@@ -4738,6 +4747,7 @@ pub fn trigger_spot_order(
                 .historical_oracle_data
                 .last_oracle_price_twap_5min,
         ),
+        confidence: 0,
     };
 
     validate!(
@@ -1,4 +1,6 @@
-use crate::controller::amm::{update_pnl_pool_and_user_balance, update_pool_balances};
+use crate::controller::amm::{
+    simulate_pool_balances, update_pnl_pool_and_user_balance, update_pool_balances,
+};
 use crate::controller::funding::settle_funding_payment;
 use crate::controller::orders::{
     attempt_burn_user_lp_shares_for_risk_reduction, cancel_orders,
@@ -9,7 +11,7 @@ use crate::controller::position::{
     update_quote_asset_and_break_even_amount, update_settled_pnl, PositionDelta,
 };
 use crate::controller::spot_balance::{
-    update_spot_balances, update_spot_market_cumulative_interest,
+    transfer_spot_balances, update_spot_balances, update_spot_market_cumulative_interest,
 };
 use crate::error::{DriftResult, ErrorCode};
 use crate::math::amm::calculate_net_user_pnl;
@@ -25,14 +27,17 @@ use crate::math::safe_math::SafeMath;
 use crate::math::spot_balance::get_token_amount;
 use crate::state::margin_calculation::MarginContext;
 
-use crate::state::events::{OrderActionExplanation, SettlePnlExplanation, SettlePnlRecord};
+use crate::state::events::{
+    OrderActionExplanation, SettlePnlExplanation, SettlePnlRecord, SocializedLossRecord,
+    SETTLE_PNL_RECORD_VERSION,
+};
 use crate::state::oracle_map::OracleMap;
 use crate::state::paused_operations::PerpOperation;
-use crate::state::perp_market::MarketStatus;
+use crate::state::perp_market::{MarketStatus, PerpMarket};
 use crate::state::perp_market_map::PerpMarketMap;
-use crate::state::spot_market::{SpotBalance, SpotBalanceType};
+use crate::state::spot_market::{SpotBalance, SpotBalanceType, SpotMarket};
 use crate::state::spot_market_map::SpotMarketMap;
-use crate::state::state::State;
+use crate::state::state::{FeeStructure, State};
 use crate::state::user::{MarketType, User};
 use crate::validate;
 use anchor_lang::prelude::Pubkey;
@@ -46,6 +51,32 @@ mod tests;
 #[cfg(test)]
 mod delisting;
 
+/// Price the user's pnl is settled at. Oracle validity and pnl pool checks always use the oracle
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PriceSource {
+    Oracle,
+    /// the amm's mark twap, which can't be moved within a single transaction like the reserve price
+    Mark,
+}
+
+impl Default for PriceSource {
+    fn default() -> Self {
+        PriceSource::Oracle
+    }
+}
+
+/// Optional overrides for settle_pnl, the defaults settle the full pnl at the oracle price
+#[derive(Clone, Copy, Default)]
+pub struct SettlePnlOptions<'a> {
+    pub max_settle_amount: Option<u128>,
+    pub fee_structure: Option<&'a FeeStructure>,
+    /// rejects the settle if the oracle was last updated more than this many slots ago (oracle
+    /// delay is measured in slots, not seconds)
+    pub max_oracle_delay_slots: Option<i64>,
+    pub settlement_price_source: PriceSource,
+    pub settle_to_quote_target: Option<i128>,
+}
+
 pub fn settle_pnl(
     market_index: u16,
     user: &mut User,
@@ -56,6 +87,42 @@ pub fn settle_pnl(
     oracle_map: &mut OracleMap,
     clock: &Clock,
     state: &State,
+    options: SettlePnlOptions,
+) -> DriftResult {
+    validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
+    let now = clock.unix_timestamp;
+    {
+        let spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
+        update_spot_market_cumulative_interest(spot_market, None, now)?;
+    }
+
+    settle_pnl_for_market(
+        market_index,
+        user,
+        authority,
+        user_key,
+        perp_market_map,
+        spot_market_map,
+        oracle_map,
+        clock,
+        state,
+        None,
+        options,
+    )?;
+
+    Ok(())
+}
+
+pub fn settle_pnl_for_markets(
+    market_indexes: &[u16],
+    user: &mut User,
+    authority: &Pubkey,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    state: &State,
 ) -> DriftResult {
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
     let now = clock.unix_timestamp;
@@ -64,13 +131,124 @@ pub fn settle_pnl(
         update_spot_market_cumulative_interest(spot_market, None, now)?;
     }
 
+    let mut meets_maintenance_margin_requirement =
+        meets_maintenance_margin_requirement(user, perp_market_map, spot_market_map, oracle_map)?;
+
+    for market_index in market_indexes.iter() {
+        let position_index = match get_position_index(&user.perp_positions, *market_index) {
+            Ok(position_index) => position_index,
+            Err(_) => {
+                msg!("User has no position for market {}", market_index);
+                continue;
+            }
+        };
+
+        // a market is only skipped before anything is mutated, once settle_pnl_for_market starts
+        // settling funding every error is returned
+        let market_check = {
+            let perp_market = perp_market_map.get_ref(market_index)?;
+            validate_perp_market_for_settle_pnl(&perp_market, state).and_then(|_| {
+                validate_perp_market_status_for_settle_pnl(
+                    &perp_market,
+                    user.perp_positions[position_index].base_asset_amount,
+                )
+            })
+        };
+
+        match market_check {
+            Ok(()) => {}
+            Err(e) if is_skippable_settle_pnl_error(e) => {
+                msg!("Skipping settle pnl for market {}: {:?}", market_index, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let pnl_settled = settle_pnl_for_market(
+            *market_index,
+            user,
+            authority,
+            user_key,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+            clock,
+            state,
+            Some(meets_maintenance_margin_requirement),
+            SettlePnlOptions::default(),
+        )?;
+
+        // settling moves the user's quote balance (pnl, fees, socialized loss), so the cached
+        // check is stale
+        if pnl_settled != 0 {
+            meets_maintenance_margin_requirement = meets_maintenance_margin_requirement(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn settle_pnl_for_market(
+    market_index: u16,
+    user: &mut User,
+    authority: &Pubkey,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    clock: &Clock,
+    state: &State,
+    meets_maintenance_margin_requirement_cached: Option<bool>,
+    options: SettlePnlOptions,
+) -> DriftResult<i128> {
+    let SettlePnlOptions {
+        max_settle_amount,
+        fee_structure,
+        max_oracle_delay_slots,
+        settlement_price_source,
+        settle_to_quote_target,
+    } = options;
+    let now = clock.unix_timestamp;
+
     let mut market = perp_market_map.get_ref_mut(&market_index)?;
 
-    validate_market_within_price_band(&market, state, true, None)?;
+    validate_perp_market_for_settle_pnl(&market, state)?;
 
     crate::controller::lp::settle_funding_payment_then_lp(user, user_key, &mut market, now)?;
 
-    let oracle_price = oracle_map.get_price_data(&market.amm.oracle)?.price;
+    let oracle_price_data = oracle_map.get_price_data(&market.amm.oracle)?;
+    let oracle_price = oracle_price_data.price;
+
+    let settle_price = match settlement_price_source {
+        PriceSource::Oracle => oracle_price,
+        PriceSource::Mark => {
+            let last_mark_price_twap = market.amm.last_mark_price_twap;
+            validate!(
+                last_mark_price_twap > 0,
+                ErrorCode::DefaultError,
+                "market={} has no mark twap to settle pnl at",
+                market_index
+            )?;
+            last_mark_price_twap.cast()?
+        }
+    };
+
+    if let Some(max_oracle_delay_slots) = max_oracle_delay_slots {
+        validate!(
+            oracle_price_data.delay <= max_oracle_delay_slots,
+            ErrorCode::InvalidOracleForSettlePnl,
+            "Oracle for market={} is too stale to settle pnl (delay={} > max_oracle_delay_slots={})",
+            market_index,
+            oracle_price_data.delay,
+            max_oracle_delay_slots
+        )?;
+    }
+
     drop(market);
 
     let position_index = get_position_index(&user.perp_positions, market_index)?;
@@ -115,17 +293,23 @@ pub fn settle_pnl(
                     "Unable to settle market={} negative pnl as user is in liquidation territory",
                     market_index
                 );
-                return Ok(());
+                return Ok(0);
             }
         }
     } else if unrealized_pnl < 0 {
+        let meets_maintenance_margin_requirement = match meets_maintenance_margin_requirement_cached
+        {
+            Some(meets_maintenance_margin_requirement) => meets_maintenance_margin_requirement,
+            None => meets_maintenance_margin_requirement(
+                user,
+                perp_market_map,
+                spot_market_map,
+                oracle_map,
+            )?,
+        };
+
         // cannot settle pnl this way on a user who is in liquidation territory
-        if !(meets_maintenance_margin_requirement(
-            user,
-            perp_market_map,
-            spot_market_map,
-            oracle_map,
-        )?) {
+        if !meets_maintenance_margin_requirement {
             return Err(ErrorCode::InsufficientCollateralForSettlingPNL);
         }
     }
@@ -133,6 +317,15 @@ pub fn settle_pnl(
     let spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
     let perp_market = &mut perp_market_map.get_ref_mut(&market_index)?;
 
+    validate!(
+        spot_market.market_index == perp_market.quote_spot_market_index,
+        ErrorCode::InvalidSpotMarketAccount,
+        "perp market={} settles in spot market={}, not quote spot market={}",
+        market_index,
+        perp_market.quote_spot_market_index,
+        spot_market.market_index
+    )?;
+
     if perp_market.amm.curve_update_intensity > 0 {
         let healthy_oracle = perp_market.amm.is_recent_oracle_valid(oracle_map.slot)?;
 
@@ -171,81 +364,109 @@ pub fn settle_pnl(
         }
     }
 
-    validate!(
-        !perp_market.is_operation_paused(PerpOperation::SettlePnl),
-        ErrorCode::InvalidMarketStatusToSettlePnl,
-        "Cannot settle pnl under current market = {} status",
-        market_index
+    validate_perp_market_status_for_settle_pnl(
+        perp_market,
+        user.perp_positions[position_index].base_asset_amount,
     )?;
 
-    if user.perp_positions[position_index].base_asset_amount != 0 {
-        validate!(
-            !perp_market.is_operation_paused(PerpOperation::SettlePnlWithPosition),
-            ErrorCode::InvalidMarketStatusToSettlePnl,
-            "Cannot settle pnl with position under current market = {} operation paused",
-            market_index
-        )?;
+    let max_pnl_pool_excess =
+        calculate_max_pnl_pool_excess(perp_market, spot_market, oracle_price)?;
 
-        validate!(
-            perp_market.status == MarketStatus::Active,
-            ErrorCode::InvalidMarketStatusToSettlePnl,
-            "Cannot settle pnl with position under non-Active current market = {} status",
-            market_index
-        )?;
-    } else {
-        validate!(
-            perp_market.status == MarketStatus::Active
-                || perp_market.status == MarketStatus::ReduceOnly,
-            ErrorCode::InvalidMarketStatusToSettlePnl,
-            "Cannot settle pnl under current market = {} status (neither Active or ReduceOnly)",
-            market_index
-        )?;
+    // maker rebates are credited to quote_asset_amount at fill time (and netted out of the market's
+    // fee revenue), so they're settled here as part of the claimable pnl
+    let mut user_unsettled_pnl: i128 =
+        user.perp_positions[position_index].get_claimable_pnl(settle_price, max_pnl_pool_excess)?;
+
+    if let Some(max_settle_amount) = max_settle_amount {
+        let max_settle_amount = max_settle_amount.cast::<i128>()?;
+        user_unsettled_pnl = user_unsettled_pnl.clamp(-max_settle_amount, max_settle_amount);
     }
 
-    let pnl_pool_token_amount = get_token_amount(
-        perp_market.pnl_pool.scaled_balance,
-        spot_market,
-        perp_market.pnl_pool.balance_type(),
-    )?;
+    // settling pnl moves quote_asset_amount by -pnl, so only settle what lands it on the target
+    let pnl_to_reach_quote_target = match settle_to_quote_target {
+        Some(quote_target) => {
+            let pnl_to_reach_quote_target = user.perp_positions[position_index]
+                .quote_asset_amount
+                .cast::<i128>()?
+                .safe_sub(quote_target)?;
 
-    let fraction_of_fee_pool_token_amount = get_token_amount(
-        perp_market.amm.fee_pool.scaled_balance,
-        spot_market,
-        perp_market.amm.fee_pool.balance_type(),
-    )?
-    .safe_div(5)?;
+            user_unsettled_pnl = if user_unsettled_pnl > 0 {
+                user_unsettled_pnl.min(pnl_to_reach_quote_target.max(0))
+            } else {
+                user_unsettled_pnl.max(pnl_to_reach_quote_target.min(0))
+            };
 
-    // add a buffer from fee pool for pnl pool balance
-    let pnl_tokens_available: i128 = pnl_pool_token_amount
-        .safe_add(fraction_of_fee_pool_token_amount)?
-        .cast()?;
-
-    let net_user_pnl = calculate_net_user_pnl(&perp_market.amm, oracle_price)?;
-    let max_pnl_pool_excess = if net_user_pnl < pnl_tokens_available {
-        pnl_tokens_available.safe_sub(net_user_pnl.max(0))?
-    } else {
-        0
+            Some(pnl_to_reach_quote_target)
+        }
+        None => None,
     };
 
-    let user_unsettled_pnl: i128 =
-        user.perp_positions[position_index].get_claimable_pnl(oracle_price, max_pnl_pool_excess)?;
+    // only positive pnl is rate limited, a threshold of 0 applies the cooldown to any amount
+    if perp_market.min_settle_interval > 0
+        && user_unsettled_pnl > 0
+        && (perp_market.settle_cooldown_pnl_threshold == 0
+            || user_unsettled_pnl <= perp_market.settle_cooldown_pnl_threshold.cast()?)
+    {
+        if let Some(last_settle_pnl_ts) = user.get_last_settle_pnl_ts(position_index)? {
+            let time_since_last_settle = now.safe_sub(last_settle_pnl_ts)?;
+            validate!(
+                time_since_last_settle >= perp_market.min_settle_interval.cast()?,
+                ErrorCode::SettlePnlCooldownNotElapsed,
+                "market={} last settled {}s ago (min_settle_interval={}) and pnl {} is within settle_cooldown_pnl_threshold={} (0 = any)",
+                market_index,
+                time_since_last_settle,
+                perp_market.min_settle_interval,
+                user_unsettled_pnl,
+                perp_market.settle_cooldown_pnl_threshold
+            )?;
+        }
+    }
+
+    let socialized_loss_ratio_before = perp_market.socialized_loss_ratio;
+    perp_market.socialized_loss_ratio =
+        calculate_socialized_loss_ratio(perp_market, spot_market, oracle_price)?;
 
-    let pnl_to_settle_with_user = update_pool_balances(
+    // positive pnl is paid out net of the socialized loss ratio. only the pnl settled here is
+    // written off, so the ratio is applied once to each unit of pnl however many times it's settled
+    let socialized_loss = perp_market.get_socialized_loss(user_unsettled_pnl)?;
+    let pnl_to_pay_user = user_unsettled_pnl.safe_sub(socialized_loss)?;
+
+    let pnl_paid_to_user = update_pool_balances(
         perp_market,
         spot_market,
         user.get_quote_spot_position(),
-        user_unsettled_pnl,
+        pnl_to_pay_user,
         now,
     )?;
+
+    let socialized_loss = if pnl_paid_to_user == pnl_to_pay_user {
+        socialized_loss
+    } else {
+        perp_market.get_socialized_loss_for_payout(pnl_paid_to_user)?
+    };
+
+    let pnl_to_settle_with_user = pnl_paid_to_user.safe_add(socialized_loss)?;
+
+    if let Some(pnl_to_reach_quote_target) = pnl_to_reach_quote_target {
+        validate!(
+            pnl_to_settle_with_user == pnl_to_reach_quote_target,
+            ErrorCode::QuoteTargetUnreachableForSettlePnl,
+            "market={} can only settle {} of the {} pnl needed to reach quote target",
+            market_index,
+            pnl_to_settle_with_user,
+            pnl_to_reach_quote_target
+        )?;
+    }
+
     if user_unsettled_pnl == 0 {
         msg!("User has no unsettled pnl for market {}", market_index);
-        return Ok(());
+        return Ok(0);
     } else if pnl_to_settle_with_user == 0 {
         msg!(
             "Pnl Pool cannot currently settle with user for market {}",
             market_index
         );
-        return Ok(());
+        return Ok(0);
     }
 
     validate!(
@@ -257,9 +478,35 @@ pub fn settle_pnl(
         "User must settle their own unsettled pnl when its positive and pnl pool not in excess"
     )?;
 
+    // only positive pnl paid out to the user is charged a fee
+    let fee = match fee_structure {
+        Some(fee_structure) if pnl_paid_to_user > 0 => pnl_paid_to_user
+            .safe_mul(fee_structure.fee_tiers[0].fee_numerator.cast()?)?
+            .safe_div(fee_structure.fee_tiers[0].fee_denominator.cast()?)?,
+        _ => 0,
+    };
+
+    let net_pnl_to_settle_with_user = pnl_paid_to_user.safe_sub(fee)?;
+
+    if fee > 0 {
+        update_spot_balances(
+            fee.unsigned_abs(),
+            &SpotBalanceType::Deposit,
+            spot_market,
+            &mut perp_market.amm.fee_pool,
+            false,
+        )?;
+
+        perp_market.amm.total_fee = perp_market.amm.total_fee.safe_add(fee)?;
+        perp_market.amm.total_fee_minus_distributions = perp_market
+            .amm
+            .total_fee_minus_distributions
+            .safe_add(fee)?;
+    }
+
     update_spot_balances(
-        pnl_to_settle_with_user.unsigned_abs(),
-        if pnl_to_settle_with_user > 0 {
+        net_pnl_to_settle_with_user.unsigned_abs(),
+        if net_pnl_to_settle_with_user > 0 {
             &SpotBalanceType::Deposit
         } else {
             &SpotBalanceType::Borrow
@@ -276,10 +523,36 @@ pub fn settle_pnl(
     )?;
 
     update_settled_pnl(user, position_index, pnl_to_settle_with_user.cast()?)?;
+    user.update_last_settle_pnl_ts(position_index, now)?;
+
+    if socialized_loss > 0 {
+        msg!(
+            "Wrote off {} of user pnl for socialized loss in market {}",
+            socialized_loss,
+            market_index
+        );
+
+        user.increment_total_socialized_loss(socialized_loss.cast()?)?;
+
+        emit!(SocializedLossRecord {
+            ts: now,
+            user: *user_key,
+            market_index,
+            socialized_loss: socialized_loss.unsigned_abs(),
+            socialized_loss_ratio_before,
+            socialized_loss_ratio_after: perp_market.socialized_loss_ratio,
+        });
+    }
 
     let base_asset_amount = user.perp_positions[position_index].base_asset_amount;
     let quote_asset_amount_after = user.perp_positions[position_index].quote_asset_amount;
     let quote_entry_amount = user.perp_positions[position_index].quote_entry_amount;
+    let settled_pnl_after = user.perp_positions[position_index].settled_pnl;
+    let pnl_pool_balance_after = get_token_amount(
+        perp_market.pnl_pool.scaled_balance,
+        spot_market,
+        perp_market.pnl_pool.balance_type(),
+    )?;
 
     crate::validation::perp_market::validate_perp_market(perp_market)?;
     crate::validation::position::validate_perp_position_with_perp_market(
@@ -291,17 +564,261 @@ pub fn settle_pnl(
         ts: now,
         user: *user_key,
         market_index,
-        pnl: pnl_to_settle_with_user,
+        pnl: net_pnl_to_settle_with_user,
         base_asset_amount,
         quote_asset_amount_after,
         quote_entry_amount,
-        settle_price: oracle_price,
+        settle_price,
         explanation: SettlePnlExplanation::None,
+        pnl_pool_balance_after,
+        fee: fee.unsigned_abs(),
+        settled_pnl_after,
+        version: SETTLE_PNL_RECORD_VERSION,
     });
 
+    Ok(pnl_to_settle_with_user)
+}
+
+/// Per market failures that shouldn't abort settling the rest of the markets. Each is raised before
+/// the user's pnl is moved, so skipping the market leaves it unsettled
+fn validate_perp_market_for_settle_pnl(perp_market: &PerpMarket, state: &State) -> DriftResult {
+    validate!(
+        !perp_market.status.allows_expired_settlement(),
+        ErrorCode::InvalidMarketStatusToSettlePnl,
+        "Market={} is in settlement, use settle_expired_position instead",
+        perp_market.market_index
+    )?;
+
+    validate_market_within_price_band(perp_market, state, true, None)?;
+
+    Ok(())
+}
+
+fn validate_perp_market_status_for_settle_pnl(
+    perp_market: &PerpMarket,
+    base_asset_amount: i64,
+) -> DriftResult {
+    let market_index = perp_market.market_index;
+
+    validate!(
+        !perp_market.is_operation_paused(PerpOperation::SettlePnl),
+        ErrorCode::InvalidMarketStatusToSettlePnl,
+        "Cannot settle pnl under current market = {} status",
+        market_index
+    )?;
+
+    if base_asset_amount != 0 {
+        validate!(
+            !perp_market.is_operation_paused(PerpOperation::SettlePnlWithPosition),
+            ErrorCode::InvalidMarketStatusToSettlePnl,
+            "Cannot settle pnl with position under current market = {} operation paused",
+            market_index
+        )?;
+
+        validate!(
+            perp_market.status == MarketStatus::Active,
+            ErrorCode::InvalidMarketStatusToSettlePnl,
+            "Cannot settle pnl with position under non-Active current market = {} status",
+            market_index
+        )?;
+    } else {
+        validate!(
+            perp_market.status.allows_pnl_settlement(),
+            ErrorCode::InvalidMarketStatusToSettlePnl,
+            "Cannot settle pnl under current market = {} status (neither Active or ReduceOnly)",
+            market_index
+        )?;
+    }
+
     Ok(())
 }
 
+fn is_skippable_settle_pnl_error(error: ErrorCode) -> bool {
+    matches!(
+        error,
+        ErrorCode::InvalidMarketStatusToSettlePnl | ErrorCode::PriceBandsBreached
+    )
+}
+
+/// Socialized loss ratio for the market's current outstanding pnl and pnl pool, 0 unless socialized
+/// loss is enabled, the market is reduce only and the pool can't cover the net pnl owed to users
+fn calculate_socialized_loss_ratio(
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+) -> DriftResult<u32> {
+    if !perp_market.socialized_loss_enabled || perp_market.status != MarketStatus::ReduceOnly {
+        return Ok(0);
+    }
+
+    let pnl_pool_token_amount = get_token_amount(
+        perp_market.pnl_pool.scaled_balance,
+        spot_market,
+        perp_market.pnl_pool.balance_type(),
+    )?;
+
+    let fraction_of_fee_pool_token_amount = get_token_amount(
+        perp_market.amm.fee_pool.scaled_balance,
+        spot_market,
+        perp_market.amm.fee_pool.balance_type(),
+    )?
+    .safe_div(5)?;
+
+    // same fee pool buffer for the pnl pool as calculate_max_pnl_pool_excess
+    let pnl_tokens_available = pnl_pool_token_amount.safe_add(fraction_of_fee_pool_token_amount)?;
+
+    let net_user_pnl = calculate_net_user_pnl(&perp_market.amm, oracle_price)?;
+
+    perp_market.calculate_socialized_loss_ratio(net_user_pnl, pnl_tokens_available)
+}
+
+fn calculate_max_pnl_pool_excess(
+    perp_market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+) -> DriftResult<i128> {
+    let pnl_pool_token_amount = get_token_amount(
+        perp_market.pnl_pool.scaled_balance,
+        spot_market,
+        perp_market.pnl_pool.balance_type(),
+    )?;
+
+    let fraction_of_fee_pool_token_amount = get_token_amount(
+        perp_market.amm.fee_pool.scaled_balance,
+        spot_market,
+        perp_market.amm.fee_pool.balance_type(),
+    )?
+    .safe_div(5)?;
+
+    // add a buffer from fee pool for pnl pool balance
+    let pnl_tokens_available: i128 = pnl_pool_token_amount
+        .safe_add(fraction_of_fee_pool_token_amount)?
+        .cast()?;
+
+    let net_user_pnl = calculate_net_user_pnl(&perp_market.amm, oracle_price)?;
+    let max_pnl_pool_excess = if net_user_pnl < pnl_tokens_available {
+        pnl_tokens_available.safe_sub(net_user_pnl.max(0))?
+    } else {
+        0
+    };
+
+    Ok(max_pnl_pool_excess)
+}
+
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct SettlePnlPreview {
+    pub user_unsettled_pnl: i128,
+    pub pnl_to_settle_with_user: i128,
+    pub meets_margin_requirement: bool,
+}
+
+/// Estimates the outcome of settle_pnl on copies of the user and markets without mutating any account
+pub fn preview_settle_pnl(
+    market_index: u16,
+    user: &User,
+    user_key: &Pubkey,
+    perp_market_map: &PerpMarketMap,
+    spot_market_map: &SpotMarketMap,
+    oracle_map: &mut OracleMap,
+    now: i64,
+) -> DriftResult<SettlePnlPreview> {
+    let mut user = *user;
+    let mut perp_market = *perp_market_map.get_ref(&market_index)?;
+    let mut spot_market = *spot_market_map.get_quote_spot_market()?;
+
+    update_spot_market_cumulative_interest(&mut spot_market, None, now)?;
+
+    crate::controller::lp::settle_funding_payment_then_lp(
+        &mut user,
+        user_key,
+        &mut perp_market,
+        now,
+    )?;
+
+    let oracle_price = oracle_map.get_price_data(&perp_market.amm.oracle)?.price;
+
+    let position_index = get_position_index(&user.perp_positions, market_index)?;
+    let unrealized_pnl = user.perp_positions[position_index].get_unrealized_pnl(oracle_price)?;
+
+    let meets_margin_requirement = unrealized_pnl >= 0
+        || meets_maintenance_margin_requirement(
+            &user,
+            perp_market_map,
+            spot_market_map,
+            oracle_map,
+        )?;
+
+    let max_pnl_pool_excess =
+        calculate_max_pnl_pool_excess(&perp_market, &spot_market, oracle_price)?;
+
+    let mut user_unsettled_pnl: i128 =
+        user.perp_positions[position_index].get_claimable_pnl(oracle_price, max_pnl_pool_excess)?;
+
+    perp_market.socialized_loss_ratio =
+        calculate_socialized_loss_ratio(&perp_market, &spot_market, oracle_price)?;
+    let socialized_loss = perp_market.get_socialized_loss(user_unsettled_pnl)?;
+    user_unsettled_pnl = user_unsettled_pnl.safe_sub(socialized_loss)?;
+
+    let pnl_to_settle_with_user = if meets_margin_requirement {
+        simulate_pool_balances(
+            &perp_market,
+            &spot_market,
+            user.get_quote_spot_position(),
+            user_unsettled_pnl,
+            now,
+        )?
+    } else {
+        0
+    };
+
+    Ok(SettlePnlPreview {
+        user_unsettled_pnl,
+        pnl_to_settle_with_user,
+        meets_margin_requirement,
+    })
+}
+
+/// Positive claimable pnl the pnl pool can't currently pay out, i.e. what's stuck waiting for the
+/// pool to refill. spot_market is the quote spot market. Negative pnl is never stuck
+pub fn get_unsettleable_pnl(
+    user: &User,
+    market: &PerpMarket,
+    spot_market: &SpotMarket,
+    oracle_price: i64,
+    now: i64,
+) -> DriftResult<i128> {
+    let position_index = get_position_index(&user.perp_positions, market.market_index)?;
+
+    let max_pnl_pool_excess = calculate_max_pnl_pool_excess(market, spot_market, oracle_price)?;
+
+    let user_unsettled_pnl =
+        user.perp_positions[position_index].get_claimable_pnl(oracle_price, max_pnl_pool_excess)?;
+
+    // the written off part isn't stuck, it will never be paid out
+    let mut market = *market;
+    market.socialized_loss_ratio =
+        calculate_socialized_loss_ratio(&market, spot_market, oracle_price)?;
+    let user_unsettled_pnl =
+        user_unsettled_pnl.safe_sub(market.get_socialized_loss(user_unsettled_pnl)?)?;
+
+    if user_unsettled_pnl <= 0 {
+        return Ok(0);
+    }
+
+    let pnl_to_settle_with_user = simulate_pool_balances(
+        &market,
+        spot_market,
+        user.get_quote_spot_position(),
+        user_unsettled_pnl,
+        now,
+    )?;
+
+    user_unsettled_pnl.safe_sub(pnl_to_settle_with_user)
+}
+
+/// Permissionless on purpose, unlike positive pnl in settle_pnl there is no authority or delegate
+/// check. Every position in a market in settlement has to be closed at the expiry price before
+/// the market can be deleted, so the user (or their delegate) can't be allowed to hold it open
 pub fn settle_expired_position(
     perp_market_index: u16,
     user: &mut User,
@@ -311,6 +828,7 @@ pub fn settle_expired_position(
     oracle_map: &mut OracleMap,
     clock: &Clock,
     state: &State,
+    dust_threshold: u64,
 ) -> DriftResult {
     validate!(!user.is_bankrupt(), ErrorCode::UserBankrupt)?;
 
@@ -361,13 +879,31 @@ pub fn settle_expired_position(
 
     let quote_spot_market = &mut spot_market_map.get_quote_spot_market_mut()?;
     let perp_market = &mut perp_market_map.get_ref_mut(&perp_market_index)?;
+
+    validate!(
+        quote_spot_market.market_index == perp_market.quote_spot_market_index,
+        ErrorCode::InvalidSpotMarketAccount,
+        "perp market={} settles in spot market={}, not quote spot market={}",
+        perp_market_index,
+        perp_market.quote_spot_market_index,
+        quote_spot_market.market_index
+    )?;
+
     validate!(
-        perp_market.status == MarketStatus::Settlement,
+        perp_market.status.allows_expired_settlement(),
         ErrorCode::PerpMarketNotInSettlement,
         "Perp Market isn't in settlement, expiry_ts={}",
         perp_market.expiry_ts
     )?;
 
+    // expiry_price can be negative, but zero means it was never set
+    validate!(
+        perp_market.expiry_price != 0,
+        ErrorCode::PerpMarketExpiryPriceNotSet,
+        "Perp Market expiry_price not set, expiry_ts={}",
+        perp_market.expiry_ts
+    )?;
+
     let position_settlement_ts = perp_market
         .expiry_ts
         .safe_add(state.settlement_duration.cast()?)?;
@@ -391,9 +927,20 @@ pub fn settle_expired_position(
         "User must first burn lp shares for expired market"
     )?;
 
+    // a twap over the final window is harder to game than the expiry snapshot
+    let last_oracle_price_twap = perp_market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap;
+    let settle_price = if perp_market.use_twap_for_expiry && last_oracle_price_twap > 0 {
+        last_oracle_price_twap
+    } else {
+        perp_market.expiry_price
+    };
+
     let base_asset_value = calculate_base_asset_value_with_expiry_price(
         &user.perp_positions[position_index],
-        perp_market.expiry_price,
+        settle_price,
     )?;
 
     let base_asset_amount = user.perp_positions[position_index].base_asset_amount;
@@ -423,8 +970,53 @@ pub fn settle_expired_position(
 
     let pnl = user.perp_positions[position_index].quote_asset_amount;
 
-    let pnl_to_settle_with_user =
-        update_pnl_pool_and_user_balance(perp_market, quote_spot_market, user, pnl.cast()?)?;
+    // positive pnl below the dust threshold left on the closed position is swept from the pnl pool
+    // into the fee pool instead of being paid out. the user's quote deposit is never touched
+    let dust_to_sweep = if pnl > 0 && pnl < dust_threshold.cast()? {
+        pnl.cast::<i128>()?
+    } else {
+        0
+    };
+
+    let pnl_to_settle_with_user = if dust_to_sweep > 0 {
+        let pnl_pool_token_amount = get_token_amount(
+            perp_market.pnl_pool.scaled_balance,
+            quote_spot_market,
+            perp_market.pnl_pool.balance_type(),
+        )?;
+
+        validate!(
+            pnl_pool_token_amount >= dust_to_sweep.unsigned_abs(),
+            ErrorCode::InsufficientPerpPnlPool,
+            "pnl_pool_amount doesnt have enough to sweep dust ({} < {})",
+            pnl_pool_token_amount,
+            dust_to_sweep
+        )?;
+
+        let perp_market = &mut **perp_market;
+        transfer_spot_balances(
+            dust_to_sweep,
+            quote_spot_market,
+            &mut perp_market.pnl_pool,
+            &mut perp_market.amm.fee_pool,
+        )?;
+
+        perp_market.amm.total_fee = perp_market.amm.total_fee.safe_add(dust_to_sweep)?;
+        perp_market.amm.total_fee_minus_distributions = perp_market
+            .amm
+            .total_fee_minus_distributions
+            .safe_add(dust_to_sweep)?;
+
+        update_quote_asset_amount(
+            &mut user.perp_positions[position_index],
+            perp_market,
+            -dust_to_sweep.cast()?,
+        )?;
+
+        0
+    } else {
+        update_pnl_pool_and_user_balance(perp_market, quote_spot_market, user, pnl.cast()?)?
+    };
 
     update_quote_asset_amount(
         &mut user.perp_positions[position_index],
@@ -440,6 +1032,12 @@ pub fn settle_expired_position(
         .safe_add(position_delta.base_asset_amount.cast()?)?;
 
     let quote_asset_amount_after = user.perp_positions[position_index].quote_asset_amount;
+    let settled_pnl_after = user.perp_positions[position_index].settled_pnl;
+    let pnl_pool_balance_after = get_token_amount(
+        perp_market.pnl_pool.scaled_balance,
+        quote_spot_market,
+        perp_market.pnl_pool.balance_type(),
+    )?;
 
     emit!(SettlePnlRecord {
         ts: now,
@@ -449,8 +1047,12 @@ pub fn settle_expired_position(
         base_asset_amount,
         quote_asset_amount_after,
         quote_entry_amount,
-        settle_price: perp_market.expiry_price,
+        settle_price,
         explanation: SettlePnlExplanation::ExpiredPosition,
+        pnl_pool_balance_after,
+        fee: fee.unsigned_abs().cast()?,
+        settled_pnl_after,
+        version: SETTLE_PNL_RECORD_VERSION,
     });
 
     validate!(
@@ -810,6 +810,7 @@ pub mod delisting_test {
             &mut oracle_map,
             &clock,
             &state,
+            0,
         )
         .unwrap();
 
@@ -1028,6 +1029,7 @@ pub mod delisting_test {
             &mut oracle_map,
             &clock,
             &state,
+            0,
         )
         .unwrap();
 
@@ -1233,6 +1235,7 @@ pub mod delisting_test {
             &mut oracle_map,
             &clock,
             &state,
+            0,
         )
         .unwrap();
 
@@ -1555,6 +1558,7 @@ pub mod delisting_test {
                 &mut oracle_map,
                 &clock,
                 &state,
+                0,
             )
             .unwrap();
 
@@ -1613,6 +1617,7 @@ pub mod delisting_test {
             &mut oracle_map,
             &clock,
             &state,
+            0,
         )
         .unwrap();
 
@@ -1651,6 +1656,7 @@ pub mod delisting_test {
             &mut oracle_map,
             &clock,
             &state,
+            0,
         )
         .unwrap();
         let market = market_map.get_ref_mut(&0).unwrap();
@@ -1963,7 +1969,8 @@ pub mod delisting_test {
                     &spot_market_map,
                     &mut oracle_map,
                     &clock,
-                    &state
+                    &state,
+                    0
                 )
                 .is_err(),
                 true
@@ -2365,6 +2372,7 @@ pub mod delisting_test {
                 &mut oracle_map,
                 &clock,
                 &state,
+                0,
             )
             .is_err());
 
@@ -2680,6 +2688,7 @@ pub mod delisting_test {
                 &mut oracle_map,
                 &clock,
                 &state,
+                0,
             )
             .unwrap();
 
@@ -2805,6 +2814,7 @@ pub mod delisting_test {
                 &mut oracle_map,
                 &clock,
                 &state,
+                0,
             )
             .unwrap();
             assert_eq!(longer.perp_positions[0].quote_asset_amount, 0);
@@ -3,16 +3,19 @@ use std::str::FromStr;
 use anchor_lang::Owner;
 use solana_program::pubkey::Pubkey;
 
-use crate::controller::pnl::settle_pnl;
+use crate::controller::pnl::{
+    get_unsettleable_pnl, preview_settle_pnl, settle_expired_position, settle_pnl,
+    settle_pnl_for_markets, PriceSource, SettlePnlOptions, SettlePnlPreview,
+};
 use crate::create_account_info;
 use crate::create_anchor_account_info;
 use crate::error::ErrorCode;
 use crate::math::casting::Cast;
 use crate::math::constants::{
     AMM_RESERVE_PRECISION, BASE_PRECISION_I128, BASE_PRECISION_I64, LIQUIDATION_FEE_PRECISION,
-    PEG_PRECISION, QUOTE_PRECISION_I128, QUOTE_PRECISION_I64, QUOTE_SPOT_MARKET_INDEX,
-    SPOT_BALANCE_PRECISION, SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION,
-    SPOT_WEIGHT_PRECISION,
+    PEG_PRECISION, PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION, QUOTE_PRECISION_I128,
+    QUOTE_PRECISION_I64, QUOTE_PRECISION_U64, QUOTE_SPOT_MARKET_INDEX, SPOT_BALANCE_PRECISION,
+    SPOT_BALANCE_PRECISION_U64, SPOT_CUMULATIVE_INTEREST_PRECISION, SPOT_WEIGHT_PRECISION,
 };
 use crate::state::oracle::{HistoricalOracleData, OracleSource};
 use crate::state::oracle_map::OracleMap;
@@ -20,7 +23,7 @@ use crate::state::perp_market::{MarketStatus, PerpMarket, PoolBalance, AMM};
 use crate::state::perp_market_map::PerpMarketMap;
 use crate::state::spot_market::{SpotBalanceType, SpotMarket};
 use crate::state::spot_market_map::SpotMarketMap;
-use crate::state::state::{OracleGuardRails, State, ValidityGuardRails};
+use crate::state::state::{FeeStructure, OracleGuardRails, State, ValidityGuardRails};
 use crate::state::user::{PerpPosition, SpotPosition, User};
 use crate::test_utils::*;
 use crate::test_utils::{get_positions, get_pyth_price, get_spot_positions};
@@ -136,6 +139,7 @@ pub fn user_no_position() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     );
 
     assert_eq!(result, Err(ErrorCode::UserHasNoPositionInMarket));
@@ -259,6 +263,7 @@ pub fn user_does_not_meet_maintenance_requirement() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     );
 
     assert_eq!(result, Err(ErrorCode::InsufficientCollateralForSettlingPNL))
@@ -392,6 +397,7 @@ pub fn user_unsettled_negative_pnl() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     )
     .unwrap();
 
@@ -525,6 +531,7 @@ pub fn user_unsettled_positive_pnl_more_than_pool() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     )
     .unwrap();
 
@@ -660,6 +667,7 @@ pub fn user_unsettled_positive_pnl_less_than_pool() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     )
     .unwrap();
 
@@ -668,15 +676,14 @@ pub fn user_unsettled_positive_pnl_less_than_pool() {
 }
 
 #[test]
-pub fn market_fee_pool_receives_portion() {
+pub fn user_unsettled_positive_pnl_within_settle_cooldown() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 0,
+        unix_timestamp: 100,
     };
-    let slot = clock.slot;
     let state = State {
         oracle_guard_rails: OracleGuardRails {
             validity: ValidityGuardRails {
@@ -699,7 +706,7 @@ pub fn market_fee_pool_receives_portion() {
         &pyth_program,
         oracle_account_info
     );
-    let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
     let mut market = PerpMarket {
         amm: AMM {
@@ -718,7 +725,6 @@ pub fn market_fee_pool_receives_portion() {
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
-            total_fee_minus_distributions: QUOTE_PRECISION_I128,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price: oracle_price.agg.price,
                 last_oracle_price_twap_5min: oracle_price.agg.price,
@@ -739,6 +745,8 @@ pub fn market_fee_pool_receives_portion() {
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        min_settle_interval: 60,
+        settle_cooldown_pnl_threshold: 100 * QUOTE_PRECISION_U64,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -761,34 +769,27 @@ pub fn market_fee_pool_receives_portion() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
+        last_settle_pnl_ts: 70,
+        last_settle_pnl_ts_offsets: [1, 0, 0, 0, 0, 0, 0, 0],
         ..User::default()
     };
 
     let user_key = Pubkey::default();
     let authority = Pubkey::default();
 
-    let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = 0;
-    expected_user.settled_perp_pnl = -100 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = -100 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 100 * SPOT_BALANCE_PRECISION_U64;
-
-    let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 149 * SPOT_BALANCE_PRECISION;
-    expected_market.amm.fee_pool.scaled_balance = SPOT_BALANCE_PRECISION;
-    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
-    expected_market.number_of_users = 0;
+    let expected_user = user;
+    let expected_market = market;
 
-    settle_pnl(
+    let result = settle_pnl(
         0,
         &mut user,
         &authority,
@@ -798,21 +799,22 @@ pub fn market_fee_pool_receives_portion() {
         &mut oracle_map,
         &clock,
         &state,
-    )
-    .unwrap();
+        SettlePnlOptions::default(),
+    );
 
+    assert_eq!(result, Err(ErrorCode::SettlePnlCooldownNotElapsed));
     assert_eq!(expected_user, user);
     assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
 
 #[test]
-pub fn market_fee_pool_pays_back_to_pnl_pool() {
+pub fn user_unsettled_positive_pnl_within_settle_cooldown_no_threshold() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 0,
+        unix_timestamp: 100,
     };
     let state = State {
         oracle_guard_rails: OracleGuardRails {
@@ -855,12 +857,6 @@ pub fn market_fee_pool_pays_back_to_pnl_pool() {
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
-            total_fee_minus_distributions: QUOTE_PRECISION_I128,
-            fee_pool: PoolBalance {
-                scaled_balance: (2 * SPOT_BALANCE_PRECISION) as u128,
-                market_index: QUOTE_SPOT_MARKET_INDEX,
-                ..PoolBalance::default()
-            },
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price: oracle_price.agg.price,
                 last_oracle_price_twap_5min: oracle_price.agg.price,
@@ -881,6 +877,8 @@ pub fn market_fee_pool_pays_back_to_pnl_pool() {
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        min_settle_interval: 60,
+        settle_cooldown_pnl_threshold: 0,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -903,34 +901,27 @@ pub fn market_fee_pool_pays_back_to_pnl_pool() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
+        last_settle_pnl_ts: 70,
+        last_settle_pnl_ts_offsets: [1, 0, 0, 0, 0, 0, 0, 0],
         ..User::default()
     };
 
     let user_key = Pubkey::default();
     let authority = Pubkey::default();
 
-    let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = 0;
-    expected_user.settled_perp_pnl = -100 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = -100 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 100 * SPOT_BALANCE_PRECISION_U64;
-
-    let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 151 * SPOT_BALANCE_PRECISION;
-    expected_market.amm.fee_pool.scaled_balance = SPOT_BALANCE_PRECISION;
-    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
-    expected_market.number_of_users = 0;
+    let expected_user = user;
+    let expected_market = market;
 
-    settle_pnl(
+    let result = settle_pnl(
         0,
         &mut user,
         &authority,
@@ -940,21 +931,22 @@ pub fn market_fee_pool_pays_back_to_pnl_pool() {
         &mut oracle_map,
         &clock,
         &state,
-    )
-    .unwrap();
+        SettlePnlOptions::default(),
+    );
 
+    assert_eq!(result, Err(ErrorCode::SettlePnlCooldownNotElapsed));
     assert_eq!(expected_user, user);
     assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
 
 #[test]
-pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
+pub fn user_unsettled_negative_pnl_within_settle_cooldown() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 0,
+        unix_timestamp: 100,
     };
     let state = State {
         oracle_guard_rails: OracleGuardRails {
@@ -968,7 +960,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
         },
         ..State::default()
     };
-    let mut oracle_price = get_pyth_price(150, 6);
+    let mut oracle_price = get_pyth_price(100, 6);
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
     let pyth_program = crate::ids::pyth_program::id();
@@ -989,7 +981,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
             ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
             ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
             sqrt_k: 100 * AMM_RESERVE_PRECISION,
-            peg_multiplier: 151 * PEG_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
@@ -1008,6 +1000,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1016,6 +1009,8 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        min_settle_interval: 60,
+        settle_cooldown_pnl_threshold: 100 * QUOTE_PRECISION_U64,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -1038,10 +1033,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: BASE_PRECISION_I64,
-            quote_asset_amount: -50 * QUOTE_PRECISION_I64,
-            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            quote_asset_amount: -25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
@@ -1050,23 +1042,15 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
+        last_settle_pnl_ts: 70,
+        last_settle_pnl_ts_offsets: [1, 0, 0, 0, 0, 0, 0, 0],
         ..User::default()
     };
 
     let user_key = Pubkey::default();
     let authority = Pubkey::default();
 
-    let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = -100 * QUOTE_PRECISION_I64;
-    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
-
-    let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 0;
-    expected_market.amm.quote_asset_amount = -200 * QUOTE_PRECISION_I128;
-
-    settle_pnl(
+    let result = settle_pnl(
         0,
         &mut user,
         &authority,
@@ -1076,21 +1060,22 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
         &mut oracle_map,
         &clock,
         &state,
-    )
-    .unwrap();
+        SettlePnlOptions::default(),
+    );
 
-    assert_eq!(expected_user, user);
-    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+    // losses aren't rate limited
+    assert_eq!(result, Ok(()));
+    assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
 }
 
 #[test]
-pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached() {
+pub fn user_unsettled_positive_pnl_past_settle_cooldown() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 0,
+        unix_timestamp: 100,
     };
     let state = State {
         oracle_guard_rails: OracleGuardRails {
@@ -1104,7 +1089,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
         },
         ..State::default()
     };
-    let mut oracle_price = get_pyth_price(150, 10);
+    let mut oracle_price = get_pyth_price(100, 6);
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
     let pyth_program = crate::ids::pyth_program::id();
@@ -1125,7 +1110,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
             ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
             ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
             sqrt_k: 100 * AMM_RESERVE_PRECISION,
-            peg_multiplier: 121 * PEG_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
@@ -1144,6 +1129,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1152,6 +1138,8 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        min_settle_interval: 60,
+        settle_cooldown_pnl_threshold: 100 * QUOTE_PRECISION_U64,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -1174,10 +1162,7 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: BASE_PRECISION_I64,
-            quote_asset_amount: -50 * QUOTE_PRECISION_I64,
-            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
@@ -1186,6 +1171,8 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
+        last_settle_pnl_ts: 30,
+        last_settle_pnl_ts_offsets: [1, 0, 0, 0, 0, 0, 0, 0],
         ..User::default()
     };
 
@@ -1193,16 +1180,18 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
     let authority = Pubkey::default();
 
     let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = -100 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
-    expected_user.spot_positions[0].cumulative_deposits = 50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 125 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.last_settle_pnl_ts = 100;
 
     let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 0;
-    expected_market.amm.quote_asset_amount = -200 * QUOTE_PRECISION_I128;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
 
-    assert!(settle_pnl(
+    settle_pnl(
         0,
         &mut user,
         &authority,
@@ -1212,18 +1201,22 @@ pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached()
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     )
-    .is_err());
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
 
 #[test]
-pub fn user_long_negative_unrealized_pnl() {
+pub fn user_unsettled_positive_pnl_settle_cooldown_is_per_market() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 0,
+        unix_timestamp: 100,
     };
     let state = State {
         oracle_guard_rails: OracleGuardRails {
@@ -1237,7 +1230,7 @@ pub fn user_long_negative_unrealized_pnl() {
         },
         ..State::default()
     };
-    let mut oracle_price = get_pyth_price(50, 6);
+    let mut oracle_price = get_pyth_price(100, 6);
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
     let pyth_program = crate::ids::pyth_program::id();
@@ -1258,7 +1251,7 @@ pub fn user_long_negative_unrealized_pnl() {
             ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
             ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
             sqrt_k: 100 * AMM_RESERVE_PRECISION,
-            peg_multiplier: 51 * PEG_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
@@ -1277,6 +1270,7 @@ pub fn user_long_negative_unrealized_pnl() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1285,6 +1279,8 @@ pub fn user_long_negative_unrealized_pnl() {
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        min_settle_interval: 60,
+        settle_cooldown_pnl_threshold: 100 * QUOTE_PRECISION_U64,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -1307,10 +1303,7 @@ pub fn user_long_negative_unrealized_pnl() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: BASE_PRECISION_I64,
-            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
-            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
@@ -1319,6 +1312,9 @@ pub fn user_long_negative_unrealized_pnl() {
             scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
+        // market 0 last settled 61s ago, the position at index 1 10s ago
+        last_settle_pnl_ts: 90,
+        last_settle_pnl_ts_offsets: [52, 1, 0, 0, 0, 0, 0, 0],
         ..User::default()
     };
 
@@ -1326,14 +1322,17 @@ pub fn user_long_negative_unrealized_pnl() {
     let authority = Pubkey::default();
 
     let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = -50 * QUOTE_PRECISION_I64;
-    expected_user.settled_perp_pnl = -50 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = -50 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 50 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 125 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.last_settle_pnl_ts = 100;
+    expected_user.last_settle_pnl_ts_offsets = [1, 11, 0, 0, 0, 0, 0, 0];
 
     let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
-    expected_market.amm.quote_asset_amount = -100 * QUOTE_PRECISION_I128;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
 
     settle_pnl(
         0,
@@ -1345,6 +1344,7 @@ pub fn user_long_negative_unrealized_pnl() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
     )
     .unwrap();
 
@@ -1353,7 +1353,7 @@ pub fn user_long_negative_unrealized_pnl() {
 }
 
 #[test]
-pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
+pub fn user_unsettled_pnl_perp_market_quote_not_quote_spot_market() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
@@ -1373,7 +1373,7 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
         },
         ..State::default()
     };
-    let mut oracle_price = get_pyth_price(50, 6);
+    let mut oracle_price = get_pyth_price(100, 6);
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
     let pyth_program = crate::ids::pyth_program::id();
@@ -1394,11 +1394,11 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
             ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
             ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
             sqrt_k: 100 * AMM_RESERVE_PRECISION,
-            peg_multiplier: 51 * PEG_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
-            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
@@ -1413,6 +1413,7 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1421,6 +1422,7 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
             ..PoolBalance::default()
         },
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        quote_spot_market_index: 1,
         ..PerpMarket::default()
     };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
@@ -1438,15 +1440,25 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
         ..SpotMarket::default()
     };
     create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
-    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+    let mut other_spot_market = SpotMarket {
+        market_index: 1,
+        ..spot_market
+    };
+    create_anchor_account_info!(
+        other_spot_market,
+        SpotMarket,
+        other_spot_market_account_info
+    );
+    let spot_market_map = SpotMarketMap::load_multiple(
+        vec![&spot_market_account_info, &other_spot_market_account_info],
+        true,
+    )
+    .unwrap();
 
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: -BASE_PRECISION_I64,
-            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
-            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
@@ -1461,17 +1473,9 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
     let user_key = Pubkey::default();
     let authority = Pubkey::default();
 
-    let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = 50 * QUOTE_PRECISION_I64;
-    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
-
-    let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 0;
-    expected_market.amm.quote_asset_amount = 100 * QUOTE_PRECISION_I128;
+    let expected_user = user;
 
-    settle_pnl(
+    let result = settle_pnl(
         0,
         &mut user,
         &authority,
@@ -1481,15 +1485,15 @@ pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
         &mut oracle_map,
         &clock,
         &state,
-    )
-    .unwrap();
+        SettlePnlOptions::default(),
+    );
 
+    assert_eq!(result, Err(ErrorCode::InvalidSpotMarketAccount));
     assert_eq!(expected_user, user);
-    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
 
 #[test]
-pub fn user_short_negative_unrealized_pnl() {
+pub fn user_unsettled_positive_pnl_capped_by_max_settle_amount() {
     let clock = Clock {
         slot: 0,
         epoch_start_timestamp: 0,
@@ -1534,7 +1538,7 @@ pub fn user_short_negative_unrealized_pnl() {
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
-            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
@@ -1549,6 +1553,7 @@ pub fn user_short_negative_unrealized_pnl() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1579,10 +1584,7 @@ pub fn user_short_negative_unrealized_pnl() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: -BASE_PRECISION_I64,
-            quote_asset_amount: 50 * QUOTE_PRECISION_I64,
-            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
@@ -1598,14 +1600,14 @@ pub fn user_short_negative_unrealized_pnl() {
     let authority = Pubkey::default();
 
     let mut expected_user = user;
-    expected_user.perp_positions[0].quote_asset_amount = 100 * QUOTE_PRECISION_I64;
-    expected_user.settled_perp_pnl = -50 * QUOTE_PRECISION_I64;
-    expected_user.perp_positions[0].settled_pnl = -50 * QUOTE_PRECISION_I64;
-    expected_user.spot_positions[0].scaled_balance = 50 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.perp_positions[0].quote_asset_amount = 15 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 10 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 10 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 110 * SPOT_BALANCE_PRECISION_U64;
 
     let mut expected_market = market;
-    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
-    expected_market.amm.quote_asset_amount = 200 * QUOTE_PRECISION_I128;
+    expected_market.pnl_pool.scaled_balance = 40 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -160 * QUOTE_PRECISION_I128;
 
     settle_pnl(
         0,
@@ -1617,6 +1619,10 @@ pub fn user_short_negative_unrealized_pnl() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions {
+            max_settle_amount: Some(10 * QUOTE_PRECISION),
+            ..SettlePnlOptions::default()
+        },
     )
     .unwrap();
 
@@ -1625,14 +1631,15 @@ pub fn user_short_negative_unrealized_pnl() {
 }
 
 #[test]
-pub fn user_invalid_oracle_position() {
+pub fn market_fee_pool_receives_portion() {
     let clock = Clock {
-        slot: 100000,
+        slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 19929299,
+        unix_timestamp: 0,
     };
+    let slot = clock.slot;
     let state = State {
         oracle_guard_rails: OracleGuardRails {
             validity: ValidityGuardRails {
@@ -1646,7 +1653,6 @@ pub fn user_invalid_oracle_position() {
         ..State::default()
     };
     let mut oracle_price = get_pyth_price(100, 6);
-    oracle_price.curr_slot = clock.slot - 10;
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
     let pyth_program = crate::ids::pyth_program::id();
@@ -1656,7 +1662,7 @@ pub fn user_invalid_oracle_position() {
         &pyth_program,
         oracle_account_info
     );
-    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
 
     let mut market = PerpMarket {
         amm: AMM {
@@ -1671,11 +1677,11 @@ pub fn user_invalid_oracle_position() {
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
-            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
-            curve_update_intensity: 100,
+            total_fee_minus_distributions: QUOTE_PRECISION_I128,
             historical_oracle_data: HistoricalOracleData {
                 last_oracle_price: oracle_price.agg.price,
                 last_oracle_price_twap_5min: oracle_price.agg.price,
@@ -1687,6 +1693,7 @@ pub fn user_invalid_oracle_position() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1697,6 +1704,9 @@ pub fn user_invalid_oracle_position() {
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
         ..PerpMarket::default()
     };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
     let mut spot_market = SpotMarket {
         market_index: 0,
         oracle_source: OracleSource::QuoteAsset,
@@ -1714,16 +1724,13 @@ pub fn user_invalid_oracle_position() {
     let mut user = User {
         perp_positions: get_positions(PerpPosition {
             market_index: 0,
-            base_asset_amount: -BASE_PRECISION_I64,
-            quote_asset_amount: 50 * QUOTE_PRECISION_I64,
-            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
-            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
             ..PerpPosition::default()
         }),
         spot_positions: get_spot_positions(SpotPosition {
             market_index: 0,
             balance_type: SpotBalanceType::Deposit,
-            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
             ..SpotPosition::default()
         }),
         ..User::default()
@@ -1732,21 +1739,19 @@ pub fn user_invalid_oracle_position() {
     let user_key = Pubkey::default();
     let authority = Pubkey::default();
 
-    market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min -= market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min
-        / 33;
-    create_anchor_account_info!(market, PerpMarket, market_account_info);
-    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = -100 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -100 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 100 * SPOT_BALANCE_PRECISION_U64;
 
-    let result = settle_pnl(
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 149 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.fee_pool.scaled_balance = SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    settle_pnl(
         0,
         &mut user,
         &authority,
@@ -1756,19 +1761,3624 @@ pub fn user_invalid_oracle_position() {
         &mut oracle_map,
         &clock,
         &state,
-    );
-    assert_eq!(result, Err(ErrorCode::InvalidOracle));
-
-    market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min /= 2;
-    market.amm.last_update_slot = clock.slot;
-    create_anchor_account_info!(market, PerpMarket, market_account_info);
-    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn market_fee_pool_pays_back_to_pnl_pool() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            total_fee_minus_distributions: QUOTE_PRECISION_I128,
+            fee_pool: PoolBalance {
+                scaled_balance: (2 * SPOT_BALANCE_PRECISION) as u128,
+                market_index: QUOTE_SPOT_MARKET_INDEX,
+                ..PoolBalance::default()
+            },
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 200 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = -100 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -100 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 100 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 151 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.fee_pool.scaled_balance = SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(150, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 151 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -50 * QUOTE_PRECISION_I64,
+            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = -100 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 0;
+    expected_market.amm.quote_asset_amount = -200 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_long_positive_unrealized_pnl_up_to_max_positive_pnl_price_breached() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(150, 10);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 121 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -50 * QUOTE_PRECISION_I64,
+            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = -100 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.spot_positions[0].cumulative_deposits = 50 * QUOTE_PRECISION_I64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 0;
+    expected_market.amm.quote_asset_amount = -200 * QUOTE_PRECISION_I128;
+
+    assert!(settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .is_err());
+}
+
+#[test]
+pub fn user_long_negative_unrealized_pnl() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(50, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 51 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = -50 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 50 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -100 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_short_positive_unrealized_pnl_up_to_max_positive_pnl() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(50, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 51 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 50 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 0;
+    expected_market.amm.quote_asset_amount = 100 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_short_negative_unrealized_pnl() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 50 * QUOTE_PRECISION_I64,
+            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 100 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 50 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = 200 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_invalid_oracle_position() {
+    let clock = Clock {
+        slot: 100000,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 19929299,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    oracle_price.curr_slot = clock.slot - 10;
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            curve_update_intensity: 100,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: -BASE_PRECISION_I64,
+            quote_asset_amount: 50 * QUOTE_PRECISION_I64,
+            quote_entry_amount: 50 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: 50 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min -= market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min
+        / 33;
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(result, Err(ErrorCode::InvalidOracle));
+
+    market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min /= 2;
+    market.amm.last_update_slot = clock.slot;
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(result, Err(ErrorCode::PriceBandsBreached));
+
+    market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min *= 4;
+    market.amm.last_update_slot = clock.slot;
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(result, Err(ErrorCode::PriceBandsBreached));
+
+    market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min = oracle_price.agg.price * 95 / 100;
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(result, Err(ErrorCode::InvalidOracle));
+
+    market
+        .amm
+        .historical_oracle_data
+        .last_oracle_price_twap_5min = oracle_price.agg.price - 789789;
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    assert!(market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+pub fn is_price_divergence_ok_on_invalid_oracle() {
+    let clock = Clock {
+        slot: 100000,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 19929299,
+    };
+
+    let mut oracle_price = get_pyth_price(100, 6);
+    oracle_price.curr_slot = clock.slot - 10;
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+
+    assert!(market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    market.amm.mark_std = (oracle_price.agg.price / 100) as u64;
+    market.amm.oracle_std = (oracle_price.agg.price / 190) as u64;
+
+    assert!(market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    market.amm.mark_std = (oracle_price.agg.price / 10) as u64;
+
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+
+    market.amm.oracle_std = (oracle_price.agg.price * 10) as u64;
+
+    assert!(!market
+        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
+        .unwrap());
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_multiple_markets() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    let mut market_1 = PerpMarket {
+        market_index: 1,
+        ..market
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    create_anchor_account_info!(market_1, PerpMarket, market_1_account_info);
+    let market_map =
+        PerpMarketMap::load_multiple(vec![&market_account_info, &market_1_account_info], true)
+            .unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut perp_positions = get_positions(PerpPosition {
+        market_index: 0,
+        quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+        ..PerpPosition::default()
+    });
+    perp_positions[1] = PerpPosition {
+        market_index: 1,
+        quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+        ..PerpPosition::default()
+    };
+    let mut user = User {
+        perp_positions,
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[1].quote_asset_amount = 0;
+    expected_user.perp_positions[1].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    let mut expected_market_1 = market_1;
+    expected_market_1.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market_1.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market_1.number_of_users = 0;
+
+    // market 2 has no position and is skipped
+    settle_pnl_for_markets(
+        &[0, 1, 2],
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+    assert_eq!(expected_market_1, *market_map.get_ref(&1).unwrap());
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_multiple_markets_skips_failed_market() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    let mut market_1 = PerpMarket {
+        market_index: 1,
+        status: MarketStatus::Initialized,
+        ..market
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    create_anchor_account_info!(market_1, PerpMarket, market_1_account_info);
+    let market_map =
+        PerpMarketMap::load_multiple(vec![&market_account_info, &market_1_account_info], true)
+            .unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut perp_positions = get_positions(PerpPosition {
+        market_index: 0,
+        quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+        ..PerpPosition::default()
+    });
+    perp_positions[1] = PerpPosition {
+        market_index: 1,
+        quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+        ..PerpPosition::default()
+    };
+    let mut user = User {
+        perp_positions,
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 125 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    // market 1 can't settle pnl while initialized, the rest of the batch still settles
+    let expected_market_1 = market_1;
+
+    settle_pnl_for_markets(
+        &[1, 0],
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+    assert_eq!(expected_market_1, *market_map.get_ref(&1).unwrap());
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_charged_settlement_fee() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let fee_structure = FeeStructure::perps_default(); // 10 bps
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    // settled pnl records the 25 taken out of quote_asset_amount, including the fee
+    expected_user.settled_perp_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 124975000000;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.amm.fee_pool.scaled_balance = 25000000;
+    expected_market.amm.total_fee = 25000;
+    expected_market.amm.total_fee_minus_distributions = 25000;
+    expected_market.number_of_users = 0;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            fee_structure: Some(&fee_structure),
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_unsettled_negative_pnl_not_charged_settlement_fee() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -50 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let fee_structure = FeeStructure::perps_default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = -50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 50 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 100 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -100 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            fee_structure: Some(&fee_structure),
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn settle_expired_position_expiry_price_not_set() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        expiry_price: 0,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    let result = settle_expired_position(
+        0,
+        &mut user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        0,
+    );
+
+    assert_eq!(result, Err(ErrorCode::PerpMarketExpiryPriceNotSet));
+}
+
+#[test]
+pub fn settle_expired_position_sweeps_quote_dust() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 1,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        expiry_price: 100 * PRICE_PRECISION_I64,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            // $.01 of pnl left on the position
+            quote_asset_amount: 10000,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    settle_expired_position(
+        0,
+        &mut user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        20000,
+    )
+    .unwrap();
+
+    // quote deposit is untouched
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        100 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert_eq!(user.perp_positions[0].settled_pnl, 0);
+    assert!(user.perp_positions[0].is_available());
+
+    let market = market_map.get_ref(&0).unwrap();
+    assert_eq!(market.pnl_pool.scaled_balance, 49990000000);
+    assert_eq!(market.amm.fee_pool.scaled_balance, 10000000);
+    assert_eq!(market.amm.total_fee, 10000);
+    assert_eq!(market.amm.total_fee_minus_distributions, 10000);
+}
+
+#[test]
+pub fn settle_expired_position_leaves_quote_deposit_below_dust_threshold() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 1,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        expiry_price: 100 * PRICE_PRECISION_I64,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -99990000,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    settle_expired_position(
+        0,
+        &mut user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        20000,
+    )
+    .unwrap();
+
+    // the $.01 deposit left after paying the loss stays with the user
+    assert_eq!(user.spot_positions[0].scaled_balance, 10000000);
+    assert!(user.perp_positions[0].is_available());
+
+    let market = market_map.get_ref(&0).unwrap();
+    assert_eq!(market.amm.fee_pool.scaled_balance, 0);
+    assert_eq!(market.amm.total_fee, 0);
+    assert_eq!(market.amm.total_fee_minus_distributions, 0);
+}
+
+#[test]
+pub fn settle_expired_position_perp_market_quote_not_quote_spot_market() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 1,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        expiry_price: 100 * PRICE_PRECISION_I64,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        quote_spot_market_index: 1,
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let mut other_spot_market = SpotMarket {
+        market_index: 1,
+        ..spot_market
+    };
+    create_anchor_account_info!(
+        other_spot_market,
+        SpotMarket,
+        other_spot_market_account_info
+    );
+    let spot_market_map = SpotMarketMap::load_multiple(
+        vec![&spot_market_account_info, &other_spot_market_account_info],
+        true,
+    )
+    .unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: -99990000,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    let result = settle_expired_position(
+        0,
+        &mut user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        20000,
+    );
+
+    assert_eq!(result, Err(ErrorCode::InvalidSpotMarketAccount));
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        100 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert!(!user.perp_positions[0].is_available());
+}
+
+#[test]
+pub fn settle_expired_position_with_twap() {
+    fn settle(use_twap: bool) -> User {
+        let clock = Clock {
+            slot: 0,
+            epoch_start_timestamp: 0,
+            epoch: 0,
+            leader_schedule_epoch: 0,
+            unix_timestamp: 1,
+        };
+        let state = State {
+            oracle_guard_rails: OracleGuardRails {
+                validity: ValidityGuardRails {
+                    slots_before_stale_for_amm: 10,     // 5s
+                    slots_before_stale_for_margin: 120, // 60s
+                    confidence_interval_max_size: 1000,
+                    too_volatile_ratio: 5,
+                },
+                ..OracleGuardRails::default()
+            },
+            ..State::default()
+        };
+        let mut oracle_price = get_pyth_price(100, 6);
+        let oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            oracle_price,
+            &oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+        let mut market = PerpMarket {
+            amm: AMM {
+                base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+                bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+                ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+                sqrt_k: 100 * AMM_RESERVE_PRECISION,
+                peg_multiplier: 100 * PEG_PRECISION,
+                max_slippage_ratio: 50,
+                max_fill_reserve_fraction: 100,
+                order_step_size: 10000000,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I128,
+                base_asset_amount_with_amm: BASE_PRECISION_I128,
+                base_asset_amount_long: BASE_PRECISION_I128,
+                oracle: oracle_price_key,
+                historical_oracle_data: HistoricalOracleData {
+                    last_oracle_price: oracle_price.agg.price,
+                    last_oracle_price_twap_5min: oracle_price.agg.price,
+                    last_oracle_price_twap: 110 * PRICE_PRECISION_I64,
+                    ..HistoricalOracleData::default()
+                },
+                ..AMM::default()
+            },
+            margin_ratio_initial: 1000,
+            margin_ratio_maintenance: 500,
+            number_of_users_with_base: 1,
+            number_of_users: 1,
+            status: MarketStatus::Settlement,
+            expiry_price: 100 * PRICE_PRECISION_I64,
+            use_twap_for_expiry: use_twap,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+            pnl_pool: PoolBalance {
+                scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+                market_index: QUOTE_SPOT_MARKET_INDEX,
+                ..PoolBalance::default()
+            },
+            unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+            ..PerpMarket::default()
+        };
+        create_anchor_account_info!(market, PerpMarket, market_account_info);
+        let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+        let mut spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 150 * SPOT_BALANCE_PRECISION,
+            historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+        let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+        let mut user = User {
+            perp_positions: get_positions(PerpPosition {
+                market_index: 0,
+                base_asset_amount: BASE_PRECISION_I64,
+                quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+                quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+                quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+                ..PerpPosition::default()
+            }),
+            spot_positions: get_spot_positions(SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            }),
+            ..User::default()
+        };
+
+        let user_key = Pubkey::default();
+
+        settle_expired_position(
+            0,
+            &mut user,
+            &user_key,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            &clock,
+            &state,
+            0,
+        )
+        .unwrap();
+
+        assert!(user.perp_positions[0].is_available());
+
+        user
+    }
+
+    // settles at expiry_price of $100, only pays the 10bps fee
+    let user = settle(false);
+    assert_eq!(user.spot_positions[0].scaled_balance, 99900000000);
+
+    // settles at twap of $110, gains $10 less the 10bps fee
+    let user = settle(true);
+    assert_eq!(user.spot_positions[0].scaled_balance, 109890000000);
+}
+
+#[test]
+pub fn settle_expired_position_pays_pnl_above_dust_threshold() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 1,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        expiry_price: 100 * PRICE_PRECISION_I64,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 30000,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    settle_expired_position(
+        0,
+        &mut user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        20000,
+    )
+    .unwrap();
+
+    assert_eq!(user.spot_positions[0].scaled_balance, 100030000000);
+    assert!(user.perp_positions[0].is_available());
+
+    let market = market_map.get_ref(&0).unwrap();
+    assert_eq!(market.amm.fee_pool.scaled_balance, 0);
+    assert_eq!(market.amm.total_fee, 0);
+}
+
+#[test]
+pub fn user_unsettled_pnl_stale_oracle() {
+    let clock = Clock {
+        slot: 100,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    // oracle last updated at slot 0
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            max_oracle_delay_slots: Some(10),
+            ..SettlePnlOptions::default()
+        },
+    );
+
+    assert_eq!(result, Err(ErrorCode::InvalidOracleForSettlePnl));
+
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            max_oracle_delay_slots: Some(100),
+            ..SettlePnlOptions::default()
+        },
+    );
+
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_settled_by_authority_or_delegate() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 0,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 3,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (100 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        authority: Pubkey::new_unique(),
+        delegate: Pubkey::new_unique(),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+
+    // pnl pool not in excess, so only the authority or delegate can settle positive pnl
+    let mut user_settled_by_authority = user;
+    let authority = user.authority;
+    settle_pnl(
+        0,
+        &mut user_settled_by_authority,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        user_settled_by_authority.perp_positions[0].quote_asset_amount,
+        0
+    );
+
+    let mut user_settled_by_delegate = user;
+    let delegate = user.delegate;
+    settle_pnl(
+        0,
+        &mut user_settled_by_delegate,
+        &delegate,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        user_settled_by_delegate.perp_positions[0].quote_asset_amount,
+        0
+    );
+
+    let mut user_settled_by_unrelated_key = user;
+    let unrelated_key = Pubkey::new_unique();
+    let result = settle_pnl(
+        0,
+        &mut user_settled_by_unrelated_key,
+        &unrelated_key,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    );
+    assert_eq!(
+        result,
+        Err(ErrorCode::UserMustSettleTheirOwnPositiveUnsettledPNL)
+    );
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_socialized_loss_in_reduce_only_market() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            // another user is long 1 with 100 of cost basis, net user pnl is this user's 100
+            quote_asset_amount: 0,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::ReduceOnly,
+        socialized_loss_enabled: true,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = 100 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 100 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+    expected_user.total_social_loss = 50 * QUOTE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 0;
+    expected_market.amm.quote_asset_amount = -100 * QUOTE_PRECISION_I128;
+    // pool can only cover half of the net user pnl
+    expected_market.socialized_loss_ratio = 500000;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_socialized_loss_applied_once_across_settles() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            // net user pnl of 200, another user is long 1 with 100 of unsettled pnl
+            quote_asset_amount: 100 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::ReduceOnly,
+        socialized_loss_enabled: true,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (100 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    // settle 40 of the 100 pnl, half is written off
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            max_settle_amount: Some(40 * QUOTE_PRECISION),
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        user.perp_positions[0].quote_asset_amount,
+        60 * QUOTE_PRECISION_I64
+    );
+    assert_eq!(user.perp_positions[0].settled_pnl, 40 * QUOTE_PRECISION_I64);
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        120 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert_eq!(user.total_social_loss, 20 * QUOTE_PRECISION_U64);
+    {
+        let market = market_map.get_ref(&0).unwrap();
+        assert_eq!(market.socialized_loss_ratio, 500000);
+        assert_eq!(
+            market.pnl_pool.scaled_balance,
+            (80 * SPOT_BALANCE_PRECISION) as u128
+        );
+    }
+
+    // settling the rest only writes off the remaining 60 once
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+    assert_eq!(user.perp_positions[0].settled_pnl, 100 * QUOTE_PRECISION_I64);
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        150 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert_eq!(user.total_social_loss, 50 * QUOTE_PRECISION_U64);
+    {
+        let market = market_map.get_ref(&0).unwrap();
+        assert_eq!(market.socialized_loss_ratio, 500000);
+        assert_eq!(
+            market.pnl_pool.scaled_balance,
+            (50 * SPOT_BALANCE_PRECISION) as u128
+        );
+        assert_eq!(market.amm.quote_asset_amount, 0);
+    }
+
+    // pool refilled past the net user pnl, the ratio resets and pnl is paid in full
+    market_map.get_ref_mut(&0).unwrap().pnl_pool.scaled_balance =
+        (200 * SPOT_BALANCE_PRECISION) as u128;
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 20 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+    assert_eq!(
+        user.spot_positions[0].scaled_balance,
+        120 * SPOT_BALANCE_PRECISION_U64
+    );
+    assert_eq!(user.total_social_loss, 0);
+    assert_eq!(market_map.get_ref(&0).unwrap().socialized_loss_ratio, 0);
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_no_socialized_loss_unless_enabled() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            // another user is long 1 with 100 of cost basis, net user pnl is this user's 100
+            quote_asset_amount: 0,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::ReduceOnly,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 50 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 50 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 150 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 0;
+    expected_market.amm.quote_asset_amount = -50 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+}
+
+#[test]
+pub fn preview_settle_pnl_matches_settle_pnl() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 0;
+    expected_user.settled_perp_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 25 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 125 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 25 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -175 * QUOTE_PRECISION_I128;
+    expected_market.number_of_users = 0;
+
+    let preview = preview_settle_pnl(
+        0,
+        &user,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        clock.unix_timestamp,
+    )
+    .unwrap();
+
+    // preview doesnt mutate the user or market
+    assert_eq!(market.pnl_pool, market_map.get_ref(&0).unwrap().pnl_pool);
+    assert_eq!(
+        user.perp_positions[0].quote_asset_amount,
+        25 * QUOTE_PRECISION_I64
+    );
+
+    assert_eq!(
+        preview,
+        SettlePnlPreview {
+            user_unsettled_pnl: 25 * QUOTE_PRECISION_I128,
+            pnl_to_settle_with_user: 25 * QUOTE_PRECISION_I128,
+            meets_margin_requirement: true,
+        }
+    );
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
+    assert_eq!(
+        preview.pnl_to_settle_with_user,
+        user.settled_perp_pnl.cast::<i128>().unwrap()
+    );
+}
+
+#[test]
+pub fn user_unsettled_pnl_market_in_settlement() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Settlement,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
 
     let result = settle_pnl(
         0,
@@ -1780,23 +5390,297 @@ pub fn user_invalid_oracle_position() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap_err();
+
+    assert_eq!(result, ErrorCode::InvalidMarketStatusToSettlePnl);
+}
+
+#[test]
+pub fn settled_pnl_accumulates_across_settlements() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
+
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: 0,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 3,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (100 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            max_settle_amount: Some(10 * QUOTE_PRECISION),
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(user.perp_positions[0].settled_pnl, 10 * QUOTE_PRECISION_I64);
+
+    settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    // 10 + 15
+    assert_eq!(user.perp_positions[0].quote_asset_amount, 0);
+    assert_eq!(user.perp_positions[0].settled_pnl, 25 * QUOTE_PRECISION_I64);
+    assert_eq!(user.settled_perp_pnl, 25 * QUOTE_PRECISION_I64);
+}
+
+#[test]
+pub fn user_long_negative_unrealized_pnl_settled_at_mark_price() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(50, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
     );
-    assert_eq!(result, Err(ErrorCode::PriceBandsBreached));
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
-    market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min *= 4;
-    market.amm.last_update_slot = clock.slot;
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 50 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            last_mark_price_twap: 51 * PRICE_PRECISION_U64,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
     let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
 
-    let result = settle_pnl(
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            base_asset_amount: BASE_PRECISION_I64,
+            quote_asset_amount: -100 * QUOTE_PRECISION_I64,
+            quote_entry_amount: -100 * QUOTE_PRECISION_I64,
+            quote_break_even_amount: -100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut user_settled_at_oracle = user;
+    settle_pnl(
         0,
-        &mut user,
+        &mut user_settled_at_oracle,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions::default(),
+    )
+    .unwrap();
+
+    // oracle price is 50, pnl is -50
+    assert_eq!(
+        user_settled_at_oracle.perp_positions[0].settled_pnl,
+        -50 * QUOTE_PRECISION_I64
+    );
+    assert_eq!(
+        user_settled_at_oracle.perp_positions[0].quote_asset_amount,
+        -50 * QUOTE_PRECISION_I64
+    );
+
+    let mut user_settled_at_mark = user;
+    settle_pnl(
+        0,
+        &mut user_settled_at_mark,
         &authority,
         &user_key,
         &market_map,
@@ -1804,43 +5688,142 @@ pub fn user_invalid_oracle_position() {
         &mut oracle_map,
         &clock,
         &state,
+        SettlePnlOptions {
+            settlement_price_source: PriceSource::Mark,
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+
+    // mark twap is 51 while the reserve price is 50, pnl is -49
+    assert_eq!(
+        user_settled_at_mark.perp_positions[0].settled_pnl,
+        -49 * QUOTE_PRECISION_I64
+    );
+    assert_eq!(
+        user_settled_at_mark.perp_positions[0].quote_asset_amount,
+        -51 * QUOTE_PRECISION_I64
+    );
+}
+
+#[test]
+pub fn user_unsettled_positive_pnl_settled_to_quote_target() {
+    let clock = Clock {
+        slot: 0,
+        epoch_start_timestamp: 0,
+        epoch: 0,
+        leader_schedule_epoch: 0,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
+    };
+    let mut oracle_price = get_pyth_price(100, 6);
+    let oracle_price_key =
+        Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
     );
-    assert_eq!(result, Err(ErrorCode::PriceBandsBreached));
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
-    market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min = oracle_price.agg.price * 95 / 100;
+    let mut market = PerpMarket {
+        amm: AMM {
+            base_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            quote_asset_reserve: 100 * AMM_RESERVE_PRECISION,
+            bid_base_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            bid_quote_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_base_asset_reserve: 99 * AMM_RESERVE_PRECISION,
+            ask_quote_asset_reserve: 101 * AMM_RESERVE_PRECISION,
+            sqrt_k: 100 * AMM_RESERVE_PRECISION,
+            peg_multiplier: 100 * PEG_PRECISION,
+            max_slippage_ratio: 50,
+            max_fill_reserve_fraction: 100,
+            order_step_size: 10000000,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            base_asset_amount_with_amm: BASE_PRECISION_I128,
+            base_asset_amount_long: BASE_PRECISION_I128,
+            oracle: oracle_price_key,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price: oracle_price.agg.price,
+                last_oracle_price_twap_5min: oracle_price.agg.price,
+                last_oracle_price_twap: oracle_price.agg.price,
+                ..HistoricalOracleData::default()
+            },
+            ..AMM::default()
+        },
+        margin_ratio_initial: 1000,
+        margin_ratio_maintenance: 500,
+        number_of_users_with_base: 1,
+        number_of_users: 1,
+        status: MarketStatus::Active,
+        liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
+        ..PerpMarket::default()
+    };
     create_anchor_account_info!(market, PerpMarket, market_account_info);
     let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
 
-    let result = settle_pnl(
-        0,
-        &mut user,
-        &authority,
-        &user_key,
-        &market_map,
-        &spot_market_map,
-        &mut oracle_map,
-        &clock,
-        &state,
-    );
-    assert_eq!(result, Err(ErrorCode::InvalidOracle));
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-    market
-        .amm
-        .historical_oracle_data
-        .last_oracle_price_twap_5min = oracle_price.agg.price - 789789;
-    create_anchor_account_info!(market, PerpMarket, market_account_info);
-    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 25 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
 
-    assert!(market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
-    let result = settle_pnl(
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
+
+    let mut expected_user = user;
+    expected_user.perp_positions[0].quote_asset_amount = 10 * QUOTE_PRECISION_I64;
+    expected_user.settled_perp_pnl = 15 * QUOTE_PRECISION_I64;
+    expected_user.perp_positions[0].settled_pnl = 15 * QUOTE_PRECISION_I64;
+    expected_user.spot_positions[0].scaled_balance = 115 * SPOT_BALANCE_PRECISION_U64;
+
+    let mut expected_market = market;
+    expected_market.pnl_pool.scaled_balance = 35 * SPOT_BALANCE_PRECISION;
+    expected_market.amm.quote_asset_amount = -165 * QUOTE_PRECISION_I128;
+
+    settle_pnl(
         0,
         &mut user,
         &authority,
@@ -1850,24 +5833,49 @@ pub fn user_invalid_oracle_position() {
         &mut oracle_map,
         &clock,
         &state,
-    );
-    assert_eq!(result, Ok(()));
+        SettlePnlOptions {
+            settle_to_quote_target: Some(10 * QUOTE_PRECISION_I128),
+            ..SettlePnlOptions::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(expected_user, user);
+    assert_eq!(expected_market, *market_map.get_ref(&0).unwrap());
 }
 
 #[test]
-pub fn is_price_divergence_ok_on_invalid_oracle() {
+pub fn user_unsettled_positive_pnl_quote_target_more_than_pool() {
     let clock = Clock {
-        slot: 100000,
+        slot: 0,
         epoch_start_timestamp: 0,
         epoch: 0,
         leader_schedule_epoch: 0,
-        unix_timestamp: 19929299,
+        unix_timestamp: 0,
+    };
+    let state = State {
+        oracle_guard_rails: OracleGuardRails {
+            validity: ValidityGuardRails {
+                slots_before_stale_for_amm: 10,     // 5s
+                slots_before_stale_for_margin: 120, // 60s
+                confidence_interval_max_size: 1000,
+                too_volatile_ratio: 5,
+            },
+            ..OracleGuardRails::default()
+        },
+        ..State::default()
     };
-
     let mut oracle_price = get_pyth_price(100, 6);
-    oracle_price.curr_slot = clock.slot - 10;
     let oracle_price_key =
         Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+    let pyth_program = crate::ids::pyth_program::id();
+    create_account_info!(
+        oracle_price,
+        &oracle_price_key,
+        &pyth_program,
+        oracle_account_info
+    );
+    let mut oracle_map = OracleMap::load_one(&oracle_account_info, clock.slot, None).unwrap();
 
     let mut market = PerpMarket {
         amm: AMM {
@@ -1882,7 +5890,7 @@ pub fn is_price_divergence_ok_on_invalid_oracle() {
             max_slippage_ratio: 50,
             max_fill_reserve_fraction: 100,
             order_step_size: 10000000,
-            quote_asset_amount: 150 * QUOTE_PRECISION_I128,
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
             base_asset_amount_with_amm: BASE_PRECISION_I128,
             base_asset_amount_long: BASE_PRECISION_I128,
             oracle: oracle_price_key,
@@ -1897,6 +5905,7 @@ pub fn is_price_divergence_ok_on_invalid_oracle() {
         margin_ratio_initial: 1000,
         margin_ratio_maintenance: 500,
         number_of_users_with_base: 1,
+        number_of_users: 1,
         status: MarketStatus::Active,
         liquidator_fee: LIQUIDATION_FEE_PRECISION / 100,
         pnl_pool: PoolBalance {
@@ -1907,27 +5916,114 @@ pub fn is_price_divergence_ok_on_invalid_oracle() {
         unrealized_pnl_maintenance_asset_weight: SPOT_WEIGHT_PRECISION.cast().unwrap(),
         ..PerpMarket::default()
     };
+    create_anchor_account_info!(market, PerpMarket, market_account_info);
+    let market_map = PerpMarketMap::load_one(&market_account_info, true).unwrap();
 
-    assert!(market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+    let mut spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        initial_asset_weight: SPOT_WEIGHT_PRECISION,
+        maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        historical_oracle_data: HistoricalOracleData::default_price(QUOTE_PRECISION_I64),
+        ..SpotMarket::default()
+    };
+    create_anchor_account_info!(spot_market, SpotMarket, spot_market_account_info);
+    let spot_market_map = SpotMarketMap::load_one(&spot_market_account_info, true).unwrap();
 
-    market.amm.mark_std = (oracle_price.agg.price / 100) as u64;
-    market.amm.oracle_std = (oracle_price.agg.price / 190) as u64;
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
 
-    assert!(market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+    let user_key = Pubkey::default();
+    let authority = Pubkey::default();
 
-    market.amm.mark_std = (oracle_price.agg.price / 10) as u64;
+    let result = settle_pnl(
+        0,
+        &mut user,
+        &authority,
+        &user_key,
+        &market_map,
+        &spot_market_map,
+        &mut oracle_map,
+        &clock,
+        &state,
+        SettlePnlOptions {
+            settle_to_quote_target: Some(0),
+            ..SettlePnlOptions::default()
+        },
+    );
 
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+    // pnl pool only has 50 of the 100 needed to reach the target
+    assert_eq!(result, Err(ErrorCode::QuoteTargetUnreachableForSettlePnl));
+}
 
-    market.amm.oracle_std = (oracle_price.agg.price * 10) as u64;
+#[test]
+pub fn user_unsettleable_positive_pnl_more_than_pool() {
+    let market = PerpMarket {
+        market_index: 0,
+        amm: AMM {
+            quote_asset_amount: -150 * QUOTE_PRECISION_I128,
+            ..AMM::default()
+        },
+        status: MarketStatus::Active,
+        pnl_pool: PoolBalance {
+            scaled_balance: (50 * SPOT_BALANCE_PRECISION) as u128,
+            market_index: QUOTE_SPOT_MARKET_INDEX,
+            ..PoolBalance::default()
+        },
+        ..PerpMarket::default()
+    };
 
-    assert!(!market
-        .is_price_divergence_ok_for_settle_pnl(oracle_price.agg.price)
-        .unwrap());
+    let spot_market = SpotMarket {
+        market_index: 0,
+        oracle_source: OracleSource::QuoteAsset,
+        cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+        decimals: 6,
+        deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+        ..SpotMarket::default()
+    };
+
+    let mut user = User {
+        perp_positions: get_positions(PerpPosition {
+            market_index: 0,
+            quote_asset_amount: 100 * QUOTE_PRECISION_I64,
+            ..PerpPosition::default()
+        }),
+        spot_positions: get_spot_positions(SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        }),
+        ..User::default()
+    };
+
+    // 100 unsettled, pool can only cover 50
+    let unsettleable_pnl =
+        get_unsettleable_pnl(&user, &market, &spot_market, 100 * PRICE_PRECISION_I64, 0).unwrap();
+    assert_eq!(unsettleable_pnl, 50 * QUOTE_PRECISION_I128);
+
+    user.perp_positions[0].quote_asset_amount = 25 * QUOTE_PRECISION_I64;
+    let unsettleable_pnl =
+        get_unsettleable_pnl(&user, &market, &spot_market, 100 * PRICE_PRECISION_I64, 0).unwrap();
+    assert_eq!(unsettleable_pnl, 0);
+
+    user.perp_positions[0].quote_asset_amount = -25 * QUOTE_PRECISION_I64;
+    let unsettleable_pnl =
+        get_unsettleable_pnl(&user, &market, &spot_market, 100 * PRICE_PRECISION_I64, 0).unwrap();
+    assert_eq!(unsettleable_pnl, 0);
 }
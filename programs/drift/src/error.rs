@@ -523,6 +523,22 @@ pub enum ErrorCode {
     CantReclaimRent,
     #[msg("InsuranceFundOperationPaused")]
     InsuranceFundOperationPaused,
+    #[msg("PerpMarketExpiryPriceNotSet")]
+    PerpMarketExpiryPriceNotSet,
+    #[msg("CantMatchSameUser")]
+    CantMatchSameUser,
+    #[msg("InvalidBaseDecimals")]
+    InvalidBaseDecimals,
+    #[msg("OrderMarketMismatch")]
+    OrderMarketMismatch,
+    #[msg("OrdersOnSameSide")]
+    OrdersOnSameSide,
+    #[msg("QuoteTargetUnreachableForSettlePnl")]
+    QuoteTargetUnreachableForSettlePnl,
+    #[msg("SettlePnlCooldownNotElapsed")]
+    SettlePnlCooldownNotElapsed,
+    #[msg("OrderBelowMinNotional")]
+    OrderBelowMinNotional,
 }
 
 #[macro_export]
@@ -675,7 +675,13 @@ pub fn handle_initialize_perp_market(
         paused_operations: 0,
         quote_spot_market_index: QUOTE_SPOT_MARKET_INDEX,
         fee_adjustment: 0,
-        padding: [0; 46],
+        padding1: 0,
+        socialized_loss_ratio: 0,
+        settle_cooldown_pnl_threshold: 0,
+        min_settle_interval: 0,
+        socialized_loss_enabled: false,
+        use_twap_for_expiry: false,
+        padding: [0; 26],
         amm: AMM {
             oracle: *ctx.accounts.oracle.key,
             oracle_source,
@@ -1980,6 +1986,12 @@ pub fn handle_update_perp_market_status(
 
     let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
     perp_market.status = status;
+
+    // pnl is only socialized in reduce only markets
+    if status != MarketStatus::ReduceOnly {
+        perp_market.socialized_loss_ratio = 0;
+    }
+
     Ok(())
 }
 
@@ -2451,6 +2463,85 @@ pub fn handle_update_perp_market_fee_adjustment(
     Ok(())
 }
 
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_settle_cooldown(
+    ctx: Context<AdminUpdatePerpMarket>,
+    min_settle_interval: u32,
+    settle_cooldown_pnl_threshold: u64,
+) -> Result<()> {
+    // user settle times are only kept for u8::MAX seconds
+    validate!(
+        min_settle_interval <= u8::MAX as u32,
+        ErrorCode::DefaultError,
+        "min_settle_interval must be at most {} seconds",
+        u8::MAX
+    )?;
+
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    msg!(
+        "perp_market.min_settle_interval: {:?} -> {:?}",
+        perp_market.min_settle_interval,
+        min_settle_interval
+    );
+
+    msg!(
+        "perp_market.settle_cooldown_pnl_threshold: {:?} -> {:?}",
+        perp_market.settle_cooldown_pnl_threshold,
+        settle_cooldown_pnl_threshold
+    );
+
+    perp_market.min_settle_interval = min_settle_interval;
+    perp_market.settle_cooldown_pnl_threshold = settle_cooldown_pnl_threshold;
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_socialized_loss_enabled(
+    ctx: Context<AdminUpdatePerpMarket>,
+    socialized_loss_enabled: bool,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    msg!(
+        "perp_market.socialized_loss_enabled: {:?} -> {:?}",
+        perp_market.socialized_loss_enabled,
+        socialized_loss_enabled
+    );
+
+    perp_market.socialized_loss_enabled = socialized_loss_enabled;
+
+    if !socialized_loss_enabled {
+        perp_market.socialized_loss_ratio = 0;
+    }
+
+    Ok(())
+}
+
+#[access_control(
+    perp_market_valid(&ctx.accounts.perp_market)
+)]
+pub fn handle_update_perp_market_use_twap_for_expiry(
+    ctx: Context<AdminUpdatePerpMarket>,
+    use_twap_for_expiry: bool,
+) -> Result<()> {
+    let perp_market = &mut load_mut!(ctx.accounts.perp_market)?;
+
+    msg!(
+        "perp_market.use_twap_for_expiry: {:?} -> {:?}",
+        perp_market.use_twap_for_expiry,
+        use_twap_for_expiry
+    );
+
+    perp_market.use_twap_for_expiry = use_twap_for_expiry;
+
+    Ok(())
+}
+
 pub fn handle_update_perp_market_number_of_users(
     ctx: Context<AdminUpdatePerpMarket>,
     number_of_users: Option<u32>,
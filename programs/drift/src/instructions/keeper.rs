@@ -1,6 +1,7 @@
 use anchor_lang::prelude::*;
 use anchor_spl::token::{Token, TokenAccount};
 
+use crate::controller::pnl::SettlePnlOptions;
 use crate::error::ErrorCode;
 use crate::instructions::constraints::*;
 use crate::instructions::optional_accounts::{load_maps, AccountMaps};
@@ -440,6 +441,7 @@ pub fn handle_settle_pnl(ctx: Context<SettlePNL>, market_index: u16) -> Result<(
             &mut oracle_map,
             &clock,
             state,
+            0,
         )?;
 
         user.update_last_active_slot(clock.slot);
@@ -463,6 +465,7 @@ pub fn handle_settle_pnl(ctx: Context<SettlePNL>, market_index: u16) -> Result<(
             &mut oracle_map,
             &clock,
             state,
+            SettlePnlOptions::default(),
         )
         .map(|_| ErrorCode::InvalidOracleForSettlePnl)?;
 
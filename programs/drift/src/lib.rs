@@ -1164,6 +1164,32 @@ pub mod drift {
         handle_update_perp_market_fee_adjustment(ctx, fee_adjustment)
     }
 
+    pub fn update_perp_market_settle_cooldown(
+        ctx: Context<AdminUpdatePerpMarket>,
+        min_settle_interval: u32,
+        settle_cooldown_pnl_threshold: u64,
+    ) -> Result<()> {
+        handle_update_perp_market_settle_cooldown(
+            ctx,
+            min_settle_interval,
+            settle_cooldown_pnl_threshold,
+        )
+    }
+
+    pub fn update_perp_market_socialized_loss_enabled(
+        ctx: Context<AdminUpdatePerpMarket>,
+        socialized_loss_enabled: bool,
+    ) -> Result<()> {
+        handle_update_perp_market_socialized_loss_enabled(ctx, socialized_loss_enabled)
+    }
+
+    pub fn update_perp_market_use_twap_for_expiry(
+        ctx: Context<AdminUpdatePerpMarket>,
+        use_twap_for_expiry: bool,
+    ) -> Result<()> {
+        handle_update_perp_market_use_twap_for_expiry(ctx, use_twap_for_expiry)
+    }
+
     pub fn update_spot_market_fee_adjustment(
         ctx: Context<AdminUpdateSpotMarket>,
         fee_adjustment: i16,
@@ -332,6 +332,63 @@ mod calculate_auction_price {
         assert_eq!(price, 8 * PRICE_PRECISION_U64 / 10);
     }
 
+    #[test]
+    fn long_market_order() {
+        let tick_size = 1;
+
+        // auction from $100 to $102 over slots 10 to 20
+        let order = Order {
+            order_type: OrderType::Market,
+            direction: PositionDirection::Long,
+            auction_duration: 10,
+            slot: 10,
+            auction_start_price: 100 * PRICE_PRECISION_I64,
+            auction_end_price: 102 * PRICE_PRECISION_I64,
+            ..Order::default()
+        };
+
+        let price = calculate_auction_price(&order, 10, tick_size, None).unwrap();
+        assert_eq!(price, 100 * PRICE_PRECISION_U64);
+
+        let price = calculate_auction_price(&order, 15, tick_size, None).unwrap();
+        assert_eq!(price, 101 * PRICE_PRECISION_U64);
+
+        let price = calculate_auction_price(&order, 20, tick_size, None).unwrap();
+        assert_eq!(price, 102 * PRICE_PRECISION_U64);
+
+        // clamped to the end price after the auction
+        let price = calculate_auction_price(&order, 30, tick_size, None).unwrap();
+        assert_eq!(price, 102 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn short_market_order() {
+        let tick_size = 1;
+
+        // auction from $100 to $98 over slots 10 to 20
+        let order = Order {
+            order_type: OrderType::Market,
+            direction: PositionDirection::Short,
+            auction_duration: 10,
+            slot: 10,
+            auction_start_price: 100 * PRICE_PRECISION_I64,
+            auction_end_price: 98 * PRICE_PRECISION_I64,
+            ..Order::default()
+        };
+
+        let price = calculate_auction_price(&order, 10, tick_size, None).unwrap();
+        assert_eq!(price, 100 * PRICE_PRECISION_U64);
+
+        let price = calculate_auction_price(&order, 15, tick_size, None).unwrap();
+        assert_eq!(price, 99 * PRICE_PRECISION_U64);
+
+        let price = calculate_auction_price(&order, 20, tick_size, None).unwrap();
+        assert_eq!(price, 98 * PRICE_PRECISION_U64);
+
+        let price = calculate_auction_price(&order, 30, tick_size, None).unwrap();
+        assert_eq!(price, 98 * PRICE_PRECISION_U64);
+    }
+
     #[test]
     fn same_auction_start_and_end() {
         let tick_size = 1;
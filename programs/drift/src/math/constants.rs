@@ -40,6 +40,7 @@ pub const MARGIN_PRECISION_U128: u128 = 10_000; // expo = -4
 pub const SPOT_WEIGHT_PRECISION: u32 = MARGIN_PRECISION; // expo = -4
 pub const SPOT_WEIGHT_PRECISION_U128: u128 = SPOT_WEIGHT_PRECISION as u128; // expo = -4
 pub const SPOT_WEIGHT_PRECISION_I128: i128 = SPOT_WEIGHT_PRECISION as i128; // expo = -4
+pub const LEVERAGE_PRECISION: u128 = 100; // expo = -2
 
 pub const LIQUIDATION_PCT_PRECISION: u128 = 10_000;
 
@@ -113,6 +114,11 @@ pub const SHARE_OF_IF_ESCROW_ALLOCATED_TO_PROTOCOL_DENOMINATOR: u128 = 2;
 pub const SHARE_OF_REVENUE_ALLOCATED_TO_INSURANCE_FUND_VAULT_NUMERATOR: u128 = 1;
 pub const SHARE_OF_REVENUE_ALLOCATED_TO_INSURANCE_FUND_VAULT_DENOMINATOR: u128 = 1;
 
+// filler multiplier thresholds (1000 => 1x) for maker rebate tiers
+pub const MAKER_REBATE_TIER_BRONZE_MULTIPLIER: u64 = TEN_BPS as u64 * 5; // 5x
+pub const MAKER_REBATE_TIER_SILVER_MULTIPLIER: u64 = TEN_BPS as u64 * 20; // 20x
+pub const MAKER_REBATE_TIER_GOLD_MULTIPLIER: u64 = TEN_BPS as u64 * 50; // 50x
+
 // TIME PERIODS
 pub const ONE_MINUTE: i128 = 60_i128;
 pub const FIVE_MINUTE: i128 = (60 * 5) as i128;
@@ -195,9 +201,16 @@ pub const DEFAULT_QUOTE_ASSET_AMOUNT_TICK_SIZE: u64 =
 
 // FUNDING
 pub const FUNDING_RATE_OFFSET_DENOMINATOR: i64 = 5000; // 5000 => 7.3% annualized rate for hourly funding
+pub const FILLER_MULTIPLIER_FUNDING_RATE_THRESHOLD: u128 = PERCENTAGE_PRECISION / 1000; // .1% of oracle price
 
 // ORDERS
 pub const AUCTION_DERIVE_PRICE_FRACTION: i64 = 200;
+pub const MIN_BASE_DECIMALS: u32 = 6;
+pub const MAX_BASE_DECIMALS: u32 = 12;
 
 // WITHDRAWS
 pub const SPOT_MARKET_TOKEN_TWAP_WINDOW: i64 = TWENTY_FOUR_HOUR;
+
+// LIQUIDATION PRIORITY
+// score = margin_shortage * (PERCENTAGE_PRECISION + num_liabilities * LIQUIDATION_PRIORITY_LIABILITY_WEIGHT) / PERCENTAGE_PRECISION
+pub const LIQUIDATION_PRIORITY_LIABILITY_WEIGHT: u128 = PERCENTAGE_PRECISION / 10; // 10% per liability
@@ -327,6 +327,48 @@ pub fn calculate_fee_for_fulfillment_with_match(
     })
 }
 
+/// Fees for a maker/taker fill at the base fee tier, without referrer rewards or a fee adjustment.
+/// The filler reward is for a fill in the order's slot. Unlike calculate_fee_for_fulfillment_with_match,
+/// fee_to_market goes negative rather than erroring when the maker rebate exceeds the taker fee
+pub fn calculate_fees_for_fill(
+    quote_asset_amount: u128,
+    fee_structure: &FeeStructure,
+    filler_multiplier: u128,
+) -> DriftResult<FillFees> {
+    let fee_tier = &fee_structure.fee_tiers[0];
+    let quote_asset_amount = quote_asset_amount.cast::<u64>()?;
+
+    let taker_fee = calculate_taker_fee(quote_asset_amount, fee_tier, 0)?;
+    let maker_rebate = calculate_maker_rebate(quote_asset_amount, fee_tier, 0)?;
+
+    let filler_reward = if filler_multiplier == 0 {
+        0_u64
+    } else {
+        calculate_filler_reward(
+            taker_fee,
+            0,
+            0,
+            filler_multiplier.cast()?,
+            &fee_structure.filler_reward_structure,
+        )?
+    };
+
+    let fee_to_market = taker_fee
+        .cast::<i64>()?
+        .safe_sub(filler_reward.cast()?)?
+        .safe_sub(maker_rebate.cast()?)?;
+
+    Ok(FillFees {
+        user_fee: taker_fee,
+        maker_rebate,
+        fee_to_market,
+        fee_to_market_for_lp: 0,
+        filler_reward,
+        referrer_reward: 0,
+        referee_discount: 0,
+    })
+}
+
 pub struct ExternalFillFees {
     pub user_fee: u64,
     pub fee_to_market: u64,
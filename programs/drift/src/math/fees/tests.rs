@@ -663,3 +663,83 @@ mod calculate_fee_for_fulfillment_with_serum {
         assert_eq!(filler_reward, 2000);
     }
 }
+
+mod calculate_fees_for_fill {
+    use crate::math::constants::QUOTE_PRECISION;
+    use crate::math::fees::{calculate_fees_for_fill, FillFees};
+    use crate::state::state::{FeeStructure, FeeTier};
+
+    #[test]
+    fn zero_fee() {
+        let mut fee_structure = FeeStructure::test_default();
+        fee_structure.fee_tiers[0] = FeeTier::default();
+
+        let FillFees {
+            user_fee: taker_fee,
+            maker_rebate,
+            fee_to_market,
+            filler_reward,
+            ..
+        } = calculate_fees_for_fill(100 * QUOTE_PRECISION, &fee_structure, 1).unwrap();
+
+        assert_eq!(taker_fee, 0);
+        assert_eq!(maker_rebate, 0);
+        assert_eq!(fee_to_market, 0);
+        assert_eq!(filler_reward, 0);
+    }
+
+    #[test]
+    fn standard_fee() {
+        let fee_structure = FeeStructure::test_default();
+
+        let FillFees {
+            user_fee: taker_fee,
+            maker_rebate,
+            fee_to_market,
+            filler_reward,
+            referrer_reward,
+            referee_discount,
+            ..
+        } = calculate_fees_for_fill(100 * QUOTE_PRECISION, &fee_structure, 0).unwrap();
+
+        assert_eq!(taker_fee, 100000);
+        assert_eq!(maker_rebate, 60000);
+        assert_eq!(fee_to_market, 40000);
+        assert_eq!(filler_reward, 0);
+        assert_eq!(referrer_reward, 0);
+        assert_eq!(referee_discount, 0);
+
+        let FillFees {
+            user_fee: taker_fee,
+            maker_rebate,
+            fee_to_market,
+            filler_reward,
+            ..
+        } = calculate_fees_for_fill(100 * QUOTE_PRECISION, &fee_structure, 1).unwrap();
+
+        assert_eq!(taker_fee, 100000);
+        assert_eq!(maker_rebate, 60000);
+        assert_eq!(fee_to_market, 30000);
+        assert_eq!(filler_reward, 10000);
+    }
+
+    #[test]
+    fn rebate_exceeds_fee() {
+        let mut fee_structure = FeeStructure::test_default();
+        fee_structure.fee_tiers[0].maker_rebate_numerator =
+            fee_structure.fee_tiers[0].fee_numerator * 2;
+
+        let FillFees {
+            user_fee: taker_fee,
+            maker_rebate,
+            fee_to_market,
+            filler_reward,
+            ..
+        } = calculate_fees_for_fill(100 * QUOTE_PRECISION, &fee_structure, 1).unwrap();
+
+        assert_eq!(taker_fee, 100000);
+        assert_eq!(maker_rebate, 200000);
+        assert_eq!(fee_to_market, -110000);
+        assert_eq!(filler_reward, 10000);
+    }
+}
@@ -160,6 +160,21 @@ pub fn calculate_funding_payment(
     .cast()
 }
 
+/// Funding owed to (positive) or by (negative) the position since it was last settled
+pub fn calculate_unrealized_funding(
+    market_position: &PerpPosition,
+    market: &PerpMarket,
+) -> DriftResult<i64> {
+    calculate_funding_payment(
+        if market_position.base_asset_amount > 0 {
+            market.amm.cumulative_funding_rate_long
+        } else {
+            market.amm.cumulative_funding_rate_short
+        },
+        market_position,
+    )
+}
+
 fn _calculate_funding_payment(
     funding_rate_delta: i128,
     base_asset_amount: i128,
@@ -1,8 +1,11 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+
 use crate::error::DriftResult;
 use crate::error::ErrorCode;
 use crate::math::constants::{
-    MARGIN_PRECISION_U128, MAX_POSITIVE_UPNL_FOR_INITIAL_MARGIN, PRICE_PRECISION,
-    SPOT_IMF_PRECISION_U128, SPOT_WEIGHT_PRECISION, SPOT_WEIGHT_PRECISION_U128,
+    MARGIN_PRECISION, MARGIN_PRECISION_U128, MAX_POSITIVE_UPNL_FOR_INITIAL_MARGIN, PRICE_PRECISION,
+    QUOTE_SPOT_MARKET_INDEX, SPOT_IMF_PRECISION_U128, SPOT_WEIGHT_PRECISION,
+    SPOT_WEIGHT_PRECISION_U128,
 };
 use crate::math::position::{
     calculate_base_asset_value_and_pnl_with_oracle_price,
@@ -13,7 +16,7 @@ use crate::{validate, PRICE_PRECISION_I128};
 use crate::{validation, PRICE_PRECISION_I64};
 
 use crate::math::casting::Cast;
-use crate::math::funding::calculate_funding_payment;
+use crate::math::funding::calculate_unrealized_funding;
 use crate::math::oracle::{is_oracle_valid_for_action, DriftAction};
 
 use crate::math::spot_balance::{get_strict_token_value, get_token_value};
@@ -34,13 +37,32 @@ use std::cmp::{max, min, Ordering};
 #[cfg(test)]
 mod tests;
 
-#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
 pub enum MarginRequirementType {
     Initial,
     Fill,
     Maintenance,
 }
 
+/// Picks the market's margin ratio for margin_type. Ratios are capped at MARGIN_PRECISION and
+/// initial is floored at maintenance so a misconfigured market can't make initial the looser check
+pub fn resolve_margin_ratio(
+    market_margin_ratio_initial: u32,
+    market_margin_ratio_maintenance: u32,
+    margin_type: MarginRequirementType,
+) -> u32 {
+    let margin_ratio_maintenance = market_margin_ratio_maintenance.min(MARGIN_PRECISION);
+    let margin_ratio_initial = market_margin_ratio_initial
+        .max(margin_ratio_maintenance)
+        .min(MARGIN_PRECISION);
+
+    match margin_type {
+        MarginRequirementType::Initial => margin_ratio_initial,
+        MarginRequirementType::Fill => (margin_ratio_initial + margin_ratio_maintenance) / 2,
+        MarginRequirementType::Maintenance => margin_ratio_maintenance,
+    }
+}
+
 pub fn calculate_size_premium_liability_weight(
     size: u128, // AMM_RESERVE_PRECISION
     imf_factor: u32,
@@ -112,14 +134,7 @@ pub fn calculate_perp_position_value_and_pnl(
     };
 
     // the funding must be calculated before calculated the unrealized pnl w simulated lp position
-    let unrealized_funding = calculate_funding_payment(
-        if market_position.base_asset_amount > 0 {
-            market.amm.cumulative_funding_rate_long
-        } else {
-            market.amm.cumulative_funding_rate_short
-        },
-        market_position,
-    )?;
+    let unrealized_funding = calculate_unrealized_funding(market_position, market)?;
 
     let market_position = market_position.simulate_settled_lp_position(market, valuation_price)?;
 
@@ -258,6 +273,15 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             continue;
         }
 
+        // quote only assumes all collateral is quote, skip the oracle lookups and order simulation.
+        // borrows and open orders are still counted so meets_margin_requirement_quote_only rejects them
+        if context.quote_only && spot_position.market_index != QUOTE_SPOT_MARKET_INDEX {
+            if spot_position.is_borrow() || spot_position.has_open_order() {
+                calculation.add_spot_liability()?;
+            }
+            continue;
+        }
+
         let spot_market = spot_market_map.get_ref(&spot_position.market_index)?;
         let (oracle_price_data, oracle_validity) = oracle_map.get_price_data_and_validity(
             MarketType::Spot,
@@ -267,12 +291,12 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             spot_market.get_max_confidence_interval_multiplier()?,
         )?;
 
-        calculation.update_all_oracles_valid(is_oracle_valid_for_action(
-            oracle_validity,
-            Some(DriftAction::MarginCalc),
-        )?);
+        calculation.update_oracle_valid_for_market(
+            is_oracle_valid_for_action(oracle_validity, Some(DriftAction::MarginCalc))?,
+            MarketIdentifier::spot(spot_market.market_index),
+        );
 
-        let strict_oracle_price = StrictOraclePrice::new(
+        let mut strict_oracle_price = StrictOraclePrice::new(
             oracle_price_data.price,
             spot_market
                 .historical_oracle_data
@@ -281,6 +305,10 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
         );
         strict_oracle_price.validate()?;
 
+        if calculation.context.strict_confidence {
+            strict_oracle_price = strict_oracle_price.with_confidence(oracle_price_data.confidence);
+        }
+
         if spot_market.market_index == 0 {
             let token_amount = spot_position.get_signed_token_amount(&spot_market)?;
             if token_amount == 0 {
@@ -298,7 +326,8 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
 
             match spot_position.balance_type {
                 SpotBalanceType::Deposit => {
-                    calculation.add_total_collateral(token_value)?;
+                    calculation.add_quote_collateral(token_value)?;
+                    calculation.add_maintenance_total_collateral(token_value)?;
 
                     #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(token_value)?;
@@ -318,7 +347,9 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                         token_value,
                         token_value,
                         MarketIdentifier::spot(0),
+                        None,
                     )?;
+                    calculation.add_maintenance_margin_requirement(token_value)?;
 
                     calculation.add_spot_liability()?;
 
@@ -362,12 +393,13 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 spot_position.margin_requirement_for_open_orders()?,
                 0,
                 MarketIdentifier::spot(spot_market.market_index),
+                None,
             )?;
 
             match worst_case_token_value.cmp(&0) {
                 Ordering::Greater => {
                     calculation
-                        .add_total_collateral(worst_case_weighted_token_value.cast::<i128>()?)?;
+                        .add_spot_collateral(worst_case_weighted_token_value.cast::<i128>()?)?;
 
                     #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(worst_case_token_value)?;
@@ -392,6 +424,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                         worst_case_weighted_token_value.unsigned_abs(),
                         worst_case_token_value.unsigned_abs(),
                         MarketIdentifier::spot(spot_market.market_index),
+                        None,
                     )?;
 
                     calculation.add_spot_liability()?;
@@ -414,7 +447,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
 
             match worst_case_orders_value.cmp(&0) {
                 Ordering::Greater => {
-                    calculation.add_total_collateral(worst_case_orders_value.cast::<i128>()?)?;
+                    calculation.add_spot_collateral(worst_case_orders_value.cast::<i128>()?)?;
 
                     #[cfg(feature = "drift-rs")]
                     calculation.add_spot_asset_value(worst_case_orders_value)?;
@@ -424,6 +457,7 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                         worst_case_orders_value.unsigned_abs(),
                         worst_case_orders_value.unsigned_abs(),
                         MarketIdentifier::spot(0),
+                        None,
                     )?;
 
                     #[cfg(feature = "drift-rs")]
@@ -431,6 +465,41 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
                 }
                 Ordering::Equal => {}
             }
+
+            if calculation.context.track_both_requirements {
+                let OrderFillSimulation {
+                    orders_value: maintenance_orders_value,
+                    token_value: maintenance_token_value,
+                    weighted_token_value: maintenance_weighted_token_value,
+                    ..
+                } = spot_position.get_worst_case_fill_simulation(
+                    &spot_market,
+                    &strict_oracle_price,
+                    Some(signed_token_amount),
+                    MarginRequirementType::Maintenance,
+                )?;
+
+                calculation.add_maintenance_margin_requirement(
+                    spot_position.margin_requirement_for_open_orders()?,
+                )?;
+
+                if maintenance_token_value > 0 {
+                    calculation
+                        .add_maintenance_total_collateral(maintenance_weighted_token_value)?;
+                } else {
+                    calculation.add_maintenance_margin_requirement(
+                        maintenance_weighted_token_value.unsigned_abs(),
+                    )?;
+                }
+
+                if maintenance_orders_value > 0 {
+                    calculation.add_maintenance_total_collateral(maintenance_orders_value)?;
+                } else {
+                    calculation.add_maintenance_margin_requirement(
+                        maintenance_orders_value.unsigned_abs(),
+                    )?;
+                }
+            }
         }
     }
 
@@ -494,13 +563,36 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             perp_margin_requirement,
             worst_case_base_asset_value,
             MarketIdentifier::perp(market.market_index),
+            None,
         )?;
 
         if calculation.track_open_orders_fraction() {
             calculation.add_open_orders_margin_requirement(open_order_margin_requirement)?;
         }
 
-        calculation.add_total_collateral(weighted_pnl)?;
+        calculation.add_perp_pnl_collateral(weighted_pnl)?;
+
+        if calculation.context.track_both_requirements {
+            let (maintenance_margin_requirement, maintenance_weighted_pnl, _, _) =
+                calculate_perp_position_value_and_pnl(
+                    market_position,
+                    market,
+                    oracle_price_data,
+                    &strict_quote_price,
+                    MarginRequirementType::Maintenance,
+                    0,
+                    false,
+                )?;
+
+            calculation.add_maintenance_margin_requirement(maintenance_margin_requirement)?;
+            calculation.add_maintenance_total_collateral(maintenance_weighted_pnl)?;
+        }
+
+        if calculation.context.track_unrealized_funding {
+            calculation.add_unrealized_funding(
+                calculate_unrealized_funding(market_position, market)?.cast()?,
+            )?;
+        }
 
         #[cfg(feature = "drift-rs")]
         calculation.add_perp_liability_value(worst_case_base_asset_value)?;
@@ -513,21 +605,31 @@ pub fn calculate_margin_requirement_and_total_collateral_and_liability_info(
             || market_position.is_lp();
 
         if has_perp_liability {
-            calculation.add_perp_liability()?;
+            if market_position.base_asset_amount == 0
+                && !market_position.has_open_order()
+                && !market_position.is_lp()
+            {
+                calculation.add_unsettled_pnl_perp_liability()?;
+            } else {
+                calculation.add_perp_liability()?;
+            }
             calculation.update_with_perp_isolated_liability(
                 market.contract_tier == ContractTier::Isolated,
             );
         }
 
         if has_perp_liability || calculation.context.margin_type != MarginRequirementType::Initial {
-            calculation.update_all_oracles_valid(is_oracle_valid_for_action(
-                oracle_validity,
-                Some(DriftAction::MarginCalc),
-            )?);
+            calculation.update_oracle_valid_for_market(
+                is_oracle_valid_for_action(oracle_validity, Some(DriftAction::MarginCalc))?,
+                MarketIdentifier::perp(market.market_index),
+            );
         }
     }
 
     calculation.validate_num_spot_liabilities()?;
+    calculation.validate_num_perp_liabilities()?;
+
+    calculation.finalize()?;
 
     Ok(calculation)
 }
@@ -874,14 +976,7 @@ pub fn calculate_user_equity(
             oracle_price_data.price
         };
 
-        let unrealized_funding = calculate_funding_payment(
-            if market_position.base_asset_amount > 0 {
-                market.amm.cumulative_funding_rate_long
-            } else {
-                market.amm.cumulative_funding_rate_short
-            },
-            market_position,
-        )?;
+        let unrealized_funding = calculate_unrealized_funding(market_position, market)?;
 
         let market_position =
             market_position.simulate_settled_lp_position(market, valuation_price)?;
@@ -1325,6 +1325,127 @@ mod calculate_margin_requirement_and_total_collateral {
         assert_eq!(total_collateral, 0); // todo not 0
         assert_eq!(margin_requirement, 3);
     }
+
+    #[test]
+    pub fn track_both_requirements() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let market_map = PerpMarketMap::empty();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 10000 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            open_orders: 1,
+            open_bids: 500 * 10_i64.pow(9),
+            ..SpotPosition::default()
+        };
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: [PerpPosition::default(); 8],
+            spot_positions,
+            ..User::default()
+        };
+
+        let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial)
+                .track_both_requirements()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let maintenance_calculation =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Maintenance),
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_requirement, 50000010000);
+        assert_eq!(calculation.total_collateral, 50000000000);
+        assert_eq!(
+            calculation.margin_requirement_maintenance,
+            maintenance_calculation.margin_requirement
+        );
+        assert_eq!(
+            calculation.total_collateral_maintenance,
+            maintenance_calculation.total_collateral
+        );
+
+        let initial_free_collateral = calculation
+            .get_free_collateral_for_type(MarginRequirementType::Initial)
+            .unwrap();
+        let maintenance_free_collateral = calculation
+            .get_free_collateral_for_type(MarginRequirementType::Maintenance)
+            .unwrap();
+
+        assert_eq!(initial_free_collateral, 0);
+        assert_eq!(
+            maintenance_free_collateral,
+            maintenance_calculation.get_free_collateral().unwrap()
+        );
+        assert!(maintenance_free_collateral > initial_free_collateral);
+    }
 }
 
 #[cfg(test)]
@@ -1879,6 +2000,129 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         assert_eq!(total_collateral, 990000);
     }
 
+    #[test]
+    pub fn usdc_deposit_with_confidence() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            sol_oracle_account_info
+        );
+
+        let mut usdc_oracle_price = get_hardcoded_pyth_price(99 * 10000, 6); // $.99
+        usdc_oracle_price.agg.conf = 10000; // $.01
+        let usdc_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            usdc_oracle_price,
+            &usdc_oracle_price_key,
+            &pyth_program,
+            usdc_oracle_account_info
+        );
+        let oracle_account_infos = Vec::from([sol_oracle_account_info, usdc_oracle_account_info]);
+        let mut oracle_map =
+            OracleMap::load(&mut oracle_account_infos.iter().peekable(), slot, None).unwrap();
+
+        let market_map = PerpMarketMap::empty();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            oracle: usdc_oracle_price_key,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: usdc_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        let user = User {
+            orders: [Order::default(); 32],
+            perp_positions: [PerpPosition::default(); 8],
+            spot_positions,
+            ..User::default()
+        };
+
+        let calculation_without_confidence =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial).strict(true),
+            )
+            .unwrap();
+
+        assert_eq!(calculation_without_confidence.total_collateral, 990000);
+
+        // deposit valued at the bottom of the confidence interval
+        let calculation_with_confidence =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial)
+                    .strict(true)
+                    .strict_confidence(),
+            )
+            .unwrap();
+
+        assert_eq!(calculation_with_confidence.total_collateral, 980000);
+
+        // confidence ignored outside of strict mode
+        let calculation_not_strict =
+            calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial).strict_confidence(),
+            )
+            .unwrap();
+
+        assert_eq!(calculation_not_strict.total_collateral, 990000);
+    }
+
     #[test]
     pub fn usdc_more_than_1_with_borrow() {
         let slot = 0_u64;
@@ -2194,6 +2438,184 @@ mod calculate_margin_requirement_and_total_collateral_and_liability_info {
         assert_eq!(margin_requirement, 10100000);
         assert_eq!(total_collateral, 9500000);
     }
+
+    #[test]
+    fn quote_only_skips_non_quote_spot() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let perp_market_map = PerpMarketMap::empty();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let mut sol_spot_market = SpotMarket {
+            market_index: 1,
+            oracle_source: OracleSource::Pyth,
+            oracle: sol_oracle_price_key,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            cumulative_borrow_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 9,
+            initial_asset_weight: 8 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_asset_weight: 9 * SPOT_WEIGHT_PRECISION / 10,
+            initial_liability_weight: 12 * SPOT_WEIGHT_PRECISION / 10,
+            maintenance_liability_weight: 11 * SPOT_WEIGHT_PRECISION / 10,
+            deposit_balance: 100 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: LIQUIDATION_FEE_PRECISION / 1000,
+            historical_oracle_data: HistoricalOracleData {
+                last_oracle_price_twap_5min: 100 * PRICE_PRECISION_I64,
+                ..HistoricalOracleData::default()
+            },
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(sol_spot_market, SpotMarket, sol_spot_market_account_info);
+        let spot_market_account_infos = Vec::from([
+            &usdc_spot_market_account_info,
+            &sol_spot_market_account_info,
+        ]);
+        let spot_market_map =
+            SpotMarketMap::load_multiple(spot_market_account_infos, true).unwrap();
+
+        let mut spot_positions = [SpotPosition::default(); 8];
+        spot_positions[0] = SpotPosition {
+            market_index: 0,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+        spot_positions[1] = SpotPosition {
+            market_index: 1,
+            balance_type: SpotBalanceType::Deposit,
+            scaled_balance: SPOT_BALANCE_PRECISION_U64,
+            ..SpotPosition::default()
+        };
+
+        let user = User {
+            spot_positions,
+            ..User::default()
+        };
+
+        let full = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial),
+        )
+        .unwrap();
+
+        assert_eq!(full.total_collateral, 180 * QUOTE_PRECISION as i128);
+
+        let quote_only = calculate_margin_requirement_and_total_collateral_and_liability_info(
+            &user,
+            &perp_market_map,
+            &spot_market_map,
+            &mut oracle_map,
+            MarginContext::standard(MarginRequirementType::Initial).quote_only(),
+        )
+        .unwrap();
+
+        assert_eq!(quote_only.total_collateral, 100 * QUOTE_PRECISION as i128);
+        assert_eq!(quote_only.margin_requirement, 0);
+    }
+
+    #[test]
+    fn quote_only_counts_non_quote_spot_liabilities() {
+        let slot = 0_u64;
+
+        let mut sol_oracle_price = get_pyth_price(100, 6);
+        let sol_oracle_price_key =
+            Pubkey::from_str("J83w4HKfqxwcq3BEMMkPFSppX3gqekLyLJBexebFVkix").unwrap();
+        let pyth_program = crate::ids::pyth_program::id();
+        create_account_info!(
+            sol_oracle_price,
+            &sol_oracle_price_key,
+            &pyth_program,
+            oracle_account_info
+        );
+        let mut oracle_map = OracleMap::load_one(&oracle_account_info, slot, None).unwrap();
+
+        let perp_market_map = PerpMarketMap::empty();
+
+        let mut usdc_spot_market = SpotMarket {
+            market_index: 0,
+            oracle_source: OracleSource::QuoteAsset,
+            cumulative_deposit_interest: SPOT_CUMULATIVE_INTEREST_PRECISION,
+            decimals: 6,
+            initial_asset_weight: SPOT_WEIGHT_PRECISION,
+            maintenance_asset_weight: SPOT_WEIGHT_PRECISION,
+            deposit_balance: 10000 * SPOT_BALANCE_PRECISION,
+            liquidator_fee: 0,
+            historical_oracle_data: HistoricalOracleData::default_quote_oracle(),
+            ..SpotMarket::default()
+        };
+        create_anchor_account_info!(usdc_spot_market, SpotMarket, usdc_spot_market_account_info);
+        let spot_market_map =
+            SpotMarketMap::load_one(&usdc_spot_market_account_info, true).unwrap();
+
+        for (balance_type, open_orders, expected_liabilities) in [
+            (SpotBalanceType::Deposit, 0, 0),
+            (SpotBalanceType::Deposit, 1, 1),
+            (SpotBalanceType::Borrow, 0, 1),
+        ] {
+            let mut spot_positions = [SpotPosition::default(); 8];
+            spot_positions[0] = SpotPosition {
+                market_index: 0,
+                balance_type: SpotBalanceType::Deposit,
+                scaled_balance: 100 * SPOT_BALANCE_PRECISION_U64,
+                ..SpotPosition::default()
+            };
+            spot_positions[1] = SpotPosition {
+                market_index: 1,
+                balance_type,
+                scaled_balance: SPOT_BALANCE_PRECISION_U64,
+                open_orders,
+                ..SpotPosition::default()
+            };
+
+            let user = User {
+                spot_positions,
+                ..User::default()
+            };
+
+            // sol spot market isn't loaded, quote only must not look it up
+            let calculation = calculate_margin_requirement_and_total_collateral_and_liability_info(
+                &user,
+                &perp_market_map,
+                &spot_market_map,
+                &mut oracle_map,
+                MarginContext::standard(MarginRequirementType::Initial).quote_only(),
+            )
+            .unwrap();
+
+            assert_eq!(calculation.num_spot_liabilities, expected_liabilities);
+            assert_eq!(
+                calculation.meets_margin_requirement_quote_only().is_ok(),
+                expected_liabilities == 0
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -3433,3 +3855,56 @@ mod calculate_user_equity {
         assert_eq!(net_usd_value, 1000000000);
     }
 }
+
+mod resolve_margin_ratio {
+    use crate::math::constants::MARGIN_PRECISION;
+    use crate::math::margin::{resolve_margin_ratio, MarginRequirementType};
+
+    #[test]
+    fn initial_and_maintenance() {
+        assert_eq!(
+            resolve_margin_ratio(1000, 500, MarginRequirementType::Initial),
+            1000
+        );
+        assert_eq!(
+            resolve_margin_ratio(1000, 500, MarginRequirementType::Maintenance),
+            500
+        );
+        assert_eq!(
+            resolve_margin_ratio(1000, 500, MarginRequirementType::Fill),
+            750
+        );
+    }
+
+    #[test]
+    fn maintenance_exceeds_initial() {
+        assert_eq!(
+            resolve_margin_ratio(500, 1000, MarginRequirementType::Initial),
+            1000
+        );
+        assert_eq!(
+            resolve_margin_ratio(500, 1000, MarginRequirementType::Maintenance),
+            1000
+        );
+        assert_eq!(
+            resolve_margin_ratio(500, 1000, MarginRequirementType::Fill),
+            1000
+        );
+    }
+
+    #[test]
+    fn capped_at_margin_precision() {
+        assert_eq!(
+            resolve_margin_ratio(
+                2 * MARGIN_PRECISION,
+                MARGIN_PRECISION,
+                MarginRequirementType::Initial
+            ),
+            MARGIN_PRECISION
+        );
+        assert_eq!(
+            resolve_margin_ratio(u32::MAX, u32::MAX, MarginRequirementType::Fill),
+            MARGIN_PRECISION
+        );
+    }
+}
@@ -1,22 +1,54 @@
-use std::cmp::min;
+use std::cmp::{min, Ordering};
 
 use crate::controller::position::PositionDirection;
-use crate::error::DriftResult;
+use crate::error::{DriftResult, ErrorCode};
 use crate::math::casting::Cast;
-use crate::math::constants::{BID_ASK_SPREAD_PRECISION_I128, TEN_BPS_I64};
-use crate::math::orders::calculate_quote_asset_amount_for_maker_order;
+use crate::math::constants::{
+    BID_ASK_SPREAD_PRECISION_I128, FILLER_MULTIPLIER_FUNDING_RATE_THRESHOLD, FUNDING_RATE_BUFFER,
+    MAKER_REBATE_TIER_BRONZE_MULTIPLIER, MAKER_REBATE_TIER_GOLD_MULTIPLIER,
+    MAKER_REBATE_TIER_SILVER_MULTIPLIER, MAX_BASE_DECIMALS, MIN_BASE_DECIMALS, ONE_BPS_DENOMINATOR,
+    PERCENTAGE_PRECISION, TEN_BPS_I64,
+};
+use crate::math::orders::{
+    calculate_fill_price, calculate_quote_asset_amount_for_maker_order,
+    order_satisfies_trigger_condition,
+};
 use crate::math::safe_math::SafeMath;
 
-use crate::state::user::Order;
+use crate::state::order_params::PostOnlyParam;
+use crate::state::user::{CollateralBucket, Order};
+use crate::validate;
+use anchor_lang::prelude::Pubkey;
+use solana_program::msg;
 
 #[cfg(test)]
 mod tests;
 
 pub fn is_maker_for_taker(
     maker_order: &Order,
+    maker_key: &Pubkey,
     taker_order: &Order,
+    taker_key: &Pubkey,
     slot: u64,
+    now: i64,
 ) -> DriftResult<bool> {
+    validate!(
+        maker_key != taker_key,
+        ErrorCode::CantMatchSameUser,
+        "maker and taker cant be the same user {}",
+        maker_key
+    )?;
+
+    // trigger orders can't match until their trigger condition has fired
+    if is_untriggered(maker_order) || is_untriggered(taker_order) {
+        return Ok(false);
+    }
+
+    // expired maker orders are skipped, they'll be cancelled by the next filler
+    if maker_order.has_expired(now) {
+        return Ok(false);
+    }
+
     // Maker and taker order not allowed to match if both were placed in the current slot
     if slot == maker_order.slot && slot == taker_order.slot && !maker_order.is_jit_maker() {
         return Ok(false);
@@ -39,6 +71,78 @@ pub fn is_maker_for_taker(
     }
 }
 
+/// Priority between two maker orders, Ordering::Less means maker_a fills first. Orders placed in an
+/// earlier slot go first, then larger base_asset_amount, then lower order_id. Order ids are only
+/// unique per user so makers from different users can still compare Equal
+pub fn compare_maker_orders(maker_a: &Order, maker_b: &Order) -> Ordering {
+    maker_a
+        .slot
+        .cmp(&maker_b.slot)
+        .then_with(|| maker_b.base_asset_amount.cmp(&maker_a.base_asset_amount))
+        .then_with(|| maker_a.order_id.cmp(&maker_b.order_id))
+}
+
+fn is_untriggered(order: &Order) -> bool {
+    order.must_be_triggered() && !order.triggered()
+}
+
+pub fn is_order_triggered(order: &Order, oracle_price: i64) -> DriftResult<bool> {
+    if !order.must_be_triggered() || order.triggered() {
+        return Ok(true);
+    }
+
+    if oracle_price <= 0 {
+        return Ok(false);
+    }
+
+    order_satisfies_trigger_condition(order, oracle_price.unsigned_abs())
+}
+
+/// Returns the fill clamped to the size of the existing position. Errors if the fill
+/// would open or increase a position rather than reduce it
+pub fn validate_reduce_only_fill(
+    existing_base_asset_amount: i64,
+    fill_base_asset_amount: u64,
+    direction: PositionDirection,
+) -> DriftResult<u64> {
+    let is_reducing = match direction {
+        PositionDirection::Long => existing_base_asset_amount < 0,
+        PositionDirection::Short => existing_base_asset_amount > 0,
+    };
+
+    validate!(
+        is_reducing,
+        ErrorCode::ReduceOnlyOrderIncreasedRisk,
+        "reduce only fill direction={:?} does not reduce existing_base_asset_amount={}",
+        direction,
+        existing_base_asset_amount
+    )?;
+
+    Ok(fill_base_asset_amount.min(existing_base_asset_amount.unsigned_abs()))
+}
+
+/// Caps a reduce only maker's fill at its existing position so the fill can't flip it. Doesn't
+/// check the fill direction, use validate_reduce_only_fill for that
+pub fn clamp_fill_to_maker_position(
+    fill_base_asset_amount: u64,
+    maker_existing_base_asset_amount: i64,
+    maker_reduce_only: bool,
+) -> u64 {
+    if !maker_reduce_only {
+        return fill_base_asset_amount;
+    }
+
+    fill_base_asset_amount.min(maker_existing_base_asset_amount.unsigned_abs())
+}
+
+/// (maker, taker) collateral buckets the fill should be margined against
+pub fn buckets_for_match(
+    maker_order: &Order,
+    taker_order: &Order,
+) -> (CollateralBucket, CollateralBucket) {
+    (maker_order.collateral_bucket, taker_order.collateral_bucket)
+}
+
 pub fn are_orders_same_market_but_different_sides(
     maker_order: &Order,
     taker_order: &Order,
@@ -53,52 +157,632 @@ pub fn do_orders_cross(
     maker_price: u64,
     taker_price: u64,
 ) -> bool {
+    cross_depth(maker_direction, maker_price, taker_price) >= 0
+}
+
+/// How far the taker price is through the maker price, positive when the orders cross and
+/// negative for the gap between them. Unusually deep crosses usually mean a stale maker order
+pub fn cross_depth(maker_direction: PositionDirection, maker_price: u64, taker_price: u64) -> i128 {
+    let maker_price = i128::from(maker_price);
+    let taker_price = i128::from(taker_price);
+
+    match maker_direction {
+        PositionDirection::Long => maker_price - taker_price,
+        PositionDirection::Short => taker_price - maker_price,
+    }
+}
+
+/// Like do_orders_cross but also rejects makers more than max_slippage_bps away from the taker price
+pub fn do_orders_cross_within_slippage(
+    maker_direction: PositionDirection,
+    maker_price: u64,
+    taker_price: u64,
+    max_slippage_bps: u64,
+) -> bool {
+    if !do_orders_cross(maker_direction, maker_price, taker_price) {
+        return false;
+    }
+
+    let slippage = maker_price.abs_diff(taker_price) as u128 * ONE_BPS_DENOMINATOR as u128;
+    let max_slippage = taker_price as u128 * max_slippage_bps as u128;
+
+    slippage <= max_slippage
+}
+
+/// Whether the maker price improves on oracle_price by at least improvement_bps for the taker, i.e.
+/// bids at or above oracle + improvement and asks at or below oracle - improvement
+pub fn qualifies_as_maker(
+    maker_direction: PositionDirection,
+    maker_price: u64,
+    oracle_price: i64,
+    improvement_bps: u64,
+) -> bool {
+    if oracle_price <= 0 {
+        return false;
+    }
+
+    let maker_price = maker_price as u128 * ONE_BPS_DENOMINATOR as u128;
+    let oracle_price = oracle_price.unsigned_abs() as u128;
+
     match maker_direction {
-        PositionDirection::Long => taker_price <= maker_price,
-        PositionDirection::Short => taker_price >= maker_price,
+        PositionDirection::Long => {
+            maker_price >= oracle_price * (ONE_BPS_DENOMINATOR as u128 + improvement_bps as u128)
+        }
+        PositionDirection::Short => {
+            maker_price
+                <= oracle_price
+                    * (ONE_BPS_DENOMINATOR as u128).saturating_sub(improvement_bps as u128)
+        }
     }
 }
 
+pub fn do_orders_cross_with_oracle_offsets(
+    maker_direction: PositionDirection,
+    maker_price: u64,
+    maker_oracle_price_offset: i32,
+    taker_price: u64,
+    taker_oracle_price_offset: i32,
+    oracle_price: i64,
+) -> DriftResult<bool> {
+    let maker_price =
+        calculate_effective_price(maker_price, maker_oracle_price_offset, oracle_price)?;
+    let taker_price =
+        calculate_effective_price(taker_price, taker_oracle_price_offset, oracle_price)?;
+
+    Ok(do_orders_cross(maker_direction, maker_price, taker_price))
+}
+
+// fixed price orders have no offset, oracle orders are priced at oracle_price + offset (min of 1)
+pub fn calculate_effective_price(
+    price: u64,
+    oracle_price_offset: i32,
+    oracle_price: i64,
+) -> DriftResult<u64> {
+    if oracle_price_offset == 0 {
+        return Ok(price);
+    }
+
+    oracle_price
+        .safe_add(oracle_price_offset.cast()?)?
+        .max(1)
+        .cast()
+}
+
+/// Best maker for the taker among makers for the same market and opposite side whose price crosses,
+/// ordered by price then compare_maker_orders. Prices are resolved like match_orders_with_trace, so
+/// taker auction prices must already be in taker.price. Untriggered makers are skipped, the user,
+/// slot and expiry checks in is_maker_for_taker are left to the caller
+pub fn select_maker<'a>(
+    makers: &'a [Order],
+    taker: &Order,
+    oracle_price: i128,
+) -> DriftResult<Option<&'a Order>> {
+    let oracle_price = oracle_price.cast::<i64>()?;
+    let taker_price =
+        calculate_effective_price(taker.price, taker.oracle_price_offset, oracle_price)?;
+
+    let mut best_maker: Option<(&Order, u64)> = None;
+    for maker in makers.iter() {
+        if is_untriggered(maker) || !are_orders_same_market_but_different_sides(maker, taker) {
+            continue;
+        }
+
+        let maker_price =
+            calculate_effective_price(maker.price, maker.oracle_price_offset, oracle_price)?;
+
+        if !do_orders_cross(maker.direction, maker_price, taker_price) {
+            continue;
+        }
+
+        let is_better = match best_maker {
+            None => true,
+            Some((best, best_price)) => {
+                // highest bid or lowest ask first
+                let price_ordering = match maker.direction {
+                    PositionDirection::Long => best_price.cmp(&maker_price),
+                    PositionDirection::Short => maker_price.cmp(&best_price),
+                };
+
+                price_ordering.then_with(|| compare_maker_orders(maker, best)) == Ordering::Less
+            }
+        };
+
+        if is_better {
+            best_maker = Some((maker, maker_price));
+        }
+    }
+
+    Ok(best_maker.map(|(maker, _)| maker))
+}
+
+/// How the quote amount of a fill is rounded when base * price doesn't divide evenly
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum RoundingMode {
+    Down,
+    /// a long taker can pay one unit more than base * price
+    Up,
+    /// rounds whichever way favors the maker, down when the maker is buying and up when selling
+    TowardMaker,
+    /// rounds whichever way favors the taker
+    TowardTaker,
+}
+
+impl Default for RoundingMode {
+    // TowardMaker rather than Down, it's how calculate_quote_asset_amount_for_maker_order has always
+    // rounded fills so the default leaves existing fills unchanged
+    fn default() -> Self {
+        RoundingMode::TowardMaker
+    }
+}
+
+/// The quote amount differs by at most one unit between rounding modes. Rounding up for a long taker
+/// (Up, or TowardMaker against a short maker) can charge one unit more than base * the taker's
+/// limit price when maker_price is at the limit
 pub fn calculate_fill_for_matched_orders(
     maker_base_asset_amount: u64,
     maker_price: u64,
     taker_base_asset_amount: u64,
     base_decimals: u32,
     maker_direction: PositionDirection,
+    min_base_asset_amount: u64,
+    rounding_mode: RoundingMode,
 ) -> DriftResult<(u64, u64)> {
+    validate!(
+        (MIN_BASE_DECIMALS..=MAX_BASE_DECIMALS).contains(&base_decimals),
+        ErrorCode::InvalidBaseDecimals,
+        "base_decimals={} must be between {} and {}",
+        base_decimals,
+        MIN_BASE_DECIMALS,
+        MAX_BASE_DECIMALS
+    )?;
+
     let base_asset_amount = min(maker_base_asset_amount, taker_base_asset_amount);
 
+    // (0, 0) tells the caller the fill is too small to be worth doing
+    if base_asset_amount < min_base_asset_amount {
+        return Ok((0, 0));
+    }
+
+    // calculate_quote_asset_amount_for_maker_order rounds down for a buyer and up for a seller
+    let round_down_direction = match rounding_mode {
+        RoundingMode::Down => PositionDirection::Long,
+        RoundingMode::Up => PositionDirection::Short,
+        RoundingMode::TowardMaker => maker_direction,
+        RoundingMode::TowardTaker => maker_direction.opposite(),
+    };
+
     let quote_asset_amount = calculate_quote_asset_amount_for_maker_order(
         base_asset_amount,
         maker_price,
         base_decimals,
-        maker_direction,
+        round_down_direction,
     )?;
 
     Ok((base_asset_amount, quote_asset_amount))
 }
 
+/// Errors if base_asset_amount at price is worth less than min_notional (QUOTE_PRECISION). The
+/// notional is rounded down the same way calculate_fill_for_matched_orders prices a buy
+pub fn validate_order_notional(
+    base_asset_amount: u128,
+    price: u128,
+    min_notional: u128,
+    base_precision: u32,
+) -> DriftResult {
+    validate!(
+        (MIN_BASE_DECIMALS..=MAX_BASE_DECIMALS).contains(&base_precision),
+        ErrorCode::InvalidBaseDecimals,
+        "base_decimals={} must be between {} and {}",
+        base_precision,
+        MIN_BASE_DECIMALS,
+        MAX_BASE_DECIMALS
+    )?;
+
+    let notional = calculate_quote_asset_amount_for_maker_order(
+        base_asset_amount.cast()?,
+        price.cast()?,
+        base_precision,
+        PositionDirection::Long,
+    )?
+    .cast::<u128>()?;
+
+    validate!(
+        notional >= min_notional,
+        ErrorCode::OrderBelowMinNotional,
+        "order notional={} (base_asset_amount={} price={}) below min_notional={}",
+        notional,
+        base_asset_amount,
+        price,
+        min_notional
+    )?;
+
+    Ok(())
+}
+
+/// calculate_fill_for_matched_orders for a maker/taker order pair. Errors if the orders are for
+/// different markets or on the same side so a bad caller can't corrupt positions
+pub fn match_orders(
+    maker_order: &Order,
+    taker_order: &Order,
+    maker_base_asset_amount: u64,
+    maker_price: u64,
+    taker_base_asset_amount: u64,
+    base_decimals: u32,
+    min_base_asset_amount: u64,
+    rounding_mode: RoundingMode,
+) -> DriftResult<(u64, u64)> {
+    validate!(
+        maker_order.market_index == taker_order.market_index
+            && maker_order.market_type == taker_order.market_type,
+        ErrorCode::OrderMarketMismatch,
+        "maker order market {:?} {} != taker order market {:?} {}",
+        maker_order.market_type,
+        maker_order.market_index,
+        taker_order.market_type,
+        taker_order.market_index
+    )?;
+
+    validate!(
+        maker_order.direction != taker_order.direction,
+        ErrorCode::OrdersOnSameSide,
+        "maker and taker orders are both {:?}",
+        maker_order.direction
+    )?;
+
+    calculate_fill_for_matched_orders(
+        maker_base_asset_amount,
+        maker_price,
+        taker_base_asset_amount,
+        base_decimals,
+        maker_order.direction,
+        min_base_asset_amount,
+        rounding_mode,
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum MatchTrace {
+    TwoPostOnly,
+    NotMakerForTaker,
+    NotOppositeSides,
+    DidNotCross,
+    MakerDidNotImprove,
+    MakerChosen, // eligible and crossing but nothing left to fill
+    Filled,
+}
+
+/// Runs the matching checks for a single maker/taker pair and records which one decided the outcome.
+/// Prices are the orders' fixed price or oracle_price + offset, so taker auction prices must already
+/// be resolved into taker_order.price. When maker_improvement_bps is set the maker must also pass
+/// qualifies_as_maker. Returns (base_asset_amount, quote_asset_amount) when filled
+pub fn match_orders_with_trace(
+    maker_order: &Order,
+    maker_key: &Pubkey,
+    taker_order: &Order,
+    taker_key: &Pubkey,
+    oracle_price: i64,
+    base_decimals: u32,
+    slot: u64,
+    now: i64,
+    maker_improvement_bps: Option<u64>,
+) -> DriftResult<(Option<(u64, u64)>, MatchTrace)> {
+    if maker_order.post_only && taker_order.post_only {
+        return Ok((None, MatchTrace::TwoPostOnly));
+    }
+
+    if !is_maker_for_taker(maker_order, maker_key, taker_order, taker_key, slot, now)? {
+        return Ok((None, MatchTrace::NotMakerForTaker));
+    }
+
+    if !are_orders_same_market_but_different_sides(maker_order, taker_order) {
+        return Ok((None, MatchTrace::NotOppositeSides));
+    }
+
+    let maker_price = calculate_effective_price(
+        maker_order.price,
+        maker_order.oracle_price_offset,
+        oracle_price,
+    )?;
+    let taker_price = calculate_effective_price(
+        taker_order.price,
+        taker_order.oracle_price_offset,
+        oracle_price,
+    )?;
+
+    if !do_orders_cross(maker_order.direction, maker_price, taker_price) {
+        return Ok((None, MatchTrace::DidNotCross));
+    }
+
+    if let Some(improvement_bps) = maker_improvement_bps {
+        if !qualifies_as_maker(
+            maker_order.direction,
+            maker_price,
+            oracle_price,
+            improvement_bps,
+        ) {
+            return Ok((None, MatchTrace::MakerDidNotImprove));
+        }
+    }
+
+    let (base_asset_amount, quote_asset_amount) = match_orders(
+        maker_order,
+        taker_order,
+        maker_order.get_base_asset_amount_unfilled(None)?,
+        maker_price,
+        taker_order.get_base_asset_amount_unfilled(None)?,
+        base_decimals,
+        0,
+        RoundingMode::TowardMaker,
+    )?;
+
+    if base_asset_amount == 0 {
+        return Ok((None, MatchTrace::MakerChosen));
+    }
+
+    Ok((
+        Some((base_asset_amount, quote_asset_amount)),
+        MatchTrace::Filled,
+    ))
+}
+
+/// Inverse of calculate_quote_asset_amount_for_maker_order for takers that specify a quote amount.
+/// Rounds down so the quote owed for the returned base never exceeds quote_asset_amount
+pub fn calculate_base_from_quote_for_fill(
+    quote_asset_amount: u64,
+    maker_price: u64,
+    base_decimals: u32,
+) -> DriftResult<u64> {
+    validate!(
+        maker_price > 0,
+        ErrorCode::InvalidOrderFillPrice,
+        "maker_price must be positive"
+    )?;
+
+    validate!(
+        (MIN_BASE_DECIMALS..=MAX_BASE_DECIMALS).contains(&base_decimals),
+        ErrorCode::InvalidBaseDecimals,
+        "base_decimals={} must be between {} and {}",
+        base_decimals,
+        MIN_BASE_DECIMALS,
+        MAX_BASE_DECIMALS
+    )?;
+
+    quote_asset_amount
+        .cast::<u128>()?
+        .safe_mul(10_u128.pow(base_decimals))?
+        .safe_div(maker_price.cast()?)?
+        .cast()
+}
+
+/// Also returns the realized fill price (PRICE_PRECISION) from the rounded base and quote amounts,
+/// which can differ slightly from maker_price
+pub fn calculate_fill_for_matched_orders_with_price(
+    maker_base_asset_amount: u64,
+    maker_price: u64,
+    taker_base_asset_amount: u64,
+    base_decimals: u32,
+    maker_direction: PositionDirection,
+) -> DriftResult<(u64, u64, u64)> {
+    let (base_asset_amount, quote_asset_amount) = calculate_fill_for_matched_orders(
+        maker_base_asset_amount,
+        maker_price,
+        taker_base_asset_amount,
+        base_decimals,
+        maker_direction,
+        0,
+        RoundingMode::TowardMaker,
+    )?;
+
+    let fill_price = if base_asset_amount == 0 {
+        0
+    } else {
+        calculate_fill_price(
+            quote_asset_amount,
+            base_asset_amount,
+            10_u64.pow(base_decimals),
+        )?
+    };
+
+    Ok((base_asset_amount, quote_asset_amount, fill_price))
+}
+
+pub fn validate_fill_price_within_band(
+    fill_price: u64,
+    oracle_price: i64,
+    max_divergence_bps: u64,
+) -> DriftResult {
+    validate!(
+        oracle_price > 0,
+        ErrorCode::InvalidOracle,
+        "oracle_price={} must be positive",
+        oracle_price
+    )?;
+
+    let oracle_price = oracle_price.unsigned_abs();
+
+    let divergence_bps = fill_price
+        .abs_diff(oracle_price)
+        .cast::<u128>()?
+        .safe_mul(ONE_BPS_DENOMINATOR.cast()?)?
+        .safe_div(oracle_price.cast()?)?;
+
+    validate!(
+        divergence_bps <= max_divergence_bps.cast()?,
+        ErrorCode::PriceBandsBreached,
+        "fill_price={} diverges from oracle_price={} by {} bps (max {} bps)",
+        fill_price,
+        oracle_price,
+        divergence_bps,
+        max_divergence_bps
+    )?;
+
+    Ok(())
+}
+
+/// Signed divergence of fill_price from oracle_price in bps, positive when filled above the oracle
+pub fn estimate_fill_price_impact(fill_price: u64, oracle_price: i64) -> DriftResult<i64> {
+    validate!(
+        oracle_price > 0,
+        ErrorCode::InvalidOracle,
+        "oracle_price={} must be positive",
+        oracle_price
+    )?;
+
+    fill_price
+        .cast::<i128>()?
+        .safe_sub(oracle_price.cast()?)?
+        .safe_mul(ONE_BPS_DENOMINATOR.cast()?)?
+        .safe_div(oracle_price.cast()?)?
+        .cast()
+}
+
+/// Walks makers in order, returning the (base, quote) fill for each maker matched and the
+/// unfilled taker base asset amount
+pub fn calculate_fills_for_matched_orders(
+    taker_base_asset_amount: u64,
+    makers: &[(u64, u64)],
+    base_decimals: u32,
+    maker_direction: PositionDirection,
+) -> DriftResult<(Vec<(u64, u64)>, u64)> {
+    let mut fills = Vec::with_capacity(makers.len());
+    let mut taker_base_asset_amount_remaining = taker_base_asset_amount;
+
+    for (maker_base_asset_amount, maker_price) in makers.iter() {
+        if taker_base_asset_amount_remaining == 0 {
+            break;
+        }
+
+        let (base_asset_amount, quote_asset_amount) = calculate_fill_for_matched_orders(
+            *maker_base_asset_amount,
+            *maker_price,
+            taker_base_asset_amount_remaining,
+            base_decimals,
+            maker_direction,
+            0,
+            RoundingMode::TowardMaker,
+        )?;
+
+        taker_base_asset_amount_remaining =
+            taker_base_asset_amount_remaining.safe_sub(base_asset_amount)?;
+
+        fills.push((base_asset_amount, quote_asset_amount));
+    }
+
+    Ok((fills, taker_base_asset_amount_remaining))
+}
+
 pub fn calculate_filler_multiplier_for_matched_orders(
     maker_price: u64,
     maker_direction: PositionDirection,
     oracle_price: i64,
 ) -> DriftResult<u64> {
-    // percentage oracle_price is above maker_price
-    let price_pct_diff = oracle_price
-        .safe_sub(maker_price.cast::<i64>()?)?
+    validate!(
+        oracle_price > 0,
+        ErrorCode::InvalidOracle,
+        "oracle_price={} must be positive to calculate filler multiplier",
+        oracle_price
+    )?;
+
+    // price improvement vs oracle for the maker's direction, as a percentage of oracle_price.
+    // positive when a long maker bids above or a short maker asks below the oracle
+    let improvement = match maker_direction {
+        PositionDirection::Long => maker_price.cast::<i64>()?.safe_sub(oracle_price)?,
+        PositionDirection::Short => oracle_price.safe_sub(maker_price.cast::<i64>()?)?,
+    };
+
+    let improvement_pct = improvement
         .cast::<i128>()?
         .safe_mul(BID_ASK_SPREAD_PRECISION_I128)?
         .safe_div(oracle_price.cast()?)?
         .cast::<i64>()?;
 
-    // offer filler multiplier based on price improvement from reasonable baseline
-    // multiplier between 1x and 100x
-    let multiplier = match maker_direction {
-        PositionDirection::Long => (-price_pct_diff).safe_add(TEN_BPS_I64 * 2)?,
-        PositionDirection::Short => price_pct_diff.safe_add(TEN_BPS_I64 * 2)?,
-    }
-    .max(TEN_BPS_I64)
-    .min(TEN_BPS_I64 * 100);
+    // offer filler multiplier based on price improvement from reasonable baseline (20bps worse
+    // than oracle). multiplier between 1x and 100x
+    let baseline_pct = -TEN_BPS_I64 * 2;
+    let multiplier = improvement_pct
+        .safe_sub(baseline_pct)?
+        .max(TEN_BPS_I64)
+        .min(TEN_BPS_I64 * 100);
 
     multiplier.cast()
 }
+
+/// Scales the filler multiplier down by threshold / funding pct once the funding rate
+/// (FUNDING_RATE_PRECISION) exceeds FILLER_MULTIPLIER_FUNDING_RATE_THRESHOLD of the oracle price
+pub fn calculate_filler_multiplier_with_funding(
+    maker_price: u64,
+    maker_direction: PositionDirection,
+    oracle_price: i64,
+    funding_rate: i128,
+) -> DriftResult<u64> {
+    let multiplier =
+        calculate_filler_multiplier_for_matched_orders(maker_price, maker_direction, oracle_price)?;
+
+    let funding_rate_pct = funding_rate
+        .unsigned_abs()
+        .safe_mul(PERCENTAGE_PRECISION)?
+        .safe_div(oracle_price.cast::<u128>()?.safe_mul(FUNDING_RATE_BUFFER)?)?;
+
+    if funding_rate_pct <= FILLER_MULTIPLIER_FUNDING_RATE_THRESHOLD {
+        return Ok(multiplier);
+    }
+
+    multiplier
+        .cast::<u128>()?
+        .safe_mul(FILLER_MULTIPLIER_FUNDING_RATE_THRESHOLD)?
+        .safe_div(funding_rate_pct)?
+        .max(TEN_BPS_I64.cast()?)
+        .cast()
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum MakerRebateTier {
+    None,
+    Bronze,
+    Silver,
+    Gold,
+}
+
+impl Default for MakerRebateTier {
+    fn default() -> Self {
+        MakerRebateTier::None
+    }
+}
+
+pub fn calculate_maker_rebate_tier(filler_multiplier: u64) -> MakerRebateTier {
+    if filler_multiplier >= MAKER_REBATE_TIER_GOLD_MULTIPLIER {
+        MakerRebateTier::Gold
+    } else if filler_multiplier >= MAKER_REBATE_TIER_SILVER_MULTIPLIER {
+        MakerRebateTier::Silver
+    } else if filler_multiplier >= MAKER_REBATE_TIER_BRONZE_MULTIPLIER {
+        MakerRebateTier::Bronze
+    } else {
+        MakerRebateTier::None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+pub enum PostOnlyResolution {
+    Place,  // order can be placed as is
+    Reject, // order isn't placed but tx succeeds
+    Slide,  // order price must be moved to not cross
+}
+
+pub fn resolve_post_only(
+    post_only: PostOnlyParam,
+    would_cross: bool,
+) -> DriftResult<PostOnlyResolution> {
+    if !would_cross {
+        return Ok(PostOnlyResolution::Place);
+    }
+
+    match post_only {
+        PostOnlyParam::None => Ok(PostOnlyResolution::Place),
+        PostOnlyParam::MustPostOnly => {
+            msg!("post only order would cross");
+            Err(ErrorCode::PlacePostOnlyLimitFailure)
+        }
+        PostOnlyParam::TryPostOnly => Ok(PostOnlyResolution::Reject),
+        PostOnlyParam::Slide => Ok(PostOnlyResolution::Slide),
+    }
+}
@@ -1,13 +1,19 @@
 use crate::controller::position::PositionDirection;
+use crate::error::ErrorCode;
 use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
 use crate::math::matching::*;
 
 mod is_maker_for_taker {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
     use crate::math::matching::is_maker_for_taker;
-    use crate::state::user::{Order, OrderType};
+    use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+    use anchor_lang::prelude::Pubkey;
 
     #[test]
     fn taker_is_post_only() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         let taker = Order {
             post_only: true,
             ..Default::default()
@@ -16,11 +22,16 @@ mod is_maker_for_taker {
             post_only: false,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, 0).unwrap(), false);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, 0, 0).unwrap(),
+            false
+        );
     }
 
     #[test]
     fn maker_is_market_order() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         let taker = Order {
             post_only: false,
             order_type: OrderType::Market,
@@ -31,11 +42,16 @@ mod is_maker_for_taker {
             order_type: OrderType::Market,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, 0).unwrap(), false);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, 0, 0).unwrap(),
+            false
+        );
     }
 
     #[test]
     fn maker_is_limit_order_in_auction() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         // market order
         let taker = Order {
             post_only: false,
@@ -49,7 +65,10 @@ mod is_maker_for_taker {
             slot: 0,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, 0).unwrap(), false);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, 0, 0).unwrap(),
+            false
+        );
 
         // limit order in auction
         let taker = Order {
@@ -58,11 +77,16 @@ mod is_maker_for_taker {
             auction_duration: 10,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, 0).unwrap(), false);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, 0, 0).unwrap(),
+            false
+        );
     }
 
     #[test]
     fn maker_is_post_only() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         let slot = 1;
         // market order
         let taker = Order {
@@ -77,7 +101,10 @@ mod is_maker_for_taker {
             slot: slot - 1,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
 
         // limit order in auction
         let taker = Order {
@@ -87,11 +114,93 @@ mod is_maker_for_taker {
             slot: slot - 1,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
+    }
+
+    #[test]
+    fn maker_is_untriggered_trigger_limit_order() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
+        let slot = 1;
+        let taker = Order {
+            post_only: false,
+            order_type: OrderType::Market,
+            slot: slot - 1,
+            ..Default::default()
+        };
+        let mut maker = Order {
+            post_only: true,
+            order_type: OrderType::TriggerLimit,
+            direction: PositionDirection::Long,
+            price: 100,
+            trigger_price: 100,
+            trigger_condition: OrderTriggerCondition::Below,
+            slot: slot - 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            false
+        );
+
+        maker.trigger_condition = OrderTriggerCondition::TriggeredBelow;
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
+    }
+
+    #[test]
+    fn maker_order_expiry() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
+        let slot = 1;
+        let now = 100;
+        let taker = Order {
+            post_only: false,
+            order_type: OrderType::Market,
+            slot: slot - 1,
+            ..Default::default()
+        };
+
+        // expired maker
+        let maker = Order {
+            post_only: true,
+            order_type: OrderType::Limit,
+            slot: slot - 1,
+            max_ts: now - 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, now).unwrap(),
+            false
+        );
+
+        // live maker
+        let maker = Order {
+            max_ts: now,
+            ..maker
+        };
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, now).unwrap(),
+            true
+        );
+
+        // no expiry
+        let maker = Order { max_ts: 0, ..maker };
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, now).unwrap(),
+            true
+        );
     }
 
     #[test]
     fn maker_is_resting_limit_order_after_auction() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         // market order
         let taker = Order {
             post_only: false,
@@ -106,7 +215,10 @@ mod is_maker_for_taker {
         };
         let slot = 11;
         assert_eq!(maker.is_resting_limit_order(slot).unwrap(), true);
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
 
         // limit order in auction
         let taker = Order {
@@ -117,11 +229,16 @@ mod is_maker_for_taker {
             ..Default::default()
         };
         assert_eq!(taker.is_resting_limit_order(slot).unwrap(), false);
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
     }
 
     #[test]
     fn maker_is_post_only_for_resting_taker_limit() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         let slot = 11;
 
         let taker = Order {
@@ -139,11 +256,16 @@ mod is_maker_for_taker {
             order_type: OrderType::Limit,
             ..Default::default()
         };
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
     }
 
     #[test]
     fn maker_and_taker_resting_limit_orders() {
+        let maker_key = Pubkey::new_unique();
+        let taker_key = Pubkey::new_unique();
         let slot = 15;
 
         let taker = Order {
@@ -164,7 +286,10 @@ mod is_maker_for_taker {
         };
         assert_eq!(taker.is_resting_limit_order(slot).unwrap(), true);
 
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), false);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            false
+        );
 
         let taker = Order {
             post_only: false,
@@ -175,7 +300,297 @@ mod is_maker_for_taker {
         };
         assert_eq!(taker.is_resting_limit_order(slot).unwrap(), true);
 
-        assert_eq!(is_maker_for_taker(&maker, &taker, slot).unwrap(), true);
+        assert_eq!(
+            is_maker_for_taker(&maker, &maker_key, &taker, &taker_key, slot, 0).unwrap(),
+            true
+        );
+    }
+
+    #[test]
+    fn same_user() {
+        let key = Pubkey::new_unique();
+        let taker = Order {
+            post_only: false,
+            order_type: OrderType::Market,
+            ..Default::default()
+        };
+        let maker = Order {
+            post_only: true,
+            order_type: OrderType::Limit,
+            ..Default::default()
+        };
+        assert_eq!(
+            is_maker_for_taker(&maker, &key, &taker, &key, 1, 0),
+            Err(ErrorCode::CantMatchSameUser)
+        );
+
+        let taker_key = Pubkey::new_unique();
+        assert_eq!(
+            is_maker_for_taker(&maker, &key, &taker, &taker_key, 1, 0).unwrap(),
+            true
+        );
+    }
+}
+
+mod do_orders_cross_with_oracle_offsets {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::{calculate_effective_price, do_orders_cross_with_oracle_offsets};
+
+    #[test]
+    fn oracle_offset_maker_long_crosses_fixed_taker() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        // maker bids at oracle - 1
+        let maker_oracle_price_offset = -(PRICE_PRECISION_I64 as i32);
+
+        assert!(do_orders_cross_with_oracle_offsets(
+            PositionDirection::Long,
+            0,
+            maker_oracle_price_offset,
+            98 * PRICE_PRECISION_U64,
+            0,
+            oracle_price,
+        )
+        .unwrap());
+
+        assert!(do_orders_cross_with_oracle_offsets(
+            PositionDirection::Long,
+            0,
+            maker_oracle_price_offset,
+            99 * PRICE_PRECISION_U64,
+            0,
+            oracle_price,
+        )
+        .unwrap());
+
+        assert!(!do_orders_cross_with_oracle_offsets(
+            PositionDirection::Long,
+            0,
+            maker_oracle_price_offset,
+            100 * PRICE_PRECISION_U64,
+            0,
+            oracle_price,
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn fixed_maker_short_crosses_oracle_offset_taker() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        // taker bids at oracle + 1
+        let taker_oracle_price_offset = PRICE_PRECISION_I64 as i32;
+
+        assert!(do_orders_cross_with_oracle_offsets(
+            PositionDirection::Short,
+            101 * PRICE_PRECISION_U64,
+            0,
+            0,
+            taker_oracle_price_offset,
+            oracle_price,
+        )
+        .unwrap());
+
+        assert!(!do_orders_cross_with_oracle_offsets(
+            PositionDirection::Short,
+            102 * PRICE_PRECISION_U64,
+            0,
+            0,
+            taker_oracle_price_offset,
+            oracle_price,
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn effective_price_clamped_to_one() {
+        let oracle_price = PRICE_PRECISION_I64;
+
+        assert_eq!(
+            calculate_effective_price(0, -2 * PRICE_PRECISION_I64 as i32, oracle_price).unwrap(),
+            1
+        );
+        assert_eq!(
+            calculate_effective_price(5 * PRICE_PRECISION_U64, 0, oracle_price).unwrap(),
+            5 * PRICE_PRECISION_U64
+        );
+    }
+}
+
+mod calculate_fills_for_matched_orders {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::matching::calculate_fills_for_matched_orders;
+
+    #[test]
+    fn exact_fill() {
+        let makers = [
+            (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+            (2 * BASE_PRECISION_U64, 101 * PRICE_PRECISION_U64),
+        ];
+
+        let (fills, remainder) = calculate_fills_for_matched_orders(
+            3 * BASE_PRECISION_U64,
+            &makers,
+            9,
+            PositionDirection::Short,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fills,
+            vec![
+                (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+                (2 * BASE_PRECISION_U64, 202 * PRICE_PRECISION_U64)
+            ]
+        );
+        assert_eq!(remainder, 0);
+    }
+
+    #[test]
+    fn partial_fill() {
+        let makers = [
+            (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+            (BASE_PRECISION_U64 / 2, 101 * PRICE_PRECISION_U64),
+        ];
+
+        let (fills, remainder) = calculate_fills_for_matched_orders(
+            2 * BASE_PRECISION_U64,
+            &makers,
+            9,
+            PositionDirection::Short,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fills,
+            vec![
+                (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+                (BASE_PRECISION_U64 / 2, 101 * PRICE_PRECISION_U64 / 2)
+            ]
+        );
+        assert_eq!(remainder, BASE_PRECISION_U64 / 2);
+    }
+
+    #[test]
+    fn over_supply_of_makers() {
+        let makers = [
+            (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+            (BASE_PRECISION_U64, 99 * PRICE_PRECISION_U64),
+            (BASE_PRECISION_U64, 98 * PRICE_PRECISION_U64),
+        ];
+
+        let (fills, remainder) = calculate_fills_for_matched_orders(
+            3 * BASE_PRECISION_U64 / 2,
+            &makers,
+            9,
+            PositionDirection::Long,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fills,
+            vec![
+                (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64),
+                (BASE_PRECISION_U64 / 2, 99 * PRICE_PRECISION_U64 / 2)
+            ]
+        );
+        assert_eq!(remainder, 0);
+    }
+}
+
+mod validate_fill_price_within_band {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::validate_fill_price_within_band;
+
+    #[test]
+    fn inside_band() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert!(
+            validate_fill_price_within_band(101 * PRICE_PRECISION_U64 - 1, oracle_price, 100)
+                .is_ok()
+        );
+        assert!(
+            validate_fill_price_within_band(99 * PRICE_PRECISION_U64 + 1, oracle_price, 100)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn on_band() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert!(
+            validate_fill_price_within_band(101 * PRICE_PRECISION_U64, oracle_price, 100).is_ok()
+        );
+        assert!(
+            validate_fill_price_within_band(99 * PRICE_PRECISION_U64, oracle_price, 100).is_ok()
+        );
+    }
+
+    #[test]
+    fn outside_band() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert_eq!(
+            validate_fill_price_within_band(102 * PRICE_PRECISION_U64, oracle_price, 100),
+            Err(ErrorCode::PriceBandsBreached)
+        );
+        assert_eq!(
+            validate_fill_price_within_band(98 * PRICE_PRECISION_U64, oracle_price, 100),
+            Err(ErrorCode::PriceBandsBreached)
+        );
+    }
+}
+
+mod estimate_fill_price_impact {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::estimate_fill_price_impact;
+
+    #[test]
+    fn above_oracle() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert_eq!(
+            estimate_fill_price_impact(101 * PRICE_PRECISION_U64, oracle_price),
+            Ok(100)
+        );
+    }
+
+    #[test]
+    fn at_oracle() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert_eq!(
+            estimate_fill_price_impact(100 * PRICE_PRECISION_U64, oracle_price),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn below_oracle() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert_eq!(
+            estimate_fill_price_impact(995 * PRICE_PRECISION_U64 / 10, oracle_price),
+            Ok(-50)
+        );
+    }
+
+    #[test]
+    fn invalid_oracle_price() {
+        assert_eq!(
+            estimate_fill_price_impact(100 * PRICE_PRECISION_U64, 0),
+            Err(ErrorCode::InvalidOracle)
+        );
+        assert_eq!(
+            estimate_fill_price_impact(100 * PRICE_PRECISION_U64, -PRICE_PRECISION_I64),
+            Err(ErrorCode::InvalidOracle)
+        );
     }
 }
 
@@ -255,3 +670,1488 @@ fn filler_multiplier_maker_short() {
 
     assert_eq!(mult, 2100); // 2.1x
 }
+
+#[test]
+fn filler_multiplier_non_positive_oracle_price() {
+    let maker_price = 34 * PRICE_PRECISION_U64;
+
+    for direction in [PositionDirection::Long, PositionDirection::Short] {
+        assert_eq!(
+            calculate_filler_multiplier_for_matched_orders(maker_price, direction, 0),
+            Err(ErrorCode::InvalidOracle)
+        );
+
+        assert_eq!(
+            calculate_filler_multiplier_for_matched_orders(
+                maker_price,
+                direction,
+                -34 * PRICE_PRECISION_I64
+            ),
+            Err(ErrorCode::InvalidOracle)
+        );
+    }
+}
+
+#[test]
+fn filler_multiplier_positive_oracle_price() {
+    let oracle_price = 34 * PRICE_PRECISION_I64;
+
+    let mult = calculate_filler_multiplier_for_matched_orders(
+        oracle_price as u64,
+        PositionDirection::Short,
+        oracle_price,
+    )
+    .unwrap();
+
+    assert_eq!(mult, 2000); // 2x
+}
+
+#[test]
+fn filler_multiplier_maker_worse_than_baseline() {
+    let oracle_price = 34 * PRICE_PRECISION_I64;
+
+    // long maker bidding well below oracle
+    let mult = calculate_filler_multiplier_for_matched_orders(
+        (oracle_price / 2) as u64,
+        PositionDirection::Long,
+        oracle_price,
+    )
+    .unwrap();
+    assert_eq!(mult, 1000); // 1x floor
+
+    // short maker asking well above oracle
+    let mult = calculate_filler_multiplier_for_matched_orders(
+        (oracle_price * 2) as u64,
+        PositionDirection::Short,
+        oracle_price,
+    )
+    .unwrap();
+    assert_eq!(mult, 1000); // 1x floor
+}
+
+#[test]
+fn filler_multiplier_symmetric_around_oracle() {
+    for oracle_price in [
+        PRICE_PRECISION_I64 / 100,
+        34 * PRICE_PRECISION_I64,
+        21_000 * PRICE_PRECISION_I64,
+    ] {
+        for offset_bps in [0, 1, 5, 19, 20, 21, 100, 999, 1000, 5000] {
+            let offset = oracle_price * offset_bps / 10000;
+
+            let long_improving = calculate_filler_multiplier_for_matched_orders(
+                (oracle_price + offset) as u64,
+                PositionDirection::Long,
+                oracle_price,
+            )
+            .unwrap();
+            let short_improving = calculate_filler_multiplier_for_matched_orders(
+                (oracle_price - offset) as u64,
+                PositionDirection::Short,
+                oracle_price,
+            )
+            .unwrap();
+            assert_eq!(long_improving, short_improving);
+
+            let long_worsening = calculate_filler_multiplier_for_matched_orders(
+                (oracle_price - offset) as u64,
+                PositionDirection::Long,
+                oracle_price,
+            )
+            .unwrap();
+            let short_worsening = calculate_filler_multiplier_for_matched_orders(
+                (oracle_price + offset) as u64,
+                PositionDirection::Short,
+                oracle_price,
+            )
+            .unwrap();
+            assert_eq!(long_worsening, short_worsening);
+
+            assert!(long_improving >= long_worsening);
+            assert!((1000..=100000).contains(&long_improving));
+            assert!((1000..=100000).contains(&long_worsening));
+        }
+    }
+}
+
+mod calculate_maker_rebate_tier {
+    use crate::math::constants::{
+        MAKER_REBATE_TIER_BRONZE_MULTIPLIER, MAKER_REBATE_TIER_GOLD_MULTIPLIER,
+        MAKER_REBATE_TIER_SILVER_MULTIPLIER,
+    };
+    use crate::math::matching::{calculate_maker_rebate_tier, MakerRebateTier};
+
+    #[test]
+    fn tier_boundaries() {
+        assert_eq!(calculate_maker_rebate_tier(1000), MakerRebateTier::None);
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_BRONZE_MULTIPLIER - 1),
+            MakerRebateTier::None
+        );
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_BRONZE_MULTIPLIER),
+            MakerRebateTier::Bronze
+        );
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_SILVER_MULTIPLIER - 1),
+            MakerRebateTier::Bronze
+        );
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_SILVER_MULTIPLIER),
+            MakerRebateTier::Silver
+        );
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_GOLD_MULTIPLIER - 1),
+            MakerRebateTier::Silver
+        );
+        assert_eq!(
+            calculate_maker_rebate_tier(MAKER_REBATE_TIER_GOLD_MULTIPLIER),
+            MakerRebateTier::Gold
+        );
+        assert_eq!(calculate_maker_rebate_tier(100000), MakerRebateTier::Gold);
+    }
+}
+
+mod is_order_triggered {
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::is_order_triggered;
+    use crate::state::user::{Order, OrderTriggerCondition, OrderType};
+
+    #[test]
+    fn triggered_above() {
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_price: 100 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Above,
+            ..Default::default()
+        };
+
+        assert!(is_order_triggered(&order, 101 * PRICE_PRECISION_I64).unwrap());
+        assert!(!is_order_triggered(&order, 100 * PRICE_PRECISION_I64).unwrap());
+    }
+
+    #[test]
+    fn triggered_below() {
+        let order = Order {
+            order_type: OrderType::TriggerLimit,
+            trigger_price: 100 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Below,
+            ..Default::default()
+        };
+
+        assert!(is_order_triggered(&order, 99 * PRICE_PRECISION_I64).unwrap());
+        assert!(!is_order_triggered(&order, 100 * PRICE_PRECISION_I64).unwrap());
+    }
+
+    #[test]
+    fn not_yet_triggered() {
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_price: 100 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::Above,
+            ..Default::default()
+        };
+
+        assert!(!is_order_triggered(&order, 99 * PRICE_PRECISION_I64).unwrap());
+        assert!(!is_order_triggered(&order, 0).unwrap());
+    }
+
+    #[test]
+    fn already_triggered_or_not_trigger_order() {
+        let order = Order {
+            order_type: OrderType::TriggerMarket,
+            trigger_price: 100 * PRICE_PRECISION_U64,
+            trigger_condition: OrderTriggerCondition::TriggeredAbove,
+            ..Default::default()
+        };
+        assert!(is_order_triggered(&order, 99 * PRICE_PRECISION_I64).unwrap());
+
+        let order = Order {
+            order_type: OrderType::Limit,
+            ..Default::default()
+        };
+        assert!(is_order_triggered(&order, 99 * PRICE_PRECISION_I64).unwrap());
+    }
+}
+
+mod validate_reduce_only_fill {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::matching::validate_reduce_only_fill;
+
+    #[test]
+    fn full_reduce() {
+        let fill = validate_reduce_only_fill(
+            BASE_PRECISION_U64 as i64,
+            BASE_PRECISION_U64,
+            PositionDirection::Short,
+        )
+        .unwrap();
+        assert_eq!(fill, BASE_PRECISION_U64);
+
+        let fill = validate_reduce_only_fill(
+            -(BASE_PRECISION_U64 as i64),
+            BASE_PRECISION_U64 / 2,
+            PositionDirection::Long,
+        )
+        .unwrap();
+        assert_eq!(fill, BASE_PRECISION_U64 / 2);
+    }
+
+    #[test]
+    fn over_reduce_clamped() {
+        let fill = validate_reduce_only_fill(
+            BASE_PRECISION_U64 as i64,
+            2 * BASE_PRECISION_U64,
+            PositionDirection::Short,
+        )
+        .unwrap();
+        assert_eq!(fill, BASE_PRECISION_U64);
+
+        let fill = validate_reduce_only_fill(
+            -(BASE_PRECISION_U64 as i64),
+            2 * BASE_PRECISION_U64,
+            PositionDirection::Long,
+        )
+        .unwrap();
+        assert_eq!(fill, BASE_PRECISION_U64);
+    }
+
+    #[test]
+    fn wrong_direction() {
+        assert_eq!(
+            validate_reduce_only_fill(
+                BASE_PRECISION_U64 as i64,
+                BASE_PRECISION_U64,
+                PositionDirection::Long,
+            ),
+            Err(ErrorCode::ReduceOnlyOrderIncreasedRisk)
+        );
+
+        assert_eq!(
+            validate_reduce_only_fill(
+                -(BASE_PRECISION_U64 as i64),
+                BASE_PRECISION_U64,
+                PositionDirection::Short,
+            ),
+            Err(ErrorCode::ReduceOnlyOrderIncreasedRisk)
+        );
+
+        assert_eq!(
+            validate_reduce_only_fill(0, BASE_PRECISION_U64, PositionDirection::Long),
+            Err(ErrorCode::ReduceOnlyOrderIncreasedRisk)
+        );
+    }
+}
+
+mod clamp_fill_to_maker_position {
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::matching::clamp_fill_to_maker_position;
+
+    #[test]
+    fn reduce_only_maker_fill_larger_than_position() {
+        let maker_existing_base_asset_amount = BASE_PRECISION_U64 as i64;
+        assert_eq!(
+            clamp_fill_to_maker_position(
+                2 * BASE_PRECISION_U64,
+                maker_existing_base_asset_amount,
+                true
+            ),
+            BASE_PRECISION_U64
+        );
+
+        assert_eq!(
+            clamp_fill_to_maker_position(
+                2 * BASE_PRECISION_U64,
+                -maker_existing_base_asset_amount,
+                true
+            ),
+            BASE_PRECISION_U64
+        );
+
+        // fill smaller than position is unchanged
+        assert_eq!(
+            clamp_fill_to_maker_position(
+                BASE_PRECISION_U64 / 2,
+                maker_existing_base_asset_amount,
+                true
+            ),
+            BASE_PRECISION_U64 / 2
+        );
+
+        // no position left to reduce
+        assert_eq!(clamp_fill_to_maker_position(BASE_PRECISION_U64, 0, true), 0);
+    }
+
+    #[test]
+    fn non_reduce_only_maker_unaffected() {
+        assert_eq!(
+            clamp_fill_to_maker_position(2 * BASE_PRECISION_U64, BASE_PRECISION_U64 as i64, false),
+            2 * BASE_PRECISION_U64
+        );
+        assert_eq!(
+            clamp_fill_to_maker_position(BASE_PRECISION_U64, 0, false),
+            BASE_PRECISION_U64
+        );
+    }
+}
+
+mod calculate_filler_multiplier_with_funding {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{FUNDING_RATE_BUFFER_I128, PRICE_PRECISION_I64};
+    use crate::math::matching::{
+        calculate_filler_multiplier_for_matched_orders, calculate_filler_multiplier_with_funding,
+    };
+
+    #[test]
+    fn normal_funding() {
+        let oracle_price = 34 * PRICE_PRECISION_I64;
+        let maker_price = (oracle_price + oracle_price / 1000) as u64;
+        // .01% of oracle price
+        let funding_rate = (oracle_price / 10000) as i128 * FUNDING_RATE_BUFFER_I128;
+
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let expected = calculate_filler_multiplier_for_matched_orders(
+                maker_price,
+                direction,
+                oracle_price,
+            )
+            .unwrap();
+
+            assert_eq!(
+                calculate_filler_multiplier_with_funding(
+                    maker_price,
+                    direction,
+                    oracle_price,
+                    funding_rate
+                )
+                .unwrap(),
+                expected
+            );
+            assert_eq!(
+                calculate_filler_multiplier_with_funding(
+                    maker_price,
+                    direction,
+                    oracle_price,
+                    -funding_rate
+                )
+                .unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn high_funding() {
+        let oracle_price = 34 * PRICE_PRECISION_I64;
+        let maker_price = (oracle_price - oracle_price / 1000) as u64;
+        // .4% of oracle price, 4x the threshold
+        let funding_rate = (oracle_price * 4 / 1000) as i128 * FUNDING_RATE_BUFFER_I128;
+
+        let unscaled = calculate_filler_multiplier_for_matched_orders(
+            maker_price,
+            PositionDirection::Short,
+            oracle_price,
+        )
+        .unwrap();
+        assert_eq!(unscaled, 3000); // 3x
+
+        let scaled = calculate_filler_multiplier_with_funding(
+            maker_price,
+            PositionDirection::Short,
+            oracle_price,
+            funding_rate,
+        )
+        .unwrap();
+        assert_eq!(scaled, 1000); // floored at 1x
+
+        let maker_price = (oracle_price - oracle_price / 100) as u64;
+        let scaled = calculate_filler_multiplier_with_funding(
+            maker_price,
+            PositionDirection::Short,
+            oracle_price,
+            -funding_rate,
+        )
+        .unwrap();
+        assert_eq!(scaled, 3000); // 12x / 4
+    }
+}
+
+mod calculate_fill_for_matched_orders_with_price {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::matching::calculate_fill_for_matched_orders_with_price;
+
+    #[test]
+    fn exact_price() {
+        let (base, quote, fill_price) = calculate_fill_for_matched_orders_with_price(
+            BASE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            2 * BASE_PRECISION_U64,
+            9,
+            PositionDirection::Long,
+        )
+        .unwrap();
+
+        assert_eq!(base, BASE_PRECISION_U64);
+        assert_eq!(quote, 100 * PRICE_PRECISION_U64);
+        assert_eq!(fill_price, 100 * PRICE_PRECISION_U64);
+    }
+
+    #[test]
+    fn rounding_moves_price() {
+        let maker_price = PRICE_PRECISION_U64 + 1;
+
+        // maker long rounds quote down
+        let (base, quote, fill_price) = calculate_fill_for_matched_orders_with_price(
+            BASE_PRECISION_U64 / 2,
+            maker_price,
+            BASE_PRECISION_U64 / 2,
+            9,
+            PositionDirection::Long,
+        )
+        .unwrap();
+
+        assert_eq!(base, BASE_PRECISION_U64 / 2);
+        assert_eq!(quote, 500000);
+        assert_eq!(fill_price, PRICE_PRECISION_U64);
+
+        // maker short rounds quote up
+        let (base, quote, fill_price) = calculate_fill_for_matched_orders_with_price(
+            BASE_PRECISION_U64 / 2,
+            maker_price,
+            BASE_PRECISION_U64 / 2,
+            9,
+            PositionDirection::Short,
+        )
+        .unwrap();
+
+        assert_eq!(base, BASE_PRECISION_U64 / 2);
+        assert_eq!(quote, 500001);
+        assert_eq!(fill_price, PRICE_PRECISION_U64 + 2);
+    }
+
+    #[test]
+    fn no_fill() {
+        let (base, quote, fill_price) = calculate_fill_for_matched_orders_with_price(
+            0,
+            100 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            9,
+            PositionDirection::Long,
+        )
+        .unwrap();
+
+        assert_eq!((base, quote, fill_price), (0, 0, 0));
+    }
+}
+
+mod calculate_fill_for_matched_orders {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::matching::{calculate_fill_for_matched_orders, RoundingMode};
+
+    #[test]
+    fn base_decimals_bounds() {
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                100000,
+                5,
+                PositionDirection::Long,
+                0,
+                RoundingMode::TowardMaker
+            ),
+            Err(ErrorCode::InvalidBaseDecimals)
+        );
+
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                100000,
+                6,
+                PositionDirection::Long,
+                0,
+                RoundingMode::TowardMaker
+            ),
+            Ok((100000, 100000))
+        );
+
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                100000,
+                12,
+                PositionDirection::Long,
+                0,
+                RoundingMode::TowardMaker
+            ),
+            Ok((100000, 0))
+        );
+
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                100000,
+                13,
+                PositionDirection::Long,
+                0,
+                RoundingMode::TowardMaker
+            ),
+            Err(ErrorCode::InvalidBaseDecimals)
+        );
+    }
+
+    #[test]
+    fn min_base_asset_amount() {
+        // below minimum
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                50000,
+                6,
+                PositionDirection::Long,
+                50001,
+                RoundingMode::TowardMaker
+            ),
+            Ok((0, 0))
+        );
+
+        // at minimum
+        assert_eq!(
+            calculate_fill_for_matched_orders(
+                100000,
+                1000000,
+                50000,
+                6,
+                PositionDirection::Long,
+                50000,
+                RoundingMode::TowardMaker
+            ),
+            Ok((50000, 50000))
+        );
+    }
+
+    #[test]
+    fn rounding_modes() {
+        // 1.000001 * .0015 = .0015000015 so the quote is 1500 rounded down, 1501 rounded up
+        let fill = |maker_direction: PositionDirection, rounding_mode: RoundingMode| {
+            calculate_fill_for_matched_orders(
+                1500,
+                1000001,
+                1500,
+                6,
+                maker_direction,
+                0,
+                rounding_mode,
+            )
+            .unwrap()
+            .1
+        };
+
+        for maker_direction in [PositionDirection::Long, PositionDirection::Short] {
+            assert_eq!(fill(maker_direction, RoundingMode::Down), 1500);
+            assert_eq!(fill(maker_direction, RoundingMode::Up), 1501);
+        }
+
+        // maker buying pays less, maker selling receives more
+        assert_eq!(
+            fill(PositionDirection::Long, RoundingMode::TowardMaker),
+            1500
+        );
+        assert_eq!(
+            fill(PositionDirection::Short, RoundingMode::TowardMaker),
+            1501
+        );
+
+        // taker selling receives more, taker buying pays less
+        assert_eq!(
+            fill(PositionDirection::Long, RoundingMode::TowardTaker),
+            1501
+        );
+        assert_eq!(
+            fill(PositionDirection::Short, RoundingMode::TowardTaker),
+            1500
+        );
+    }
+
+    #[test]
+    fn exact_division_same_for_all_modes() {
+        for rounding_mode in [
+            RoundingMode::Down,
+            RoundingMode::Up,
+            RoundingMode::TowardMaker,
+            RoundingMode::TowardTaker,
+        ] {
+            assert_eq!(
+                calculate_fill_for_matched_orders(
+                    100000,
+                    1000000,
+                    100000,
+                    6,
+                    PositionDirection::Short,
+                    0,
+                    rounding_mode
+                ),
+                Ok((100000, 100000))
+            );
+        }
+    }
+}
+
+mod calculate_base_from_quote_for_fill {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::constants::PRICE_PRECISION_U64;
+    use crate::math::matching::calculate_base_from_quote_for_fill;
+    use crate::math::orders::calculate_quote_asset_amount_for_maker_order;
+
+    #[test]
+    fn quote_round_trip() {
+        let quote_asset_amount = 500 * PRICE_PRECISION_U64;
+        let maker_price = 33333333; // $33.333333
+
+        let base_asset_amount =
+            calculate_base_from_quote_for_fill(quote_asset_amount, maker_price, 9).unwrap();
+        assert_eq!(base_asset_amount, 15000000150);
+
+        // taker never pays more than requested, and at most one unit less
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let quote_back = calculate_quote_asset_amount_for_maker_order(
+                base_asset_amount,
+                maker_price,
+                9,
+                direction,
+            )
+            .unwrap();
+            assert!(quote_back <= quote_asset_amount);
+            assert!(quote_asset_amount - quote_back <= 1);
+        }
+    }
+
+    #[test]
+    fn base_round_trip() {
+        let base_asset_amount = 1000001;
+        let maker_price = 3 * PRICE_PRECISION_U64 / 2; // $1.5
+
+        let quote_asset_amount = calculate_quote_asset_amount_for_maker_order(
+            base_asset_amount,
+            maker_price,
+            6,
+            PositionDirection::Long,
+        )
+        .unwrap();
+        assert_eq!(quote_asset_amount, 1500001);
+
+        let base_back =
+            calculate_base_from_quote_for_fill(quote_asset_amount, maker_price, 6).unwrap();
+        assert_eq!(base_back, 1000000);
+        assert!(base_asset_amount - base_back <= 1);
+
+        let base_asset_amount = 5 * 10_u64.pow(9);
+        let maker_price = 100 * PRICE_PRECISION_U64;
+        let quote_asset_amount = calculate_quote_asset_amount_for_maker_order(
+            base_asset_amount,
+            maker_price,
+            9,
+            PositionDirection::Short,
+        )
+        .unwrap();
+        assert_eq!(quote_asset_amount, 500 * PRICE_PRECISION_U64);
+        assert_eq!(
+            calculate_base_from_quote_for_fill(quote_asset_amount, maker_price, 9).unwrap(),
+            base_asset_amount
+        );
+    }
+
+    #[test]
+    fn invalid_inputs() {
+        assert_eq!(
+            calculate_base_from_quote_for_fill(PRICE_PRECISION_U64, 0, 9),
+            Err(ErrorCode::InvalidOrderFillPrice)
+        );
+
+        assert_eq!(
+            calculate_base_from_quote_for_fill(PRICE_PRECISION_U64, PRICE_PRECISION_U64, 13),
+            Err(ErrorCode::InvalidBaseDecimals)
+        );
+    }
+}
+
+mod resolve_post_only {
+    use crate::error::ErrorCode;
+    use crate::math::matching::{resolve_post_only, PostOnlyResolution};
+    use crate::state::order_params::PostOnlyParam;
+
+    #[test]
+    fn none() {
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::None, false),
+            Ok(PostOnlyResolution::Place)
+        );
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::None, true),
+            Ok(PostOnlyResolution::Place)
+        );
+    }
+
+    #[test]
+    fn must_post_only() {
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::MustPostOnly, false),
+            Ok(PostOnlyResolution::Place)
+        );
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::MustPostOnly, true),
+            Err(ErrorCode::PlacePostOnlyLimitFailure)
+        );
+    }
+
+    #[test]
+    fn try_post_only() {
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::TryPostOnly, false),
+            Ok(PostOnlyResolution::Place)
+        );
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::TryPostOnly, true),
+            Ok(PostOnlyResolution::Reject)
+        );
+    }
+
+    #[test]
+    fn slide() {
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::Slide, false),
+            Ok(PostOnlyResolution::Place)
+        );
+        assert_eq!(
+            resolve_post_only(PostOnlyParam::Slide, true),
+            Ok(PostOnlyResolution::Slide)
+        );
+    }
+}
+
+mod match_orders_with_trace {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{
+        BASE_PRECISION_U64, PRICE_PRECISION_I64, PRICE_PRECISION_U64, QUOTE_PRECISION_U64,
+    };
+    use crate::math::matching::{match_orders_with_trace, MatchTrace};
+    use crate::state::user::{Order, OrderType};
+    use anchor_lang::prelude::Pubkey;
+
+    fn orders() -> (Order, Order) {
+        let maker = Order {
+            post_only: true,
+            order_type: OrderType::Limit,
+            direction: PositionDirection::Short,
+            price: 100 * PRICE_PRECISION_U64,
+            base_asset_amount: BASE_PRECISION_U64,
+            ..Default::default()
+        };
+        let taker = Order {
+            order_type: OrderType::Market,
+            direction: PositionDirection::Long,
+            price: 101 * PRICE_PRECISION_U64,
+            base_asset_amount: BASE_PRECISION_U64 / 2,
+            ..Default::default()
+        };
+        (maker, taker)
+    }
+
+    fn trace(maker: &Order, taker: &Order) -> (Option<(u64, u64)>, MatchTrace) {
+        trace_with_improvement(maker, taker, None)
+    }
+
+    fn trace_with_improvement(
+        maker: &Order,
+        taker: &Order,
+        maker_improvement_bps: Option<u64>,
+    ) -> (Option<(u64, u64)>, MatchTrace) {
+        match_orders_with_trace(
+            maker,
+            &Pubkey::new_unique(),
+            taker,
+            &Pubkey::new_unique(),
+            100 * PRICE_PRECISION_I64,
+            9,
+            1,
+            0,
+            maker_improvement_bps,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn filled() {
+        let (maker, taker) = orders();
+        assert_eq!(
+            trace(&maker, &taker),
+            (
+                Some((BASE_PRECISION_U64 / 2, 50 * QUOTE_PRECISION_U64)),
+                MatchTrace::Filled
+            )
+        );
+    }
+
+    #[test]
+    fn two_post_only() {
+        let (maker, mut taker) = orders();
+        taker.post_only = true;
+        assert_eq!(trace(&maker, &taker), (None, MatchTrace::TwoPostOnly));
+    }
+
+    #[test]
+    fn not_maker_for_taker() {
+        let (mut maker, taker) = orders();
+        maker.post_only = false;
+        maker.order_type = OrderType::Market;
+        assert_eq!(trace(&maker, &taker), (None, MatchTrace::NotMakerForTaker));
+    }
+
+    #[test]
+    fn same_user() {
+        let (maker, taker) = orders();
+        let key = Pubkey::new_unique();
+        assert_eq!(
+            match_orders_with_trace(
+                &maker,
+                &key,
+                &taker,
+                &key,
+                100 * PRICE_PRECISION_I64,
+                9,
+                1,
+                0,
+                None,
+            ),
+            Err(ErrorCode::CantMatchSameUser)
+        );
+    }
+
+    #[test]
+    fn not_opposite_sides() {
+        let (maker, mut taker) = orders();
+        taker.direction = PositionDirection::Short;
+        assert_eq!(trace(&maker, &taker), (None, MatchTrace::NotOppositeSides));
+    }
+
+    #[test]
+    fn did_not_cross() {
+        let (maker, mut taker) = orders();
+        taker.price = 99 * PRICE_PRECISION_U64;
+        assert_eq!(trace(&maker, &taker), (None, MatchTrace::DidNotCross));
+    }
+
+    #[test]
+    fn maker_chosen_nothing_to_fill() {
+        let (mut maker, taker) = orders();
+        maker.base_asset_amount_filled = maker.base_asset_amount;
+        assert_eq!(trace(&maker, &taker), (None, MatchTrace::MakerChosen));
+    }
+
+    #[test]
+    fn maker_did_not_improve() {
+        let (maker, taker) = orders();
+        // maker ask is at the oracle price
+        assert_eq!(
+            trace_with_improvement(&maker, &taker, Some(10)),
+            (None, MatchTrace::MakerDidNotImprove)
+        );
+        assert_eq!(
+            trace_with_improvement(&maker, &taker, Some(0)).1,
+            MatchTrace::Filled
+        );
+    }
+}
+
+mod qualifies_as_maker {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::qualifies_as_maker;
+
+    #[test]
+    fn improving_maker() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        // bid 20 bps above oracle, ask 20 bps below
+        assert!(qualifies_as_maker(
+            PositionDirection::Long,
+            1002 * PRICE_PRECISION_U64 / 10,
+            oracle_price,
+            10
+        ));
+        assert!(qualifies_as_maker(
+            PositionDirection::Short,
+            998 * PRICE_PRECISION_U64 / 10,
+            oracle_price,
+            10
+        ));
+    }
+
+    #[test]
+    fn non_improving_maker() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        // bid 5 bps above oracle, ask at oracle
+        assert!(!qualifies_as_maker(
+            PositionDirection::Long,
+            10005 * PRICE_PRECISION_U64 / 100,
+            oracle_price,
+            10
+        ));
+        assert!(!qualifies_as_maker(
+            PositionDirection::Short,
+            100 * PRICE_PRECISION_U64,
+            oracle_price,
+            10
+        ));
+    }
+
+    #[test]
+    fn at_threshold() {
+        let oracle_price = 100 * PRICE_PRECISION_I64;
+
+        assert!(qualifies_as_maker(
+            PositionDirection::Long,
+            1001 * PRICE_PRECISION_U64 / 10,
+            oracle_price,
+            10
+        ));
+        assert!(qualifies_as_maker(
+            PositionDirection::Short,
+            999 * PRICE_PRECISION_U64 / 10,
+            oracle_price,
+            10
+        ));
+
+        assert!(!qualifies_as_maker(
+            PositionDirection::Long,
+            1001 * PRICE_PRECISION_U64 / 10 - 1,
+            oracle_price,
+            10
+        ));
+        assert!(!qualifies_as_maker(
+            PositionDirection::Short,
+            999 * PRICE_PRECISION_U64 / 10 + 1,
+            oracle_price,
+            10
+        ));
+    }
+
+    #[test]
+    fn invalid_oracle_price() {
+        assert!(!qualifies_as_maker(
+            PositionDirection::Long,
+            100 * PRICE_PRECISION_U64,
+            0,
+            0
+        ));
+    }
+}
+
+mod do_orders_cross_within_slippage {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::PRICE_PRECISION_U64;
+    use crate::math::matching::do_orders_cross_within_slippage;
+
+    #[test]
+    fn crossing_within_slippage() {
+        let taker_price = 100 * PRICE_PRECISION_U64;
+
+        // taker buying from short maker 50 bps below taker price
+        assert!(do_orders_cross_within_slippage(
+            PositionDirection::Short,
+            taker_price - taker_price / 200,
+            taker_price,
+            100
+        ));
+
+        // taker selling to long maker 100 bps above taker price, on the band
+        assert!(do_orders_cross_within_slippage(
+            PositionDirection::Long,
+            taker_price + taker_price / 100,
+            taker_price,
+            100
+        ));
+    }
+
+    #[test]
+    fn crossing_beyond_slippage() {
+        let taker_price = 100 * PRICE_PRECISION_U64;
+
+        assert!(!do_orders_cross_within_slippage(
+            PositionDirection::Short,
+            taker_price - taker_price / 50,
+            taker_price,
+            100
+        ));
+
+        assert!(!do_orders_cross_within_slippage(
+            PositionDirection::Long,
+            taker_price + taker_price / 100 + 1,
+            taker_price,
+            100
+        ));
+    }
+
+    #[test]
+    fn not_crossing() {
+        let taker_price = 100 * PRICE_PRECISION_U64;
+
+        assert!(!do_orders_cross_within_slippage(
+            PositionDirection::Short,
+            taker_price + 1,
+            taker_price,
+            100
+        ));
+
+        assert!(!do_orders_cross_within_slippage(
+            PositionDirection::Long,
+            taker_price - 1,
+            taker_price,
+            100
+        ));
+    }
+}
+
+mod buckets_for_match {
+    use crate::math::matching::buckets_for_match;
+    use crate::state::user::{CollateralBucket, Order};
+
+    #[test]
+    fn all_combinations() {
+        for maker_bucket in [CollateralBucket::Cross, CollateralBucket::Isolated] {
+            for taker_bucket in [CollateralBucket::Cross, CollateralBucket::Isolated] {
+                let maker = Order {
+                    collateral_bucket: maker_bucket,
+                    ..Default::default()
+                };
+                let taker = Order {
+                    collateral_bucket: taker_bucket,
+                    ..Default::default()
+                };
+
+                assert_eq!(
+                    buckets_for_match(&maker, &taker),
+                    (maker_bucket, taker_bucket)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn defaults_to_cross() {
+        let order = Order::default();
+        assert_eq!(
+            buckets_for_match(&order, &order),
+            (CollateralBucket::Cross, CollateralBucket::Cross)
+        );
+    }
+}
+
+mod compare_maker_orders {
+    use std::cmp::Ordering;
+
+    use crate::math::constants::BASE_PRECISION_U64;
+    use crate::math::matching::compare_maker_orders;
+    use crate::state::user::Order;
+
+    #[test]
+    fn earlier_slot_first() {
+        let maker_a = Order {
+            slot: 1,
+            base_asset_amount: BASE_PRECISION_U64,
+            ..Order::default()
+        };
+        let maker_b = Order {
+            slot: 2,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        assert_eq!(compare_maker_orders(&maker_a, &maker_b), Ordering::Less);
+        assert_eq!(compare_maker_orders(&maker_b, &maker_a), Ordering::Greater);
+    }
+
+    #[test]
+    fn equal_slot_different_size() {
+        let maker_a = Order {
+            slot: 1,
+            base_asset_amount: BASE_PRECISION_U64,
+            order_id: 1,
+            ..Order::default()
+        };
+        let maker_b = Order {
+            slot: 1,
+            base_asset_amount: 2 * BASE_PRECISION_U64,
+            order_id: 2,
+            ..Order::default()
+        };
+
+        // larger order first even though it has the higher order id
+        assert_eq!(compare_maker_orders(&maker_b, &maker_a), Ordering::Less);
+
+        let mut makers = [maker_a, maker_b];
+        makers.sort_by(compare_maker_orders);
+        assert_eq!(makers[0].order_id, 2);
+    }
+
+    #[test]
+    fn equal_slot_equal_size() {
+        let maker_a = Order {
+            slot: 1,
+            base_asset_amount: BASE_PRECISION_U64,
+            order_id: 1,
+            ..Order::default()
+        };
+        let maker_b = Order {
+            order_id: 2,
+            ..maker_a
+        };
+
+        assert_eq!(compare_maker_orders(&maker_a, &maker_b), Ordering::Less);
+        assert_eq!(compare_maker_orders(&maker_a, &maker_a), Ordering::Equal);
+    }
+}
+
+mod match_orders {
+    use crate::controller::position::PositionDirection;
+    use crate::error::ErrorCode;
+    use crate::math::constants::{BASE_PRECISION_U64, PRICE_PRECISION_U64};
+    use crate::math::matching::{match_orders, RoundingMode};
+    use crate::state::user::{MarketType, Order};
+
+    #[test]
+    fn fills_opposite_sides_same_market() {
+        let maker_order = Order {
+            market_index: 1,
+            direction: PositionDirection::Short,
+            ..Order::default()
+        };
+        let taker_order = Order {
+            market_index: 1,
+            direction: PositionDirection::Long,
+            ..Order::default()
+        };
+
+        let fill = match_orders(
+            &maker_order,
+            &taker_order,
+            BASE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            2 * BASE_PRECISION_U64,
+            9,
+            0,
+            RoundingMode::TowardMaker,
+        )
+        .unwrap();
+
+        assert_eq!(fill, (BASE_PRECISION_U64, 100 * PRICE_PRECISION_U64));
+    }
+
+    #[test]
+    fn cross_market_rejected() {
+        let maker_order = Order {
+            market_index: 1,
+            direction: PositionDirection::Short,
+            ..Order::default()
+        };
+        let taker_order = Order {
+            market_index: 2,
+            direction: PositionDirection::Long,
+            ..Order::default()
+        };
+
+        let result = match_orders(
+            &maker_order,
+            &taker_order,
+            BASE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            9,
+            0,
+            RoundingMode::TowardMaker,
+        );
+        assert_eq!(result, Err(ErrorCode::OrderMarketMismatch));
+
+        let taker_order = Order {
+            market_index: 1,
+            market_type: MarketType::Spot,
+            direction: PositionDirection::Long,
+            ..Order::default()
+        };
+
+        let result = match_orders(
+            &maker_order,
+            &taker_order,
+            BASE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            9,
+            0,
+            RoundingMode::TowardMaker,
+        );
+        assert_eq!(result, Err(ErrorCode::OrderMarketMismatch));
+    }
+
+    #[test]
+    fn same_side_rejected() {
+        let maker_order = Order {
+            market_index: 1,
+            direction: PositionDirection::Long,
+            ..Order::default()
+        };
+        let taker_order = Order {
+            market_index: 1,
+            direction: PositionDirection::Long,
+            ..Order::default()
+        };
+
+        let result = match_orders(
+            &maker_order,
+            &taker_order,
+            BASE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+            BASE_PRECISION_U64,
+            9,
+            0,
+            RoundingMode::TowardMaker,
+        );
+        assert_eq!(result, Err(ErrorCode::OrdersOnSameSide));
+    }
+}
+
+mod select_maker {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::{PRICE_PRECISION_I128, PRICE_PRECISION_I64, PRICE_PRECISION_U64};
+    use crate::math::matching::select_maker;
+    use crate::state::user::Order;
+
+    fn maker(order_id: u32, direction: PositionDirection, price: u64) -> Order {
+        Order {
+            order_id,
+            direction,
+            price,
+            base_asset_amount: 1,
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn best_priced_ask_chosen() {
+        let taker = Order {
+            direction: PositionDirection::Long,
+            price: 102 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let makers = [
+            maker(1, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+            maker(2, PositionDirection::Short, 100 * PRICE_PRECISION_U64),
+            // same side as the taker
+            maker(3, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+            // doesn't cross
+            maker(4, PositionDirection::Short, 103 * PRICE_PRECISION_U64),
+            // oracle order priced at 99.5
+            Order {
+                order_id: 5,
+                direction: PositionDirection::Short,
+                oracle_price_offset: -(PRICE_PRECISION_I64 as i32) / 2,
+                base_asset_amount: 1,
+                ..Order::default()
+            },
+        ];
+
+        let best = select_maker(&makers, &taker, 100 * PRICE_PRECISION_I128).unwrap();
+        assert_eq!(best.map(|maker| maker.order_id), Some(5));
+    }
+
+    #[test]
+    fn best_priced_bid_chosen_with_tie_break() {
+        let taker = Order {
+            direction: PositionDirection::Short,
+            price: 98 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let makers = [
+            maker(1, PositionDirection::Long, 99 * PRICE_PRECISION_U64),
+            Order {
+                slot: 2,
+                ..maker(2, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+            },
+            Order {
+                slot: 1,
+                ..maker(3, PositionDirection::Long, 100 * PRICE_PRECISION_U64)
+            },
+        ];
+
+        // same price, earlier slot wins
+        let best = select_maker(&makers, &taker, 100 * PRICE_PRECISION_I128).unwrap();
+        assert_eq!(best.map(|maker| maker.order_id), Some(3));
+    }
+
+    #[test]
+    fn none_cross() {
+        let taker = Order {
+            direction: PositionDirection::Long,
+            price: 99 * PRICE_PRECISION_U64,
+            ..Order::default()
+        };
+
+        let makers = [
+            maker(1, PositionDirection::Short, 100 * PRICE_PRECISION_U64),
+            maker(2, PositionDirection::Short, 101 * PRICE_PRECISION_U64),
+        ];
+
+        assert_eq!(
+            select_maker(&makers, &taker, 100 * PRICE_PRECISION_I128),
+            Ok(None)
+        );
+        assert_eq!(
+            select_maker(&[], &taker, 100 * PRICE_PRECISION_I128),
+            Ok(None)
+        );
+    }
+}
+
+mod validate_order_notional {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{BASE_PRECISION, PRICE_PRECISION, QUOTE_PRECISION};
+    use crate::math::matching::validate_order_notional;
+
+    #[test]
+    fn below_min_notional() {
+        // 0.099999999 @ $100 = $9.9999999, rounds down to $9.999999
+        assert_eq!(
+            validate_order_notional(
+                BASE_PRECISION / 10 - 1,
+                100 * PRICE_PRECISION,
+                10 * QUOTE_PRECISION,
+                9
+            ),
+            Err(ErrorCode::OrderBelowMinNotional)
+        );
+    }
+
+    #[test]
+    fn at_min_notional() {
+        // 0.1 @ $100 = $10
+        assert_eq!(
+            validate_order_notional(
+                BASE_PRECISION / 10,
+                100 * PRICE_PRECISION,
+                10 * QUOTE_PRECISION,
+                9
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn above_min_notional() {
+        assert_eq!(
+            validate_order_notional(
+                BASE_PRECISION,
+                100 * PRICE_PRECISION,
+                10 * QUOTE_PRECISION,
+                9
+            ),
+            Ok(())
+        );
+
+        // 6 decimal base, 30 @ $0.5 = $15
+        assert_eq!(
+            validate_order_notional(30_000_000, PRICE_PRECISION / 2, 10 * QUOTE_PRECISION, 6),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn invalid_base_decimals() {
+        assert_eq!(
+            validate_order_notional(BASE_PRECISION, 100 * PRICE_PRECISION, 0, 20),
+            Err(ErrorCode::InvalidBaseDecimals)
+        );
+    }
+}
+
+mod cross_depth {
+    use crate::controller::position::PositionDirection;
+    use crate::math::constants::PRICE_PRECISION_U64;
+    use crate::math::matching::{cross_depth, do_orders_cross};
+
+    #[test]
+    fn deep_cross() {
+        // stale long maker bidding $110 against a taker selling at $100
+        let depth = cross_depth(
+            PositionDirection::Long,
+            110 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+        );
+        assert_eq!(depth, 10 * PRICE_PRECISION_U64 as i128);
+
+        let depth = cross_depth(
+            PositionDirection::Short,
+            90 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+        );
+        assert_eq!(depth, 10 * PRICE_PRECISION_U64 as i128);
+    }
+
+    #[test]
+    fn shallow_cross() {
+        let price = 100 * PRICE_PRECISION_U64;
+
+        assert_eq!(cross_depth(PositionDirection::Long, price + 1, price), 1);
+        assert_eq!(cross_depth(PositionDirection::Short, price - 1, price), 1);
+
+        // touching prices cross with zero depth
+        assert_eq!(cross_depth(PositionDirection::Long, price, price), 0);
+        assert_eq!(cross_depth(PositionDirection::Short, price, price), 0);
+        assert!(do_orders_cross(PositionDirection::Long, price, price));
+        assert!(do_orders_cross(PositionDirection::Short, price, price));
+    }
+
+    #[test]
+    fn no_cross() {
+        let depth = cross_depth(
+            PositionDirection::Long,
+            99 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+        );
+        assert_eq!(depth, -(PRICE_PRECISION_U64 as i128));
+        assert!(!do_orders_cross(
+            PositionDirection::Long,
+            99 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64
+        ));
+
+        let depth = cross_depth(
+            PositionDirection::Short,
+            101 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64,
+        );
+        assert_eq!(depth, -(PRICE_PRECISION_U64 as i128));
+        assert!(!do_orders_cross(
+            PositionDirection::Short,
+            101 * PRICE_PRECISION_U64,
+            100 * PRICE_PRECISION_U64
+        ));
+
+        // extreme prices don't overflow
+        assert_eq!(
+            cross_depth(PositionDirection::Long, 0, u64::MAX),
+            -(u64::MAX as i128)
+        );
+    }
+}
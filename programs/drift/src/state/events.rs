@@ -4,6 +4,7 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use crate::controller::position::PositionDirection;
 use crate::error::{DriftResult, ErrorCode::InvalidOrder};
 use crate::math::casting::Cast;
+use crate::math::margin::MarginRequirementType;
 use crate::math::safe_unwrap::SafeUnwrap;
 use crate::state::traits::Size;
 use crate::state::user::{MarketType, Order};
@@ -506,8 +507,18 @@ pub struct SettlePnlRecord {
     pub quote_entry_amount: i64,
     pub settle_price: i64,
     pub explanation: SettlePnlExplanation,
+    pub pnl_pool_balance_after: u128,
+    pub fee: u128,
+    /// position's cumulative settled pnl including this settlement
+    pub settled_pnl_after: i64,
+    /// layout version of the record, see SETTLE_PNL_RECORD_VERSION
+    pub version: u8,
 }
 
+/// Bumped whenever SettlePnlRecord's layout changes.
+/// 1: added settle_price, pnl_pool_balance_after, fee and settled_pnl_after
+pub const SETTLE_PNL_RECORD_VERSION: u8 = 1;
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
 pub enum SettlePnlExplanation {
     None,
@@ -521,6 +532,45 @@ impl Default for SettlePnlExplanation {
     }
 }
 
+#[event]
+#[derive(Default)]
+pub struct SocializedLossRecord {
+    pub ts: i64,
+    pub user: Pubkey,
+    pub market_index: u16,
+    /// positive pnl written off when settling instead of being paid out
+    /// precision: QUOTE_PRECISION
+    pub socialized_loss: u128,
+    /// precision: PERCENTAGE_PRECISION
+    pub socialized_loss_ratio_before: u32,
+    /// precision: PERCENTAGE_PRECISION
+    pub socialized_loss_ratio_after: u32,
+}
+
+#[event]
+pub struct OracleInvalidRecord {
+    pub ts: i64,
+    pub user: Pubkey,
+    pub market_type: MarketType,
+    pub market_index: u16,
+}
+
+#[event]
+pub struct MarginRequirementRecord {
+    pub ts: i64,
+    pub user: Pubkey,
+    pub margin_requirement_type: MarginRequirementType,
+    /// precision: QUOTE_PRECISION
+    pub total_collateral: i128,
+    /// precision: QUOTE_PRECISION
+    pub margin_requirement: u128,
+    /// precision: QUOTE_PRECISION
+    pub margin_requirement_plus_buffer: u128,
+    pub num_spot_liabilities: u8,
+    pub num_perp_liabilities: u8,
+    pub all_oracles_valid: bool,
+}
+
 #[event]
 #[derive(Default)]
 pub struct InsuranceFundRecord {
@@ -1,10 +1,21 @@
 use crate::error::{DriftResult, ErrorCode};
+#[cfg(feature = "drift-rs")]
+use crate::math::bn::U192;
 use crate::math::casting::Cast;
+#[cfg(feature = "drift-rs")]
+use crate::math::constants::{LEVERAGE_PRECISION, PRICE_PRECISION};
+use crate::math::constants::{
+    LIQUIDATION_PRIORITY_LIABILITY_WEIGHT, PERCENTAGE_PRECISION, SPOT_WEIGHT_PRECISION_I128,
+};
 use crate::math::margin::MarginRequirementType;
 use crate::math::safe_math::SafeMath;
+use crate::state::events::{MarginRequirementRecord, OracleInvalidRecord};
 use crate::{validate, MarketType, MARGIN_PRECISION_U128};
 use anchor_lang::{prelude::*, solana_program::msg};
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Clone, Copy, Debug)]
 pub enum MarginCalculationMode {
     Standard {
@@ -12,6 +23,12 @@ pub enum MarginCalculationMode {
     },
     Liquidation {
         market_to_track_margin_requirement: Option<MarketIdentifier>,
+        exit_buffer: u128,
+        collateral_buffer_bps: u128,
+        liquidation_fee: u128,
+    },
+    Withdrawal {
+        withdraw_amount: u128,
     },
 }
 
@@ -21,6 +38,62 @@ pub struct MarginContext {
     pub mode: MarginCalculationMode,
     pub strict: bool,
     pub margin_buffer: u128,
+    #[cfg(feature = "drift-rs")]
+    pub track_breakdown: bool,
+    pub strict_asset_discount: u128,
+    pub track_unrealized_funding: bool,
+    pub track_both_requirements: bool,
+    pub include_open_orders: bool,
+    pub buffered_requirement: bool,
+    pub project_borrow_interest: bool,
+    pub quote_only: bool,
+    pub max_leverage: Option<u32>,
+    pub strict_confidence: bool,
+    /// user and ts for OracleInvalidRecords, None when they aren't emitted
+    pub emit_oracle_invalid_records: Option<(Pubkey, i64)>,
+}
+
+pub const MAX_MARGIN_BREAKDOWN_ENTRIES: usize = 16;
+
+/// get_leverage for an account with liabilities and zero or negative net asset value
+#[cfg(feature = "drift-rs")]
+pub const UNDERWATER_LEVERAGE: u128 = u128::MAX;
+
+#[derive(Default, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct MarketMarginContribution {
+    pub market_index: u16,
+    pub margin_requirement: u128,
+    pub liability_value: u128,
+}
+
+#[cfg(feature = "drift-rs")]
+#[derive(Default, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct MarginRatioComponents {
+    pub total_spot_asset_value: i128,
+    pub total_spot_liability_value: u128,
+    pub total_perp_liability_value: u128,
+    pub net_asset_value: i128,
+    pub margin_ratio: u128,
+}
+
+/// Where total_collateral came from. Unrealized funding is part of perp_pnl, see
+/// total_unrealized_funding for that portion
+#[derive(Default, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CollateralComposition {
+    pub quote: i128,
+    pub spot: i128,
+    pub perp_pnl: i128,
+    pub other: i128,
+}
+
+#[cfg(feature = "drift-rs")]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum MarginRatioStatus {
+    /// no liabilities
+    Flat,
+    /// has liabilities and net asset value is zero or negative
+    Underwater,
+    Ratio(u128),
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug, AnchorSerialize, AnchorDeserialize)]
@@ -54,6 +127,28 @@ impl MarginContext {
             },
             strict: false,
             margin_buffer: 0,
+            #[cfg(feature = "drift-rs")]
+            track_breakdown: false,
+            strict_asset_discount: 0,
+            track_unrealized_funding: false,
+            track_both_requirements: false,
+            include_open_orders: false,
+            buffered_requirement: false,
+            project_borrow_interest: false,
+            quote_only: false,
+            max_leverage: None,
+            strict_confidence: false,
+            emit_oracle_invalid_records: None,
+        }
+    }
+
+    /// Initial margin check that must also clear margin_buffer, e.g. so a newly placed order doesn't
+    /// leave the account right at the edge of liquidation
+    pub fn initial_with_buffer(margin_buffer: u32) -> Self {
+        Self {
+            margin_buffer: margin_buffer as u128,
+            buffered_requirement: true,
+            ..Self::standard(MarginRequirementType::Initial)
         }
     }
 
@@ -67,6 +162,72 @@ impl MarginContext {
         self
     }
 
+    pub fn strict_asset_discount(mut self, strict_asset_discount: u32) -> Self {
+        self.strict_asset_discount = strict_asset_discount as u128;
+        self
+    }
+
+    /// In strict mode, values spot assets at the bottom and spot liabilities at the top of the
+    /// oracle confidence interval
+    pub fn strict_confidence(mut self) -> Self {
+        self.strict_confidence = true;
+        self
+    }
+
+    pub fn track_unrealized_funding(mut self) -> Self {
+        self.track_unrealized_funding = true;
+        self
+    }
+
+    /// Reserves margin for resting orders via add_open_order_margin, only applies to initial margin
+    pub fn include_open_orders(mut self) -> Self {
+        self.include_open_orders = true;
+        self
+    }
+
+    /// Folds projected borrow interest into spot liabilities via add_spot_liability_value_with_interest
+    pub fn project_borrow_interest(mut self) -> Self {
+        self.project_borrow_interest = true;
+        self
+    }
+
+    /// Skips non-quote spot positions during accumulation and allows
+    /// meets_margin_requirement_quote_only. Non-quote deposits are ignored, non-quote borrows and
+    /// open orders are only counted as spot liabilities so meets_margin_requirement_quote_only fails
+    pub fn quote_only(mut self) -> Self {
+        self.quote_only = true;
+        self
+    }
+
+    /// Emits an OracleInvalidRecord for each market with an invalid oracle. Only set on the first
+    /// margin calculation of an instruction so the same market isn't reported more than once
+    pub fn emit_oracle_invalid_records(mut self, user_key: Pubkey, now: i64) -> Self {
+        self.emit_oracle_invalid_records = Some((user_key, now));
+        self
+    }
+
+    /// Caps total liability value at max_leverage times total collateral for initial margin checks,
+    /// on top of the market margin ratios
+    pub fn max_leverage(mut self, max_leverage: u32) -> Self {
+        self.max_leverage = Some(max_leverage);
+        self
+    }
+
+    /// Also accumulates the maintenance requirement and collateral in the same pass as an initial
+    /// margin calculation
+    pub fn track_both_requirements(mut self) -> DriftResult<Self> {
+        match (self.mode, self.margin_type) {
+            (MarginCalculationMode::Standard { .. }, MarginRequirementType::Initial) => {
+                self.track_both_requirements = true;
+            }
+            _ => {
+                msg!("Can only track both requirements for initial margin in standard mode");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        }
+        Ok(self)
+    }
+
     pub fn track_open_orders_fraction(mut self) -> DriftResult<Self> {
         match self.mode {
             MarginCalculationMode::Standard {
@@ -82,14 +243,39 @@ impl MarginContext {
         Ok(self)
     }
 
+    /// Breakdown entries are kept off the on-chain margin calculation to save stack space
+    #[cfg(feature = "drift-rs")]
+    pub fn track_breakdown(mut self) -> DriftResult<Self> {
+        match self.mode {
+            MarginCalculationMode::Standard { .. } => {
+                self.track_breakdown = true;
+            }
+            _ => {
+                msg!("Cant track margin breakdown outside of standard mode");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Requires the account to still meet margin_type after withdraw_amount leaves total_collateral
+    pub fn withdrawal(margin_type: MarginRequirementType, withdraw_amount: u128) -> Self {
+        Self {
+            mode: MarginCalculationMode::Withdrawal { withdraw_amount },
+            ..Self::standard(margin_type)
+        }
+    }
+
     pub fn liquidation(margin_buffer: u32) -> Self {
         Self {
-            margin_type: MarginRequirementType::Maintenance,
             mode: MarginCalculationMode::Liquidation {
                 market_to_track_margin_requirement: None,
+                exit_buffer: 0,
+                collateral_buffer_bps: 0,
+                liquidation_fee: 0,
             },
             margin_buffer: margin_buffer as u128,
-            strict: false,
+            ..Self::standard(MarginRequirementType::Maintenance)
         }
     }
 
@@ -111,6 +297,63 @@ impl MarginContext {
         }
         Ok(self)
     }
+
+    /// Extra collateral required on top of margin_requirement_plus_buffer to exit liquidation,
+    /// prevents accounts flipping in and out of liquidation on small price moves
+    pub fn liquidation_exit_buffer(mut self, exit_buffer: u128) -> DriftResult<Self> {
+        match self.mode {
+            MarginCalculationMode::Liquidation {
+                exit_buffer: ref mut buffer,
+                ..
+            } => {
+                *buffer = exit_buffer;
+            }
+            _ => {
+                msg!("Cant set liquidation exit buffer outside of liquidation mode");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Buffer scaled to total collateral rather than liability value, added to
+    /// margin_requirement_plus_buffer by finalize on top of the per liability margin_buffer
+    pub fn liquidation_collateral_buffer(
+        mut self,
+        collateral_buffer_bps: u32,
+    ) -> DriftResult<Self> {
+        match self.mode {
+            MarginCalculationMode::Liquidation {
+                collateral_buffer_bps: ref mut buffer,
+                ..
+            } => {
+                *buffer = collateral_buffer_bps as u128;
+            }
+            _ => {
+                msg!("Cant set liquidation collateral buffer outside of liquidation mode");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Fee charged to the liquidated user, reserved against collateral by finalize so the user
+    /// has to cover it to exit liquidation
+    pub fn liquidation_fee(mut self, liquidation_fee: u128) -> DriftResult<Self> {
+        match self.mode {
+            MarginCalculationMode::Liquidation {
+                liquidation_fee: ref mut fee,
+                ..
+            } => {
+                *fee = liquidation_fee;
+            }
+            _ => {
+                msg!("Cant set liquidation fee outside of liquidation mode");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        }
+        Ok(self)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -118,21 +361,39 @@ pub struct MarginCalculation {
     pub context: MarginContext,
     pub total_collateral: i128,
     pub margin_requirement: u128,
+    pub margin_requirement_maintenance: u128,
+    pub total_collateral_maintenance: i128,
     #[cfg(not(test))]
     margin_requirement_plus_buffer: u128,
     #[cfg(test)]
     pub margin_requirement_plus_buffer: u128,
     pub num_spot_liabilities: u8,
     pub num_perp_liabilities: u8,
+    num_unsettled_pnl_perp_liabilities: u8,
     pub all_oracles_valid: bool,
+    last_oracle_valid: bool,
     pub with_perp_isolated_liability: bool,
     pub with_spot_isolated_liability: bool,
     pub total_spot_asset_value: i128,
     pub total_spot_liability_value: u128,
     pub total_perp_liability_value: u128,
     pub total_perp_pnl: i128,
+    pub total_unrealized_funding: i128,
     pub open_orders_margin_requirement: u128,
     tracked_market_margin_requirement: u128,
+    largest_liability_market: Option<MarketIdentifier>,
+    largest_liability_value: u128,
+    #[cfg(feature = "drift-rs")]
+    breakdown: [MarketMarginContribution; MAX_MARGIN_BREAKDOWN_ENTRIES],
+    #[cfg(feature = "drift-rs")]
+    num_breakdown_entries: u8,
+    max_leverage_liability_value: u128,
+    quote_collateral: i128,
+    spot_collateral: i128,
+    perp_pnl_collateral: i128,
+    other_collateral: i128,
+    pub overflowed: bool,
+    pub num_invalid_oracles: u8,
 }
 
 impl MarginCalculation {
@@ -141,41 +402,166 @@ impl MarginCalculation {
             context,
             total_collateral: 0,
             margin_requirement: 0,
+            margin_requirement_maintenance: 0,
+            total_collateral_maintenance: 0,
             margin_requirement_plus_buffer: 0,
             num_spot_liabilities: 0,
             num_perp_liabilities: 0,
+            num_unsettled_pnl_perp_liabilities: 0,
             all_oracles_valid: true,
+            last_oracle_valid: true,
             with_perp_isolated_liability: false,
             with_spot_isolated_liability: false,
             total_spot_asset_value: 0,
             total_spot_liability_value: 0,
             total_perp_liability_value: 0,
             total_perp_pnl: 0,
+            total_unrealized_funding: 0,
             open_orders_margin_requirement: 0,
             tracked_market_margin_requirement: 0,
+            largest_liability_market: None,
+            largest_liability_value: 0,
+            #[cfg(feature = "drift-rs")]
+            breakdown: [MarketMarginContribution::default(); MAX_MARGIN_BREAKDOWN_ENTRIES],
+            #[cfg(feature = "drift-rs")]
+            num_breakdown_entries: 0,
+            max_leverage_liability_value: 0,
+            quote_collateral: 0,
+            spot_collateral: 0,
+            perp_pnl_collateral: 0,
+            other_collateral: 0,
+            overflowed: false,
+            num_invalid_oracles: 0,
+        }
+    }
+
+    /// Copy of the calculation with the deltas from a hypothetical fill applied, for what-if checks
+    /// with meets_margin_requirement/get_free_collateral. When margin_requirement_plus_buffer is
+    /// tracked margin_requirement_delta is also added to it, the buffer on any new liability isn't
+    /// included
+    pub fn with_hypothetical_position_change(
+        &self,
+        collateral_delta: i128,
+        margin_requirement_delta: u128,
+    ) -> DriftResult<MarginCalculation> {
+        let mut calculation = *self;
+        calculation.add_total_collateral(collateral_delta)?;
+        calculation.margin_requirement = calculation
+            .margin_requirement
+            .safe_add(margin_requirement_delta)?;
+        if calculation.tracks_margin_requirement_plus_buffer() {
+            calculation.margin_requirement_plus_buffer = calculation
+                .margin_requirement_plus_buffer
+                .safe_add(margin_requirement_delta)?;
+        }
+        Ok(calculation)
+    }
+
+    /// Clears every accumulator back to its `new` value so the calculation can be reused for another
+    /// account. The context is left unchanged
+    pub fn reset(&mut self) {
+        *self = Self::new(self.context);
+    }
+
+    /// Applies adjustments that depend on the accumulated totals, call once after every position
+    /// has been added
+    pub fn finalize(&mut self) -> DriftResult {
+        if let MarginCalculationMode::Liquidation {
+            collateral_buffer_bps,
+            liquidation_fee,
+            ..
+        } = self.context.mode
+        {
+            if collateral_buffer_bps > 0 && self.total_collateral > 0 {
+                let collateral_buffer = self
+                    .total_collateral
+                    .unsigned_abs()
+                    .safe_mul(collateral_buffer_bps)?
+                    .safe_div(MARGIN_PRECISION_U128)?;
+
+                self.margin_requirement_plus_buffer = self
+                    .margin_requirement_plus_buffer
+                    .safe_add(collateral_buffer)?;
+            }
+
+            self.margin_requirement_plus_buffer = self
+                .margin_requirement_plus_buffer
+                .safe_add(liquidation_fee)?;
         }
+
+        Ok(())
     }
 
+    /// Collateral that doesn't fit one of the typed adders, recorded as other in the composition
     pub fn add_total_collateral(&mut self, total_collateral: i128) -> DriftResult {
         self.total_collateral = self.total_collateral.safe_add(total_collateral)?;
+        self.other_collateral = self.other_collateral.safe_add(total_collateral)?;
         Ok(())
     }
 
+    /// For off-chain aggregation, saturates and sets overflowed instead of erroring
+    pub fn add_total_collateral_saturating(&mut self, total_collateral: i128) {
+        match self.total_collateral.checked_add(total_collateral) {
+            Some(sum) => self.total_collateral = sum,
+            None => {
+                self.total_collateral = self.total_collateral.saturating_add(total_collateral);
+                self.overflowed = true;
+            }
+        }
+
+        self.other_collateral = self.other_collateral.saturating_add(total_collateral);
+    }
+
+    /// Quote spot market deposits
+    pub fn add_quote_collateral(&mut self, quote_collateral: i128) -> DriftResult {
+        self.total_collateral = self.total_collateral.safe_add(quote_collateral)?;
+        self.quote_collateral = self.quote_collateral.safe_add(quote_collateral)?;
+        Ok(())
+    }
+
+    /// Weighted non-quote spot deposits and the quote side of open spot orders
+    pub fn add_spot_collateral(&mut self, spot_collateral: i128) -> DriftResult {
+        self.total_collateral = self.total_collateral.safe_add(spot_collateral)?;
+        self.spot_collateral = self.spot_collateral.safe_add(spot_collateral)?;
+        Ok(())
+    }
+
+    /// Weighted perp pnl, including unsettled funding
+    pub fn add_perp_pnl_collateral(&mut self, perp_pnl_collateral: i128) -> DriftResult {
+        self.total_collateral = self.total_collateral.safe_add(perp_pnl_collateral)?;
+        self.perp_pnl_collateral = self.perp_pnl_collateral.safe_add(perp_pnl_collateral)?;
+        Ok(())
+    }
+
+    pub fn get_collateral_composition(&self) -> CollateralComposition {
+        CollateralComposition {
+            quote: self.quote_collateral,
+            spot: self.spot_collateral,
+            perp_pnl: self.perp_pnl_collateral,
+            other: self.other_collateral,
+        }
+    }
+
     pub fn add_margin_requirement(
         &mut self,
         margin_requirement: u128,
         liability_value: u128,
         market_identifier: MarketIdentifier,
+        buffer_override: Option<u128>,
     ) -> DriftResult {
         self.margin_requirement = self.margin_requirement.safe_add(margin_requirement)?;
 
-        if self.context.margin_buffer > 0 {
-            self.margin_requirement_plus_buffer =
-                self.margin_requirement_plus_buffer
-                    .safe_add(margin_requirement.safe_add(
-                        liability_value.safe_mul(self.context.margin_buffer)?
-                            / MARGIN_PRECISION_U128,
-                    )?)?;
+        if self.tracks_margin_requirement_plus_buffer() {
+            self.margin_requirement_plus_buffer = self
+                .margin_requirement_plus_buffer
+                .safe_add(margin_requirement)?;
+
+            let margin_buffer = buffer_override.unwrap_or(self.context.margin_buffer);
+            if margin_buffer > 0 {
+                self.margin_requirement_plus_buffer = self
+                    .margin_requirement_plus_buffer
+                    .safe_add(liability_value.safe_mul(margin_buffer)? / MARGIN_PRECISION_U128)?;
+            }
         }
 
         if let Some(market_to_track) = self.market_to_track_margin_requirement() {
@@ -186,6 +572,135 @@ impl MarginCalculation {
             }
         }
 
+        if self.is_liquidation_mode() && liability_value > self.largest_liability_value {
+            self.largest_liability_market = Some(market_identifier);
+            self.largest_liability_value = liability_value;
+        }
+
+        if self.context.max_leverage.is_some() {
+            self.max_leverage_liability_value = self
+                .max_leverage_liability_value
+                .safe_add(liability_value)?;
+        }
+
+        #[cfg(feature = "drift-rs")]
+        if self.context.track_breakdown {
+            self.add_margin_requirement_for_market(
+                market_identifier.market_index,
+                margin_requirement,
+                liability_value,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds (margin_requirement, liability_value, market) entries in order, stopping at the first error
+    pub fn add_margin_requirements(
+        &mut self,
+        entries: &[(u128, u128, MarketIdentifier)],
+    ) -> DriftResult {
+        for (margin_requirement, liability_value, market_identifier) in entries.iter() {
+            self.add_margin_requirement(
+                *margin_requirement,
+                *liability_value,
+                *market_identifier,
+                None,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Only records the contribution, margin_requirement is updated by add_margin_requirement.
+    /// Entries past MAX_MARGIN_BREAKDOWN_ENTRIES are dropped
+    #[cfg(feature = "drift-rs")]
+    pub fn add_margin_requirement_for_market(
+        &mut self,
+        market_index: u16,
+        margin_requirement: u128,
+        liability_value: u128,
+    ) -> DriftResult {
+        let num_breakdown_entries = self.num_breakdown_entries as usize;
+        if num_breakdown_entries >= MAX_MARGIN_BREAKDOWN_ENTRIES {
+            return Ok(());
+        }
+
+        self.breakdown[num_breakdown_entries] = MarketMarginContribution {
+            market_index,
+            margin_requirement,
+            liability_value,
+        };
+        self.num_breakdown_entries = self.num_breakdown_entries.safe_add(1)?;
+
+        Ok(())
+    }
+
+    pub fn get_largest_liability(&self) -> Option<(MarketIdentifier, u128)> {
+        self.largest_liability_market
+            .map(|market| (market, self.largest_liability_value))
+    }
+
+    #[cfg(feature = "drift-rs")]
+    pub fn get_breakdown(&self) -> &[MarketMarginContribution] {
+        &self.breakdown[..self.num_breakdown_entries as usize]
+    }
+
+    /// Margin requirement recorded in the breakdown for market_index, None if it wasn't tracked.
+    /// Entries are keyed by market index only, so a spot and perp market with the same index are summed
+    #[cfg(feature = "drift-rs")]
+    pub fn margin_contribution_for_market(&self, market_index: u16) -> Option<u128> {
+        self.get_breakdown()
+            .iter()
+            .filter(|contribution| contribution.market_index == market_index)
+            .fold(None, |total, contribution| {
+                Some(
+                    total
+                        .unwrap_or(0)
+                        .saturating_add(contribution.margin_requirement),
+                )
+            })
+    }
+
+    /// Unsettled funding is already part of perp pnl in total_collateral, this only tracks the
+    /// portion of collateral that will move on the next funding settlement
+    pub fn add_unrealized_funding(&mut self, funding_pnl: i128) -> DriftResult {
+        if !self.context.track_unrealized_funding {
+            return Ok(());
+        }
+
+        self.total_unrealized_funding = self.total_unrealized_funding.safe_add(funding_pnl)?;
+        Ok(())
+    }
+
+    /// Worst case requirement if resting orders were to fill. Matches the initial margin check when
+    /// placing orders, so it's a no-op for maintenance calculations
+    pub fn add_open_order_margin(
+        &mut self,
+        order_margin_requirement: u128,
+        liability_value: u128,
+    ) -> DriftResult {
+        if !self.context.include_open_orders
+            || self.context.margin_type != MarginRequirementType::Initial
+        {
+            return Ok(());
+        }
+
+        self.margin_requirement = self.margin_requirement.safe_add(order_margin_requirement)?;
+
+        if self.tracks_margin_requirement_plus_buffer() {
+            self.margin_requirement_plus_buffer = self
+                .margin_requirement_plus_buffer
+                .safe_add(order_margin_requirement)?;
+
+            let margin_buffer = self.context.margin_buffer;
+            if margin_buffer > 0 {
+                self.margin_requirement_plus_buffer = self
+                    .margin_requirement_plus_buffer
+                    .safe_add(liability_value.safe_mul(margin_buffer)? / MARGIN_PRECISION_U128)?;
+            }
+        }
+
         Ok(())
     }
 
@@ -206,8 +721,50 @@ impl MarginCalculation {
         Ok(())
     }
 
+    /// Perp liability from unsettled losses on a position with no base, open orders or lp shares,
+    /// which has no margin requirement so validate_num_perp_liabilities doesn't count it
+    pub fn add_unsettled_pnl_perp_liability(&mut self) -> DriftResult {
+        self.add_perp_liability()?;
+        self.num_unsettled_pnl_perp_liabilities =
+            self.num_unsettled_pnl_perp_liabilities.safe_add(1)?;
+        Ok(())
+    }
+
+    /// Adds a spot deposit's value discounted by asset_weight (SPOT_WEIGHT_PRECISION) to total
+    /// collateral. The unweighted value is what's tracked for the margin ratio
+    pub fn add_weighted_spot_asset_value(
+        &mut self,
+        spot_asset_value: i128,
+        asset_weight: u32,
+    ) -> DriftResult {
+        let weighted_spot_asset_value = spot_asset_value
+            .safe_mul(asset_weight.cast()?)?
+            .safe_div(SPOT_WEIGHT_PRECISION_I128)?;
+
+        self.add_spot_collateral(weighted_spot_asset_value)?;
+
+        #[cfg(feature = "drift-rs")]
+        self.add_spot_asset_value(spot_asset_value)?;
+
+        Ok(())
+    }
+
+    /// Takes the unweighted asset value, asset weights only apply to total_collateral
     #[cfg(feature = "drift-rs")]
     pub fn add_spot_asset_value(&mut self, spot_asset_value: i128) -> DriftResult {
+        let spot_asset_value = if self.context.strict
+            && !self.last_oracle_valid
+            && self.context.strict_asset_discount > 0
+            && spot_asset_value > 0
+        {
+            let discount = spot_asset_value
+                .safe_mul(self.context.strict_asset_discount.cast()?)?
+                .safe_div(MARGIN_PRECISION_U128.cast()?)?;
+            spot_asset_value.safe_sub(discount)?
+        } else {
+            spot_asset_value
+        };
+
         self.total_spot_asset_value = self.total_spot_asset_value.safe_add(spot_asset_value)?;
         Ok(())
     }
@@ -220,6 +777,45 @@ impl MarginCalculation {
         Ok(())
     }
 
+    /// projected_interest is the interest expected to accrue on the borrow before settlement and is
+    /// only counted when the context has project_borrow_interest set
+    #[cfg(feature = "drift-rs")]
+    pub fn add_spot_liability_value_with_interest(
+        &mut self,
+        spot_liability_value: u128,
+        projected_interest: u128,
+    ) -> DriftResult {
+        let spot_liability_value = if self.context.project_borrow_interest {
+            spot_liability_value.safe_add(projected_interest)?
+        } else {
+            spot_liability_value
+        };
+
+        self.total_spot_liability_value = self
+            .total_spot_liability_value
+            .safe_add(spot_liability_value)?;
+        Ok(())
+    }
+
+    /// Values token_amount at price / precision (e.g. 10^decimals) in place, for callers holding one
+    /// price snapshot for several liabilities. Rounds up like a borrow valued with get_token_value
+    #[cfg(feature = "drift-rs")]
+    pub fn add_spot_liability_value_at_price(
+        &mut self,
+        token_amount: u128,
+        price: i128,
+        precision: u128,
+    ) -> DriftResult {
+        let spot_liability_value = token_amount
+            .safe_mul(price.cast()?)?
+            .safe_div_ceil(precision)?;
+
+        self.total_spot_liability_value = self
+            .total_spot_liability_value
+            .safe_add(spot_liability_value)?;
+        Ok(())
+    }
+
     #[cfg(feature = "drift-rs")]
     pub fn add_perp_liability_value(&mut self, perp_liability_value: u128) -> DriftResult {
         self.total_perp_liability_value = self
@@ -236,6 +832,26 @@ impl MarginCalculation {
 
     pub fn update_all_oracles_valid(&mut self, valid: bool) {
         self.all_oracles_valid &= valid;
+        self.last_oracle_valid = valid;
+    }
+
+    /// update_all_oracles_valid that also counts the market when invalid, and emits an
+    /// OracleInvalidRecord for it if the context has emit_oracle_invalid_records set
+    pub fn update_oracle_valid_for_market(&mut self, valid: bool, market: MarketIdentifier) {
+        self.update_all_oracles_valid(valid);
+
+        if !valid {
+            self.num_invalid_oracles = self.num_invalid_oracles.saturating_add(1);
+
+            if let Some((user, ts)) = self.context.emit_oracle_invalid_records {
+                emit!(OracleInvalidRecord {
+                    ts,
+                    user,
+                    market_type: market.market_type,
+                    market_index: market.market_index,
+                });
+            }
+        }
     }
 
     pub fn update_with_spot_isolated_liability(&mut self, isolated: bool) {
@@ -246,6 +862,10 @@ impl MarginCalculation {
         self.with_perp_isolated_liability |= isolated;
     }
 
+    pub fn has_isolated_liability(&self) -> bool {
+        self.with_perp_isolated_liability || self.with_spot_isolated_liability
+    }
+
     pub fn validate_num_spot_liabilities(&self) -> DriftResult {
         if self.num_spot_liabilities > 0 {
             validate!(
@@ -258,13 +878,107 @@ impl MarginCalculation {
         Ok(())
     }
 
+    pub fn validate_num_perp_liabilities(&self) -> DriftResult {
+        if self.num_perp_liabilities > self.num_unsettled_pnl_perp_liabilities {
+            validate!(
+                self.margin_requirement > 0,
+                ErrorCode::InvalidMarginRatio,
+                "num_perp_liabilities={} but margin_requirement=0",
+                self.num_perp_liabilities
+            )?;
+        }
+        Ok(())
+    }
+
     pub fn get_num_of_liabilities(&self) -> DriftResult<u8> {
         self.num_spot_liabilities
             .safe_add(self.num_perp_liabilities)
     }
 
+    pub fn get_record(&self, user_key: &Pubkey, now: i64) -> MarginRequirementRecord {
+        MarginRequirementRecord {
+            ts: now,
+            user: *user_key,
+            margin_requirement_type: self.context.margin_type,
+            total_collateral: self.total_collateral,
+            margin_requirement: self.margin_requirement,
+            margin_requirement_plus_buffer: self.margin_requirement_plus_buffer,
+            num_spot_liabilities: self.num_spot_liabilities,
+            num_perp_liabilities: self.num_perp_liabilities,
+            all_oracles_valid: self.all_oracles_valid,
+        }
+    }
+
+    pub fn emit_record(&self, user_key: &Pubkey, now: i64) {
+        emit!(self.get_record(user_key, now));
+    }
+
     pub fn meets_margin_requirement(&self) -> bool {
-        self.total_collateral >= self.margin_requirement as i128
+        let total_collateral = self
+            .total_collateral
+            .saturating_sub(self.withdraw_amount().min(i128::MAX as u128) as i128);
+
+        if !self.meets_max_leverage(total_collateral) {
+            return false;
+        }
+
+        total_collateral >= self.get_effective_margin_requirement() as i128
+    }
+
+    /// Check for scanning quote only accounts, skips the withdrawal and max leverage handling of
+    /// meets_margin_requirement
+    pub fn meets_margin_requirement_quote_only(&self) -> DriftResult<bool> {
+        validate!(
+            self.context.quote_only,
+            ErrorCode::InvalidMarginCalculation,
+            "quote_only not set on margin context"
+        )?;
+
+        validate!(
+            self.num_spot_liabilities == 0 && !self.has_isolated_liability(),
+            ErrorCode::InvalidMarginCalculation,
+            "quote only margin check used on account with spot or isolated liabilities"
+        )?;
+
+        Ok(self.total_collateral >= self.get_effective_margin_requirement().cast::<i128>()?)
+    }
+
+    pub fn get_health(&self) -> DriftResult<u8> {
+        if self.margin_requirement == 0 {
+            return Ok(100);
+        }
+
+        // 100 when total_collateral >= 2x margin_requirement, 0 when at or below margin_requirement
+        let free_collateral = self.get_free_collateral()?;
+
+        free_collateral
+            .saturating_mul(100)
+            .safe_div(self.margin_requirement)?
+            .min(100)
+            .cast()
+    }
+
+    /// Additional total_collateral needed for get_health to return at least target_health (capped
+    /// at 100), 0 if already there
+    pub fn collateral_to_reach_health(&self, target_health: u8) -> DriftResult<u128> {
+        if self.margin_requirement == 0 || target_health == 0 {
+            return Ok(0);
+        }
+
+        let target_free_collateral = self
+            .margin_requirement
+            .safe_mul(target_health.min(100).cast()?)?
+            .safe_div_ceil(100)?;
+
+        let target_total_collateral = self
+            .margin_requirement
+            .safe_add(target_free_collateral)?
+            .cast::<i128>()?;
+
+        target_total_collateral
+            .safe_sub(self.total_collateral)?
+            .max(0)
+            .cast()
     }
 
     pub fn positions_meets_margin_requirement(&self) -> DriftResult<bool> {
@@ -276,25 +990,100 @@ impl MarginCalculation {
     }
 
     pub fn can_exit_liquidation(&self) -> DriftResult<bool> {
-        if !self.is_liquidation_mode() {
-            msg!("liquidation mode not enabled");
+        let exit_buffer = match self.context.mode {
+            MarginCalculationMode::Liquidation { exit_buffer, .. } => exit_buffer,
+            _ => {
+                msg!("liquidation mode not enabled");
+                return Err(ErrorCode::InvalidMarginCalculation);
+            }
+        };
+
+        Ok(self.total_collateral
+            >= self
+                .margin_requirement_plus_buffer
+                .safe_add(exit_buffer)?
+                .cast::<i128>()?)
+    }
+
+    /// Uses the liability values passed to add_margin_requirement since the total liability values
+    /// are only tracked off-chain
+    fn meets_max_leverage(&self, total_collateral: i128) -> bool {
+        let max_leverage = match self.context.max_leverage {
+            Some(max_leverage) if self.context.margin_type == MarginRequirementType::Initial => {
+                max_leverage
+            }
+            _ => return true,
+        };
+
+        if total_collateral <= 0 {
+            return self.max_leverage_liability_value == 0;
+        }
+
+        total_collateral
+            .unsigned_abs()
+            .saturating_mul(max_leverage as u128)
+            >= self.max_leverage_liability_value
+    }
+
+    /// margin_requirement_plus_buffer for buffered initial checks, margin_requirement otherwise
+    fn get_effective_margin_requirement(&self) -> u128 {
+        if self.context.buffered_requirement {
+            self.margin_requirement_plus_buffer
+        } else {
+            self.margin_requirement
+        }
+    }
+
+    pub fn get_margin_requirement_plus_buffer(&self) -> DriftResult<u128> {
+        if !self.is_liquidation_mode() && !self.context.buffered_requirement {
+            msg!("margin_requirement_plus_buffer only tracked in liquidation mode or buffered initial");
             return Err(ErrorCode::InvalidMarginCalculation);
         }
 
-        Ok(self.total_collateral >= self.margin_requirement_plus_buffer as i128)
+        Ok(self.margin_requirement_plus_buffer)
     }
 
+    /// Collateral needed to reach margin_requirement_plus_buffer, 0 when already above it.
+    /// total_collateral and margin requirements are both quote values, so the shortage is
+    /// already denominated in quote. precision: QUOTE_PRECISION
     pub fn margin_shortage(&self) -> DriftResult<u128> {
         if self.context.margin_buffer == 0 {
             msg!("margin buffer mode not enabled");
             return Err(ErrorCode::InvalidMarginCalculation);
         }
 
-        Ok(self
-            .margin_requirement_plus_buffer
-            .cast::<i128>()?
-            .safe_sub(self.total_collateral)?
-            .unsigned_abs())
+        // computed in u128 to avoid casting margin_requirement_plus_buffer to i128
+        if self.total_collateral >= 0 {
+            Ok(self
+                .margin_requirement_plus_buffer
+                .saturating_sub(self.total_collateral.unsigned_abs()))
+        } else {
+            self.margin_requirement_plus_buffer
+                .safe_add(self.total_collateral.unsigned_abs())
+        }
+    }
+
+    /// Quote a liquidator needs to inject to clear margin_shortage. Collateral and requirements are
+    /// tracked as quote values rather than in MARGIN_PRECISION, so no rescaling is needed.
+    /// precision: QUOTE_PRECISION
+    pub fn margin_shortage_in_quote(&self) -> DriftResult<u128> {
+        self.margin_shortage()
+    }
+
+    /// Ranks liquidatable accounts, deeper shortages and more liabilities score higher.
+    /// 0 if the account can exit liquidation
+    pub fn liquidation_priority(&self) -> DriftResult<u128> {
+        if self.can_exit_liquidation()? {
+            return Ok(0);
+        }
+
+        let liability_weight = LIQUIDATION_PRIORITY_LIABILITY_WEIGHT
+            .safe_mul(self.get_num_of_liabilities()?.cast()?)?
+            .safe_add(PERCENTAGE_PRECISION)?;
+
+        self.margin_shortage()?
+            .safe_mul(liability_weight)?
+            .safe_div(PERCENTAGE_PRECISION)
     }
 
     pub fn tracked_market_margin_shortage(&self, margin_shortage: u128) -> DriftResult<u128> {
@@ -313,12 +1102,199 @@ impl MarginCalculation {
     }
 
     pub fn get_free_collateral(&self) -> DriftResult<u128> {
-        self.total_collateral
-            .safe_sub(self.margin_requirement.cast::<i128>()?)?
+        Ok(Self::free_collateral(
+            self.total_collateral,
+            self.margin_requirement,
+        ))
+    }
+
+    /// Most that can be withdrawn while still meeting the initial margin requirement
+    pub fn max_withdrawable(&self) -> DriftResult<u128> {
+        validate!(
+            self.context.margin_type == MarginRequirementType::Initial,
+            ErrorCode::InvalidMarginCalculation,
+            "max_withdrawable requires an initial margin calculation, got {:?}",
+            self.context.margin_type
+        )?;
+
+        let free_collateral = Self::free_collateral(
+            self.total_collateral,
+            self.get_effective_margin_requirement(),
+        );
+
+        Ok(free_collateral.min(self.total_collateral.max(0).unsigned_abs()))
+    }
+
+    // compared as u128 so requirements beyond i128::MAX give 0 instead of a cast error
+    fn free_collateral(total_collateral: i128, margin_requirement: u128) -> u128 {
+        if total_collateral <= 0 {
+            return 0;
+        }
+
+        total_collateral
+            .unsigned_abs()
+            .saturating_sub(margin_requirement)
+    }
+
+    /// Maintenance requirement accumulated in the same pass as the initial one, no-op unless
+    /// track_both_requirements is set
+    pub fn add_maintenance_margin_requirement(&mut self, margin_requirement: u128) -> DriftResult {
+        if !self.context.track_both_requirements {
+            return Ok(());
+        }
+
+        self.margin_requirement_maintenance = self
+            .margin_requirement_maintenance
+            .safe_add(margin_requirement)?;
+        Ok(())
+    }
+
+    /// Maintenance weighted collateral accumulated in the same pass as the initial one, no-op unless
+    /// track_both_requirements is set
+    pub fn add_maintenance_total_collateral(&mut self, total_collateral: i128) -> DriftResult {
+        if !self.context.track_both_requirements {
+            return Ok(());
+        }
+
+        self.total_collateral_maintenance = self
+            .total_collateral_maintenance
+            .safe_add(total_collateral)?;
+        Ok(())
+    }
+
+    pub fn get_free_collateral_for_type(
+        &self,
+        margin_type: MarginRequirementType,
+    ) -> DriftResult<u128> {
+        if margin_type == self.context.margin_type {
+            return self.get_free_collateral();
+        }
+
+        if margin_type == MarginRequirementType::Maintenance && self.context.track_both_requirements
+        {
+            return Ok(Self::free_collateral(
+                self.total_collateral_maintenance,
+                self.margin_requirement_maintenance,
+            ));
+        }
+
+        msg!(
+            "free collateral for {:?} not tracked by {:?} margin calculation",
+            margin_type,
+            self.context.margin_type
+        );
+        Err(ErrorCode::InvalidMarginCalculation)
+    }
+
+    pub fn get_net_asset_value(&self) -> DriftResult<i128> {
+        self.total_spot_asset_value
+            .safe_add(self.total_perp_pnl)?
+            .safe_sub(self.total_spot_liability_value.cast()?)
+    }
+
+    pub fn get_total_liability_value(&self) -> DriftResult<u128> {
+        self.total_spot_liability_value
+            .safe_add(self.total_perp_liability_value)
+    }
+
+    /// net asset value over total liability value (PRICE_PRECISION). negative when net asset value
+    /// is negative, 0 when there are no liabilities
+    #[cfg(feature = "drift-rs")]
+    pub fn get_signed_margin_ratio(&self) -> DriftResult<i128> {
+        self.get_signed_margin_ratio_for_liability_value(self.get_total_liability_value()?)
+    }
+
+    /// 0 for both flat and underwater accounts, use get_margin_ratio_status to tell them apart
+    #[cfg(feature = "drift-rs")]
+    pub fn get_margin_ratio(&self) -> DriftResult<u128> {
+        match self.get_margin_ratio_status()? {
+            MarginRatioStatus::Flat | MarginRatioStatus::Underwater => Ok(0),
+            MarginRatioStatus::Ratio(margin_ratio) => Ok(margin_ratio),
+        }
+    }
+
+    #[cfg(feature = "drift-rs")]
+    pub fn get_margin_ratio_status(&self) -> DriftResult<MarginRatioStatus> {
+        if self.get_total_liability_value()? == 0 {
+            return Ok(MarginRatioStatus::Flat);
+        }
+
+        if self.get_net_asset_value()? <= 0 {
+            return Ok(MarginRatioStatus::Underwater);
+        }
+
+        Ok(MarginRatioStatus::Ratio(
+            self.get_signed_margin_ratio()?.cast()?,
+        ))
+    }
+
+    /// Total liability value over net asset value (LEVERAGE_PRECISION, 200 is 2x). 0 when flat,
+    /// UNDERWATER_LEVERAGE when net asset value is zero or negative since leverage is unbounded
+    #[cfg(feature = "drift-rs")]
+    pub fn get_leverage(&self) -> DriftResult<u128> {
+        let total_liability_value = self.get_total_liability_value()?;
+
+        match self.get_margin_ratio_status()? {
+            MarginRatioStatus::Flat => Ok(0),
+            MarginRatioStatus::Underwater => Ok(UNDERWATER_LEVERAGE),
+            MarginRatioStatus::Ratio(_) => total_liability_value
+                .safe_mul(LEVERAGE_PRECISION)?
+                .safe_div(self.get_net_asset_value()?.cast()?),
+        }
+    }
+
+    /// net asset value over only perp liabilities, 0 when there are no perp liabilities
+    #[cfg(feature = "drift-rs")]
+    pub fn get_perp_margin_ratio(&self) -> DriftResult<u128> {
+        self.get_margin_ratio_for_liability_value(self.total_perp_liability_value)
+    }
+
+    /// net asset value over only spot liabilities, 0 when there are no spot liabilities
+    #[cfg(feature = "drift-rs")]
+    pub fn get_spot_margin_ratio(&self) -> DriftResult<u128> {
+        self.get_margin_ratio_for_liability_value(self.total_spot_liability_value)
+    }
+
+    #[cfg(feature = "drift-rs")]
+    fn get_margin_ratio_for_liability_value(&self, liability_value: u128) -> DriftResult<u128> {
+        self.get_signed_margin_ratio_for_liability_value(liability_value)?
             .max(0)
             .cast()
     }
 
+    #[cfg(feature = "drift-rs")]
+    fn get_signed_margin_ratio_for_liability_value(
+        &self,
+        liability_value: u128,
+    ) -> DriftResult<i128> {
+        if liability_value == 0 {
+            return Ok(0);
+        }
+
+        let net_asset_value = self.get_net_asset_value()?;
+
+        // multiply in U192 so large net asset values dont overflow before the division,
+        // dividing the magnitude keeps the same round towards zero as i128 division
+        let margin_ratio = U192::from(net_asset_value.unsigned_abs())
+            .safe_mul(U192::from(PRICE_PRECISION))?
+            .safe_div(U192::from(liability_value))?
+            .try_to_u128()?
+            .cast::<i128>()?;
+
+        margin_ratio.safe_mul(net_asset_value.signum())
+    }
+
+    #[cfg(feature = "drift-rs")]
+    pub fn get_margin_ratio_components(&self) -> DriftResult<MarginRatioComponents> {
+        Ok(MarginRatioComponents {
+            total_spot_asset_value: self.total_spot_asset_value,
+            total_spot_liability_value: self.total_spot_liability_value,
+            total_perp_liability_value: self.total_perp_liability_value,
+            net_asset_value: self.get_net_asset_value()?,
+            margin_ratio: self.get_signed_margin_ratio()?.max(0).cast()?,
+        })
+    }
+
     fn market_to_track_margin_requirement(&self) -> Option<MarketIdentifier> {
         if let MarginCalculationMode::Liquidation {
             market_to_track_margin_requirement: track_margin_requirement,
@@ -331,10 +1307,34 @@ impl MarginCalculation {
         }
     }
 
+    pub fn get_liquidation_fee(&self) -> u128 {
+        match self.context.mode {
+            MarginCalculationMode::Liquidation {
+                liquidation_fee, ..
+            } => liquidation_fee,
+            _ => 0,
+        }
+    }
+
+    fn withdraw_amount(&self) -> u128 {
+        match self.context.mode {
+            MarginCalculationMode::Withdrawal { withdraw_amount } => withdraw_amount,
+            _ => 0,
+        }
+    }
+
     fn is_liquidation_mode(&self) -> bool {
         matches!(self.context.mode, MarginCalculationMode::Liquidation { .. })
     }
 
+    /// Once tracked, every requirement is added to margin_requirement_plus_buffer even if its own
+    /// buffer is 0, so a zero buffer override doesn't drop the base requirement
+    fn tracks_margin_requirement_plus_buffer(&self) -> bool {
+        self.context.margin_buffer > 0
+            || self.context.buffered_requirement
+            || self.is_liquidation_mode()
+    }
+
     pub fn track_open_orders_fraction(&self) -> bool {
         matches!(
             self.context.mode,
@@ -344,3 +1344,19 @@ impl MarginCalculation {
         )
     }
 }
+
+/// Stable sorts liquidation mode calculations from highest to lowest liquidation_priority
+pub fn sort_by_liquidation_priority(calculations: &mut [MarginCalculation]) -> DriftResult {
+    let mut ranked = calculations
+        .iter()
+        .map(|calculation| Ok((calculation.liquidation_priority()?, *calculation)))
+        .collect::<DriftResult<Vec<(u128, MarginCalculation)>>>()?;
+
+    ranked.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    for (calculation, (_, ranked_calculation)) in calculations.iter_mut().zip(ranked) {
+        *calculation = ranked_calculation;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,2380 @@
+mod get_health {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(total_collateral: i128, margin_requirement: u128) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.add_total_collateral(total_collateral).unwrap();
+        calculation
+            .add_margin_requirement(margin_requirement, 0, MarketIdentifier::perp(0), None)
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn zero_margin_requirement() {
+        assert_eq!(calculation(0, 0).get_health().unwrap(), 100);
+        assert_eq!(
+            calculation(100 * QUOTE_PRECISION_I128, 0)
+                .get_health()
+                .unwrap(),
+            100
+        );
+        assert_eq!(
+            calculation(-100 * QUOTE_PRECISION_I128, 0)
+                .get_health()
+                .unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn exactly_at_margin_requirement() {
+        assert_eq!(
+            calculation(100 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn between_requirement_and_twice_requirement() {
+        assert_eq!(
+            calculation(150 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            50
+        );
+        assert_eq!(
+            calculation(200 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            100
+        );
+        assert_eq!(
+            calculation(1000 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn underwater() {
+        assert_eq!(
+            calculation(50 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            calculation(-50 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION)
+                .get_health()
+                .unwrap(),
+            0
+        );
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod breakdown {
+    use crate::math::constants::QUOTE_PRECISION;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{
+        MarginCalculation, MarginContext, MarketIdentifier, MarketMarginContribution,
+        MAX_MARGIN_BREAKDOWN_ENTRIES,
+    };
+
+    #[test]
+    fn not_tracked_by_default() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_requirement, 10 * QUOTE_PRECISION);
+        assert!(calculation.get_breakdown().is_empty());
+    }
+
+    #[test]
+    fn tracked() {
+        let context = MarginContext::standard(MarginRequirementType::Initial)
+            .track_breakdown()
+            .unwrap();
+        let mut calculation = MarginCalculation::new(context);
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                5 * QUOTE_PRECISION,
+                25 * QUOTE_PRECISION,
+                MarketIdentifier::spot(1),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_requirement, 15 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.get_breakdown(),
+            &[
+                MarketMarginContribution {
+                    market_index: 0,
+                    margin_requirement: 10 * QUOTE_PRECISION,
+                    liability_value: 100 * QUOTE_PRECISION,
+                },
+                MarketMarginContribution {
+                    market_index: 1,
+                    margin_requirement: 5 * QUOTE_PRECISION,
+                    liability_value: 25 * QUOTE_PRECISION,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn capped() {
+        let context = MarginContext::standard(MarginRequirementType::Initial)
+            .track_breakdown()
+            .unwrap();
+        let mut calculation = MarginCalculation::new(context);
+
+        for market_index in 0..(MAX_MARGIN_BREAKDOWN_ENTRIES as u16 + 4) {
+            calculation
+                .add_margin_requirement(
+                    QUOTE_PRECISION,
+                    0,
+                    MarketIdentifier::perp(market_index),
+                    None,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            calculation.margin_requirement,
+            (MAX_MARGIN_BREAKDOWN_ENTRIES as u128 + 4) * QUOTE_PRECISION
+        );
+        assert_eq!(
+            calculation.get_breakdown().len(),
+            MAX_MARGIN_BREAKDOWN_ENTRIES
+        );
+    }
+
+    #[test]
+    fn cant_track_in_liquidation_mode() {
+        assert!(MarginContext::liquidation(0).track_breakdown().is_err());
+    }
+
+    #[test]
+    fn margin_contribution_for_market() {
+        let context = MarginContext::standard(MarginRequirementType::Initial)
+            .track_breakdown()
+            .unwrap();
+        let mut calculation = MarginCalculation::new(context);
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                5 * QUOTE_PRECISION,
+                25 * QUOTE_PRECISION,
+                MarketIdentifier::spot(1),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.margin_contribution_for_market(0),
+            Some(10 * QUOTE_PRECISION)
+        );
+        assert_eq!(
+            calculation.margin_contribution_for_market(1),
+            Some(5 * QUOTE_PRECISION)
+        );
+        assert_eq!(calculation.margin_contribution_for_market(2), None);
+    }
+
+    #[test]
+    fn margin_contribution_for_market_not_tracked() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_contribution_for_market(0), None);
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod strict_asset_discount {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    fn total_spot_asset_value(context: MarginContext, oracle_valid: bool) -> i128 {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.update_all_oracles_valid(oracle_valid);
+        calculation
+            .add_spot_asset_value(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation.total_spot_asset_value
+    }
+
+    #[test]
+    fn stale_oracle() {
+        let discount = MARGIN_PRECISION / 10; // 10%
+
+        let non_strict =
+            MarginContext::standard(MarginRequirementType::Initial).strict_asset_discount(discount);
+        let strict = MarginContext::standard(MarginRequirementType::Initial)
+            .strict(true)
+            .strict_asset_discount(discount);
+
+        assert_eq!(
+            total_spot_asset_value(non_strict, false),
+            100 * QUOTE_PRECISION_I128
+        );
+        assert_eq!(
+            total_spot_asset_value(strict, false),
+            90 * QUOTE_PRECISION_I128
+        );
+        assert!(total_spot_asset_value(strict, false) < total_spot_asset_value(non_strict, false));
+    }
+
+    #[test]
+    fn valid_oracle() {
+        let strict = MarginContext::standard(MarginRequirementType::Initial)
+            .strict(true)
+            .strict_asset_discount(MARGIN_PRECISION / 10);
+
+        assert_eq!(
+            total_spot_asset_value(strict, true),
+            100 * QUOTE_PRECISION_I128
+        );
+    }
+
+    #[test]
+    fn no_discount_by_default() {
+        let strict = MarginContext::standard(MarginRequirementType::Initial).strict(true);
+
+        assert_eq!(
+            total_spot_asset_value(strict, false),
+            100 * QUOTE_PRECISION_I128
+        );
+    }
+}
+
+mod get_margin_requirement_plus_buffer {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn standard_mode() {
+        let mut calculation = MarginCalculation::new(
+            MarginContext::standard(MarginRequirementType::Maintenance)
+                .margin_buffer(MARGIN_PRECISION / 100),
+        );
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert!(calculation.get_margin_requirement_plus_buffer().is_err());
+    }
+
+    #[test]
+    fn liquidation_mode() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 100));
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.get_margin_requirement_plus_buffer().unwrap(),
+            11 * QUOTE_PRECISION
+        );
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_signed_margin_ratio {
+    use crate::math::constants::{PRICE_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    fn calculation() -> MarginCalculation {
+        MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance))
+    }
+
+    #[test]
+    fn no_exposure() {
+        let calculation = calculation();
+
+        assert_eq!(calculation.get_signed_margin_ratio().unwrap(), 0);
+        assert_eq!(calculation.get_margin_ratio().unwrap(), 0);
+    }
+
+    #[test]
+    fn healthy() {
+        let mut calculation = calculation();
+        calculation.total_spot_asset_value = 100 * QUOTE_PRECISION_I128;
+        calculation.total_perp_pnl = 100 * QUOTE_PRECISION_I128;
+        calculation.total_perp_liability_value = 1000 * QUOTE_PRECISION;
+
+        assert_eq!(
+            calculation.get_signed_margin_ratio().unwrap(),
+            (PRICE_PRECISION / 5) as i128
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), PRICE_PRECISION / 5);
+    }
+
+    #[test]
+    fn underwater() {
+        let mut calculation = calculation();
+        calculation.total_spot_asset_value = 100 * QUOTE_PRECISION_I128;
+        calculation.total_perp_pnl = -200 * QUOTE_PRECISION_I128;
+        calculation.total_spot_liability_value = 100 * QUOTE_PRECISION;
+        calculation.total_perp_liability_value = 900 * QUOTE_PRECISION;
+
+        assert_eq!(
+            calculation.get_signed_margin_ratio().unwrap(),
+            -((PRICE_PRECISION / 5) as i128)
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), 0);
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_margin_ratio_components {
+    use crate::math::constants::{PRICE_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{
+        MarginCalculation, MarginContext, MarginRatioComponents,
+    };
+
+    #[test]
+    fn components() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_spot_asset_value = 300 * QUOTE_PRECISION_I128;
+        calculation.total_perp_pnl = -50 * QUOTE_PRECISION_I128;
+        calculation.total_spot_liability_value = 50 * QUOTE_PRECISION;
+        calculation.total_perp_liability_value = 950 * QUOTE_PRECISION;
+
+        // nav = 300 - 50 - 50 = 200, liabilities = 50 + 950 = 1000
+        let components = calculation.get_margin_ratio_components().unwrap();
+        assert_eq!(
+            components,
+            MarginRatioComponents {
+                total_spot_asset_value: 300 * QUOTE_PRECISION_I128,
+                total_spot_liability_value: 50 * QUOTE_PRECISION,
+                total_perp_liability_value: 950 * QUOTE_PRECISION,
+                net_asset_value: 200 * QUOTE_PRECISION_I128,
+                margin_ratio: PRICE_PRECISION / 5,
+            }
+        );
+        assert_eq!(
+            calculation.get_margin_ratio().unwrap(),
+            components.margin_ratio
+        );
+    }
+}
+
+mod get_largest_liability {
+    use crate::math::constants::QUOTE_PRECISION;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn second_liability_larger() {
+        let mut calculation = MarginCalculation::new(MarginContext::liquidation(0));
+
+        assert_eq!(calculation.get_largest_liability(), None);
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.get_largest_liability(),
+            Some((MarketIdentifier::perp(0), 100 * QUOTE_PRECISION))
+        );
+
+        calculation
+            .add_margin_requirement(
+                20 * QUOTE_PRECISION,
+                200 * QUOTE_PRECISION,
+                MarketIdentifier::perp(3),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.get_largest_liability(),
+            Some((MarketIdentifier::perp(3), 200 * QUOTE_PRECISION))
+        );
+
+        calculation
+            .add_margin_requirement(
+                5 * QUOTE_PRECISION,
+                50 * QUOTE_PRECISION,
+                MarketIdentifier::perp(1),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.get_largest_liability(),
+            Some((MarketIdentifier::perp(3), 200 * QUOTE_PRECISION))
+        );
+    }
+
+    #[test]
+    fn spot_and_perp_with_same_index() {
+        let mut calculation = MarginCalculation::new(MarginContext::liquidation(0));
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(1),
+                None,
+            )
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                20 * QUOTE_PRECISION,
+                200 * QUOTE_PRECISION,
+                MarketIdentifier::spot(1),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.get_largest_liability(),
+            Some((MarketIdentifier::spot(1), 200 * QUOTE_PRECISION))
+        );
+    }
+
+    #[test]
+    fn not_tracked_in_standard_mode() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(calculation.get_largest_liability(), None);
+    }
+}
+
+mod buffer_override {
+    use crate::math::constants::{MARGIN_PRECISION_U128, QUOTE_PRECISION};
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn mixed_default_and_override_buffer() {
+        // 1% default buffer
+        let mut calculation = MarginCalculation::new(MarginContext::liquidation(100));
+
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.margin_requirement_plus_buffer,
+            11 * QUOTE_PRECISION
+        );
+
+        // 5% buffer for this liability
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(1),
+                Some(MARGIN_PRECISION_U128 / 20),
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_requirement, 20 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.margin_requirement_plus_buffer,
+            26 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn zero_override_still_includes_requirement() {
+        // no default buffer
+        let mut calculation = MarginCalculation::new(MarginContext::liquidation(0));
+
+        // 0 buffer for this liability
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                Some(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            calculation.margin_requirement_plus_buffer,
+            10 * QUOTE_PRECISION
+        );
+
+        // 5% buffer for this liability
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(1),
+                Some(MARGIN_PRECISION_U128 / 20),
+            )
+            .unwrap();
+
+        assert_eq!(calculation.margin_requirement, 20 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.margin_requirement_plus_buffer,
+            25 * QUOTE_PRECISION
+        );
+    }
+}
+
+mod unrealized_funding {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        // perp pnl fed into total collateral already includes -10 of unsettled funding
+        calculation
+            .add_total_collateral(150 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+            .add_unrealized_funding(-10 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn not_tracked_by_default() {
+        let calculation = calculation(MarginContext::standard(MarginRequirementType::Maintenance));
+
+        assert_eq!(calculation.total_unrealized_funding, 0);
+        assert_eq!(calculation.total_collateral, 150 * QUOTE_PRECISION_I128);
+        assert_eq!(calculation.get_health().unwrap(), 50);
+    }
+
+    #[test]
+    fn tracked_without_double_counting_collateral() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Maintenance).track_unrealized_funding(),
+        );
+
+        assert_eq!(
+            calculation.total_unrealized_funding,
+            -10 * QUOTE_PRECISION_I128
+        );
+        assert_eq!(calculation.total_collateral, 150 * QUOTE_PRECISION_I128);
+        assert_eq!(calculation.get_health().unwrap(), 50);
+    }
+}
+
+mod liquidation_priority {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(total_collateral: i128, num_perp_liabilities: u8) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 50));
+        calculation.add_total_collateral(total_collateral).unwrap();
+        // 100 requirement + 2% buffer on 1000 liability = 120
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        for _ in 0..num_perp_liabilities {
+            calculation.add_perp_liability().unwrap();
+        }
+        calculation
+    }
+
+    #[test]
+    fn deeper_shortage_scores_higher() {
+        let shallow = calculation(110 * QUOTE_PRECISION_I128, 1);
+        let deep = calculation(60 * QUOTE_PRECISION_I128, 1);
+
+        // 10 shortage * 1.1
+        assert_eq!(
+            shallow.liquidation_priority().unwrap(),
+            11 * QUOTE_PRECISION
+        );
+        // 60 shortage * 1.1
+        assert_eq!(deep.liquidation_priority().unwrap(), 66 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn more_liabilities_scores_higher() {
+        let simple = calculation(110 * QUOTE_PRECISION_I128, 1);
+        let complex = calculation(110 * QUOTE_PRECISION_I128, 3);
+
+        assert!(complex.liquidation_priority().unwrap() > simple.liquidation_priority().unwrap());
+    }
+
+    #[test]
+    fn can_exit_liquidation() {
+        let calculation = calculation(120 * QUOTE_PRECISION_I128, 1);
+
+        assert_eq!(calculation.liquidation_priority().unwrap(), 0);
+    }
+
+    #[test]
+    fn standard_mode() {
+        let calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+
+        assert_eq!(
+            calculation.liquidation_priority(),
+            Err(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+}
+
+mod can_exit_liquidation {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext, total_collateral: i128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.add_total_collateral(total_collateral).unwrap();
+        // 100 requirement + 2% buffer on 1000 liability = 120
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn no_exit_buffer() {
+        let context = MarginContext::liquidation(MARGIN_PRECISION / 50);
+
+        assert!(!calculation(context, 119 * QUOTE_PRECISION_I128)
+            .can_exit_liquidation()
+            .unwrap());
+        assert!(calculation(context, 120 * QUOTE_PRECISION_I128)
+            .can_exit_liquidation()
+            .unwrap());
+    }
+
+    #[test]
+    fn exit_buffer() {
+        let context = MarginContext::liquidation(MARGIN_PRECISION / 50)
+            .liquidation_exit_buffer(5 * QUOTE_PRECISION)
+            .unwrap();
+
+        // above margin_requirement_plus_buffer but below the exit buffer
+        assert!(!calculation(context, 121 * QUOTE_PRECISION_I128)
+            .can_exit_liquidation()
+            .unwrap());
+        assert!(!calculation(context, 124 * QUOTE_PRECISION_I128)
+            .can_exit_liquidation()
+            .unwrap());
+        assert!(calculation(context, 125 * QUOTE_PRECISION_I128)
+            .can_exit_liquidation()
+            .unwrap());
+    }
+
+    #[test]
+    fn exit_buffer_outside_liquidation_mode() {
+        assert_eq!(
+            MarginContext::standard(MarginRequirementType::Maintenance)
+                .liquidation_exit_buffer(5 * QUOTE_PRECISION)
+                .unwrap_err(),
+            ErrorCode::InvalidMarginCalculation
+        );
+    }
+}
+
+mod margin_shortage {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn margin_calculation(
+        total_collateral: i128,
+        margin_requirement_plus_buffer: u128,
+    ) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 50));
+        calculation.total_collateral = total_collateral;
+        calculation.margin_requirement_plus_buffer = margin_requirement_plus_buffer;
+        calculation
+    }
+
+    #[test]
+    fn below_requirement() {
+        let calculation = margin_calculation(100 * QUOTE_PRECISION_I128, 120 * QUOTE_PRECISION);
+        assert_eq!(calculation.margin_shortage().unwrap(), 20 * QUOTE_PRECISION);
+
+        let calculation = margin_calculation(-100 * QUOTE_PRECISION_I128, 120 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.margin_shortage().unwrap(),
+            220 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn above_requirement() {
+        let calculation = margin_calculation(150 * QUOTE_PRECISION_I128, 120 * QUOTE_PRECISION);
+        assert_eq!(calculation.margin_shortage().unwrap(), 0);
+
+        let calculation = margin_calculation(120 * QUOTE_PRECISION_I128, 120 * QUOTE_PRECISION);
+        assert_eq!(calculation.margin_shortage().unwrap(), 0);
+    }
+
+    #[test]
+    fn denominated_in_quote() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 50));
+        calculation
+            .add_total_collateral(5 * QUOTE_PRECISION_I128)
+            .unwrap();
+        // $10 requirement on $100 of liabilities plus a 2% buffer = $12
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        // $12 - $5 = $7 of quote needed
+        assert_eq!(calculation.margin_shortage().unwrap(), 7 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn in_quote() {
+        // $2 of collateral against a $5 requirement on $20 of liabilities plus a 10% buffer = $7
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 10));
+        calculation
+            .add_total_collateral(2 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                5 * QUOTE_PRECISION,
+                20 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        // $7 - $2 = $5 of quote needed
+        assert_eq!(
+            calculation.margin_shortage_in_quote().unwrap(),
+            5 * QUOTE_PRECISION
+        );
+
+        let standard =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        assert!(standard.margin_shortage_in_quote().is_err());
+    }
+
+    #[test]
+    fn requirement_near_i128_max() {
+        let margin_requirement_plus_buffer = i128::MAX as u128 + 10;
+
+        let calculation = margin_calculation(5, margin_requirement_plus_buffer);
+        assert_eq!(
+            calculation.margin_shortage().unwrap(),
+            margin_requirement_plus_buffer - 5
+        );
+
+        let calculation = margin_calculation(i128::MAX, margin_requirement_plus_buffer);
+        assert_eq!(calculation.margin_shortage().unwrap(), 10);
+
+        let calculation = margin_calculation(-1, u128::MAX);
+        assert!(calculation.margin_shortage().is_err());
+    }
+}
+
+mod add_open_order_margin {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+            .add_open_order_margin(5 * QUOTE_PRECISION, 50 * QUOTE_PRECISION)
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn not_included_by_default() {
+        let calculation = calculation(MarginContext::standard(MarginRequirementType::Initial));
+        assert_eq!(calculation.margin_requirement, 10 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn raises_initial_requirement() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Initial).include_open_orders(),
+        );
+        assert_eq!(calculation.margin_requirement, 15 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn maintenance_requirement_unchanged() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Maintenance).include_open_orders(),
+        );
+        assert_eq!(calculation.margin_requirement, 10 * QUOTE_PRECISION);
+    }
+
+    #[test]
+    fn buffer_applied() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Initial)
+                .margin_buffer(MARGIN_PRECISION / 100)
+                .include_open_orders(),
+        );
+        assert_eq!(calculation.margin_requirement, 15 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.margin_requirement_plus_buffer,
+            165 * QUOTE_PRECISION / 10
+        );
+    }
+}
+
+mod add_margin_requirements {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION};
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn matches_individual_calls() {
+        let entries = [
+            (
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+            ),
+            (
+                3 * QUOTE_PRECISION,
+                20 * QUOTE_PRECISION,
+                MarketIdentifier::spot(1),
+            ),
+            (
+                7 * QUOTE_PRECISION,
+                50 * QUOTE_PRECISION,
+                MarketIdentifier::perp(2),
+            ),
+        ];
+
+        let context = MarginContext::liquidation(MARGIN_PRECISION / 100);
+
+        let mut batch = MarginCalculation::new(context);
+        batch.add_margin_requirements(&entries).unwrap();
+
+        let mut individual = MarginCalculation::new(context);
+        for (margin_requirement, liability_value, market_identifier) in entries.iter() {
+            individual
+                .add_margin_requirement(
+                    *margin_requirement,
+                    *liability_value,
+                    *market_identifier,
+                    None,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(batch.margin_requirement, 20 * QUOTE_PRECISION);
+        assert_eq!(batch.margin_requirement, individual.margin_requirement);
+        assert_eq!(
+            batch.margin_requirement_plus_buffer,
+            individual.margin_requirement_plus_buffer
+        );
+        assert_eq!(
+            batch.get_largest_liability(),
+            individual.get_largest_liability()
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 100));
+        let entries = [
+            (u128::MAX, 0, MarketIdentifier::perp(0)),
+            (1, 0, MarketIdentifier::perp(1)),
+        ];
+        assert!(calculation.add_margin_requirements(&entries).is_err());
+    }
+}
+
+mod withdrawal {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(withdraw_amount: u128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(MarginContext::withdrawal(
+            MarginRequirementType::Initial,
+            withdraw_amount,
+        ));
+        calculation
+            .add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                60 * QUOTE_PRECISION,
+                600 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn withdrawal_leaving_account_under_requirement() {
+        let calculation = calculation(50 * QUOTE_PRECISION);
+        assert!(!calculation.meets_margin_requirement());
+    }
+
+    #[test]
+    fn smaller_withdrawal() {
+        let calculation = calculation(40 * QUOTE_PRECISION);
+        assert!(calculation.meets_margin_requirement());
+    }
+
+    #[test]
+    fn standard_ignores_withdraw_amount() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        calculation
+            .add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                60 * QUOTE_PRECISION,
+                600 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        assert!(calculation.meets_margin_requirement());
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_perp_and_spot_margin_ratio {
+    use crate::math::constants::{PRICE_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    fn calculation() -> MarginCalculation {
+        MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance))
+    }
+
+    #[test]
+    fn no_exposure() {
+        let calculation = calculation();
+
+        assert_eq!(calculation.get_perp_margin_ratio().unwrap(), 0);
+        assert_eq!(calculation.get_spot_margin_ratio().unwrap(), 0);
+    }
+
+    #[test]
+    fn mixed_exposure() {
+        let mut calculation = calculation();
+        calculation.total_spot_asset_value = 400 * QUOTE_PRECISION_I128;
+        calculation.total_spot_liability_value = 200 * QUOTE_PRECISION;
+        calculation.total_perp_liability_value = 800 * QUOTE_PRECISION;
+
+        // net asset value = 200
+        let margin_ratio = calculation.get_margin_ratio().unwrap();
+        let perp_margin_ratio = calculation.get_perp_margin_ratio().unwrap();
+        let spot_margin_ratio = calculation.get_spot_margin_ratio().unwrap();
+
+        assert_eq!(margin_ratio, PRICE_PRECISION / 5);
+        assert_eq!(perp_margin_ratio, PRICE_PRECISION / 4);
+        assert_eq!(spot_margin_ratio, PRICE_PRECISION);
+
+        // each denominator is a subset of the combined one so the combined ratio is the smallest,
+        // 1 / combined = 1 / perp + 1 / spot
+        assert!(margin_ratio <= perp_margin_ratio);
+        assert!(margin_ratio <= spot_margin_ratio);
+        assert_eq!(
+            margin_ratio,
+            perp_margin_ratio * spot_margin_ratio / (perp_margin_ratio + spot_margin_ratio)
+        );
+    }
+
+    #[test]
+    fn perp_only() {
+        let mut calculation = calculation();
+        calculation.total_spot_asset_value = 100 * QUOTE_PRECISION_I128;
+        calculation.total_perp_liability_value = 1000 * QUOTE_PRECISION;
+
+        assert_eq!(
+            calculation.get_perp_margin_ratio().unwrap(),
+            calculation.get_margin_ratio().unwrap()
+        );
+        assert_eq!(calculation.get_spot_margin_ratio().unwrap(), 0);
+    }
+
+    #[test]
+    fn underwater() {
+        let mut calculation = calculation();
+        calculation.total_perp_pnl = -100 * QUOTE_PRECISION_I128;
+        calculation.total_spot_liability_value = 100 * QUOTE_PRECISION;
+        calculation.total_perp_liability_value = 100 * QUOTE_PRECISION;
+
+        assert_eq!(calculation.get_perp_margin_ratio().unwrap(), 0);
+        assert_eq!(calculation.get_spot_margin_ratio().unwrap(), 0);
+    }
+}
+
+mod get_record {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+    use anchor_lang::prelude::Pubkey;
+
+    #[test]
+    fn matches_calculation() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 100));
+        calculation
+            .add_total_collateral(50 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.add_perp_liability().unwrap();
+        calculation.add_spot_liability().unwrap();
+        calculation.update_all_oracles_valid(false);
+
+        let user_key = Pubkey::new_unique();
+        let record = calculation.get_record(&user_key, 100);
+
+        assert_eq!(record.ts, 100);
+        assert_eq!(record.user, user_key);
+        assert_eq!(
+            record.margin_requirement_type,
+            MarginRequirementType::Maintenance
+        );
+        assert_eq!(record.total_collateral, calculation.total_collateral);
+        assert_eq!(record.margin_requirement, calculation.margin_requirement);
+        assert_eq!(
+            record.margin_requirement_plus_buffer,
+            calculation.get_margin_requirement_plus_buffer().unwrap()
+        );
+        assert_eq!(record.num_spot_liabilities, 1);
+        assert_eq!(record.num_perp_liabilities, 1);
+        assert!(!record.all_oracles_valid);
+
+        calculation.emit_record(&user_key, 100);
+    }
+}
+
+mod add_total_collateral_saturating {
+    use crate::math::constants::QUOTE_PRECISION_I128;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    fn calculation() -> MarginCalculation {
+        MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance))
+    }
+
+    #[test]
+    fn no_overflow() {
+        let mut calculation = calculation();
+        calculation.add_total_collateral_saturating(100 * QUOTE_PRECISION_I128);
+        calculation.add_total_collateral_saturating(-50 * QUOTE_PRECISION_I128);
+
+        assert_eq!(calculation.total_collateral, 50 * QUOTE_PRECISION_I128);
+        assert!(!calculation.overflowed);
+    }
+
+    #[test]
+    fn saturates_at_max() {
+        let mut calculation = calculation();
+        calculation.add_total_collateral_saturating(i128::MAX - 1);
+        assert!(!calculation.overflowed);
+
+        calculation.add_total_collateral_saturating(2);
+        assert_eq!(calculation.total_collateral, i128::MAX);
+        assert!(calculation.overflowed);
+
+        // safe version still errors
+        assert!(calculation.add_total_collateral(1).is_err());
+    }
+
+    #[test]
+    fn saturates_at_min() {
+        let mut calculation = calculation();
+        calculation.add_total_collateral_saturating(i128::MIN + 1);
+        assert!(!calculation.overflowed);
+
+        calculation.add_total_collateral_saturating(-2);
+        assert_eq!(calculation.total_collateral, i128::MIN);
+        assert!(calculation.overflowed);
+
+        // flag stays set once tripped
+        calculation.add_total_collateral_saturating(1);
+        assert_eq!(calculation.total_collateral, i128::MIN + 1);
+        assert!(calculation.overflowed);
+    }
+}
+
+mod get_total_liability_value {
+    use crate::math::constants::QUOTE_PRECISION;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    #[test]
+    fn sums_spot_and_perp() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        assert_eq!(calculation.get_total_liability_value().unwrap(), 0);
+
+        calculation.total_spot_liability_value = 150 * QUOTE_PRECISION;
+        calculation.total_perp_liability_value = 850 * QUOTE_PRECISION;
+        assert_eq!(
+            calculation.get_total_liability_value().unwrap(),
+            calculation.total_spot_liability_value + calculation.total_perp_liability_value
+        );
+
+        calculation.total_perp_liability_value = u128::MAX;
+        assert!(calculation.get_total_liability_value().is_err());
+    }
+}
+
+mod initial_with_buffer {
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext, total_collateral: i128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.add_total_collateral(total_collateral).unwrap();
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn buffered_requirement_exceeds_plain_initial() {
+        let context = MarginContext::initial_with_buffer(MARGIN_PRECISION / 100);
+        assert_eq!(context.margin_type, MarginRequirementType::Initial);
+
+        let buffered = calculation(context, 105 * QUOTE_PRECISION_I128 / 10);
+        let plain = calculation(
+            MarginContext::standard(MarginRequirementType::Initial),
+            105 * QUOTE_PRECISION_I128 / 10,
+        );
+
+        assert_eq!(plain.margin_requirement, 10 * QUOTE_PRECISION);
+        assert_eq!(
+            buffered.get_margin_requirement_plus_buffer().unwrap(),
+            11 * QUOTE_PRECISION
+        );
+
+        // $10.5 clears the plain initial requirement but not the buffered one
+        assert!(plain.meets_margin_requirement());
+        assert!(!buffered.meets_margin_requirement());
+
+        let buffered = calculation(context, 11 * QUOTE_PRECISION_I128);
+        assert!(buffered.meets_margin_requirement());
+    }
+
+    #[test]
+    fn zero_buffer_matches_plain_initial() {
+        let buffered = calculation(
+            MarginContext::initial_with_buffer(0),
+            10 * QUOTE_PRECISION_I128,
+        );
+        assert!(buffered.meets_margin_requirement());
+
+        let buffered = calculation(
+            MarginContext::initial_with_buffer(0),
+            9 * QUOTE_PRECISION_I128,
+        );
+        assert!(!buffered.meets_margin_requirement());
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod add_spot_liability_value_with_interest {
+    use crate::math::constants::{MARGIN_PRECISION_U128, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    fn margin_ratio(context: MarginContext) -> u128 {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.total_spot_asset_value = 150 * QUOTE_PRECISION_I128;
+        calculation
+            .add_spot_liability_value_with_interest(100 * QUOTE_PRECISION, 25 * QUOTE_PRECISION)
+            .unwrap();
+        calculation.get_margin_ratio().unwrap()
+    }
+
+    #[test]
+    fn flag_off_ignores_interest() {
+        let context = MarginContext::standard(MarginRequirementType::Maintenance);
+        // (150 - 100) / 100
+        assert_eq!(margin_ratio(context), MARGIN_PRECISION_U128 / 2);
+    }
+
+    #[test]
+    fn flag_on_lowers_margin_ratio() {
+        let context =
+            MarginContext::standard(MarginRequirementType::Maintenance).project_borrow_interest();
+        // (150 - 125) / 125
+        assert_eq!(margin_ratio(context), MARGIN_PRECISION_U128 / 5);
+
+        let without_interest =
+            margin_ratio(MarginContext::standard(MarginRequirementType::Maintenance));
+        assert!(margin_ratio(context) < without_interest);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut calculation = MarginCalculation::new(
+            MarginContext::standard(MarginRequirementType::Maintenance).project_borrow_interest(),
+        );
+        assert!(calculation
+            .add_spot_liability_value_with_interest(u128::MAX, 1)
+            .is_err());
+    }
+}
+
+mod reset {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn matches_new() {
+        let context = MarginContext::standard(MarginRequirementType::Initial).margin_buffer(100);
+        let mut calculation = MarginCalculation::new(context);
+
+        calculation
+            .add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                10 * QUOTE_PRECISION,
+                100 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.add_perp_liability().unwrap();
+        calculation.add_spot_liability().unwrap();
+        calculation.total_spot_liability_value = 50 * QUOTE_PRECISION;
+        calculation.update_all_oracles_valid(false);
+        calculation.add_total_collateral_saturating(i128::MAX);
+        assert!(calculation.overflowed);
+
+        calculation.reset();
+
+        assert_eq!(calculation.total_collateral, 0);
+        assert_eq!(calculation.margin_requirement, 0);
+        assert_eq!(calculation.margin_requirement_plus_buffer, 0);
+        assert_eq!(calculation.num_perp_liabilities, 0);
+        assert_eq!(calculation.num_spot_liabilities, 0);
+        assert_eq!(calculation.total_spot_liability_value, 0);
+        assert!(calculation.all_oracles_valid);
+        assert!(!calculation.overflowed);
+        assert_eq!(
+            format!("{:?}", calculation),
+            format!("{:?}", MarginCalculation::new(context))
+        );
+
+        // context is preserved
+        assert_eq!(calculation.context.margin_buffer, 100);
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_margin_ratio_large_values {
+    use crate::math::constants::PRICE_PRECISION;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    const LARGE_VALUE: u128 = 100_000_000_000_000_000_000_000_000_000_000_000; // 1e35
+
+    #[test]
+    fn large_net_asset_value() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_spot_asset_value = LARGE_VALUE as i128;
+        calculation.total_perp_liability_value = LARGE_VALUE;
+
+        // net asset value * PRICE_PRECISION overflows i128
+        assert!((LARGE_VALUE as i128)
+            .checked_mul(PRICE_PRECISION as i128)
+            .is_none());
+
+        assert_eq!(calculation.get_margin_ratio().unwrap(), PRICE_PRECISION);
+        assert_eq!(
+            calculation.get_perp_margin_ratio().unwrap(),
+            PRICE_PRECISION
+        );
+    }
+
+    #[test]
+    fn large_negative_net_asset_value() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_perp_pnl = -(LARGE_VALUE as i128);
+        calculation.total_perp_liability_value = LARGE_VALUE / 2;
+
+        assert_eq!(
+            calculation.get_signed_margin_ratio().unwrap(),
+            -2 * PRICE_PRECISION as i128
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), 0);
+    }
+
+    #[test]
+    fn rounds_towards_zero() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_perp_pnl = -1;
+        calculation.total_perp_liability_value = 3 * PRICE_PRECISION;
+
+        // -1/3 rounds to 0 like i128 division
+        assert_eq!(calculation.get_signed_margin_ratio().unwrap(), 0);
+
+        calculation.total_perp_pnl = 2;
+        assert_eq!(calculation.get_signed_margin_ratio().unwrap(), 0);
+    }
+}
+
+mod sort_by_liquidation_priority {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{
+        sort_by_liquidation_priority, MarginCalculation, MarginContext, MarketIdentifier,
+    };
+
+    fn calculation(total_collateral: i128) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::liquidation(MARGIN_PRECISION / 50));
+        calculation.add_total_collateral(total_collateral).unwrap();
+        // 100 requirement + 2% buffer on 1000 liability = 120
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.add_perp_liability().unwrap();
+        calculation
+    }
+
+    #[test]
+    fn descending_by_shortage() {
+        let mut calculations = [
+            calculation(110 * QUOTE_PRECISION_I128),
+            calculation(60 * QUOTE_PRECISION_I128),
+            calculation(90 * QUOTE_PRECISION_I128),
+        ];
+
+        sort_by_liquidation_priority(&mut calculations).unwrap();
+
+        let total_collaterals: Vec<i128> = calculations
+            .iter()
+            .map(|calculation| calculation.total_collateral)
+            .collect();
+        assert_eq!(
+            total_collaterals,
+            vec![
+                60 * QUOTE_PRECISION_I128,
+                90 * QUOTE_PRECISION_I128,
+                110 * QUOTE_PRECISION_I128
+            ]
+        );
+    }
+
+    #[test]
+    fn stable_for_equal_priority() {
+        let mut first = calculation(120 * QUOTE_PRECISION_I128);
+        first.total_spot_asset_value = 1;
+        let mut second = calculation(130 * QUOTE_PRECISION_I128);
+        second.total_spot_asset_value = 2;
+        let mut calculations = [first, second, calculation(60 * QUOTE_PRECISION_I128)];
+
+        sort_by_liquidation_priority(&mut calculations).unwrap();
+
+        // both can exit liquidation so have 0 priority and keep their order
+        assert_eq!(calculations[0].total_collateral, 60 * QUOTE_PRECISION_I128);
+        assert_eq!(calculations[1].total_spot_asset_value, 1);
+        assert_eq!(calculations[2].total_spot_asset_value, 2);
+    }
+
+    #[test]
+    fn propagates_error() {
+        let mut calculations = [
+            calculation(60 * QUOTE_PRECISION_I128),
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance)),
+        ];
+
+        assert_eq!(
+            sort_by_liquidation_priority(&mut calculations),
+            Err(ErrorCode::InvalidMarginCalculation)
+        );
+        // left untouched on error
+        assert_eq!(calculations[0].total_collateral, 60 * QUOTE_PRECISION_I128);
+    }
+}
+
+mod meets_margin_requirement_quote_only {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext, total_collateral: i128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.add_total_collateral(total_collateral).unwrap();
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.add_perp_liability().unwrap();
+        calculation
+    }
+
+    #[test]
+    fn matches_full_path() {
+        let context = MarginContext::standard(MarginRequirementType::Initial).quote_only();
+
+        for total_collateral in [
+            0,
+            99 * QUOTE_PRECISION_I128,
+            100 * QUOTE_PRECISION_I128,
+            101 * QUOTE_PRECISION_I128,
+        ] {
+            let calculation = calculation(context, total_collateral);
+            assert_eq!(
+                calculation.meets_margin_requirement_quote_only().unwrap(),
+                calculation.meets_margin_requirement()
+            );
+        }
+    }
+
+    #[test]
+    fn requires_quote_only_context() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Initial),
+            100 * QUOTE_PRECISION_I128,
+        );
+
+        assert_eq!(
+            calculation.meets_margin_requirement_quote_only(),
+            Err(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+
+    #[test]
+    fn spot_liability() {
+        let mut calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Initial).quote_only(),
+            100 * QUOTE_PRECISION_I128,
+        );
+        calculation.add_spot_liability().unwrap();
+
+        assert_eq!(
+            calculation.meets_margin_requirement_quote_only(),
+            Err(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_margin_ratio_status {
+    use crate::math::constants::PRICE_PRECISION;
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarginRatioStatus};
+
+    fn calculation(
+        total_spot_asset_value: i128,
+        total_perp_liability_value: u128,
+    ) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_spot_asset_value = total_spot_asset_value;
+        calculation.total_perp_liability_value = total_perp_liability_value;
+        calculation
+    }
+
+    #[test]
+    fn flat() {
+        let calculation = calculation(100 * QUOTE_PRECISION_I128, 0);
+        assert_eq!(
+            calculation.get_margin_ratio_status().unwrap(),
+            MarginRatioStatus::Flat
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), 0);
+    }
+
+    #[test]
+    fn underwater() {
+        let calculation = calculation(-10 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.get_margin_ratio_status().unwrap(),
+            MarginRatioStatus::Underwater
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), 0);
+
+        let zero_net_asset_value = calculation(0, 100 * QUOTE_PRECISION);
+        assert_eq!(
+            zero_net_asset_value.get_margin_ratio_status().unwrap(),
+            MarginRatioStatus::Underwater
+        );
+    }
+
+    #[test]
+    fn ratio() {
+        let calculation = calculation(50 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(
+            calculation.get_margin_ratio_status().unwrap(),
+            MarginRatioStatus::Ratio(PRICE_PRECISION / 2)
+        );
+        assert_eq!(calculation.get_margin_ratio().unwrap(), PRICE_PRECISION / 2);
+    }
+}
+
+mod add_weighted_spot_asset_value {
+    use crate::math::constants::{QUOTE_PRECISION_I128, SPOT_WEIGHT_PRECISION};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    #[test]
+    fn weighted_vs_raw() {
+        let context = MarginContext::standard(MarginRequirementType::Initial);
+
+        let mut raw = MarginCalculation::new(context);
+        raw.add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+
+        let mut weighted = MarginCalculation::new(context);
+        weighted
+            .add_weighted_spot_asset_value(
+                100 * QUOTE_PRECISION_I128,
+                SPOT_WEIGHT_PRECISION * 9 / 10,
+            )
+            .unwrap();
+
+        assert_eq!(raw.total_collateral, 100 * QUOTE_PRECISION_I128);
+        assert_eq!(weighted.total_collateral, 90 * QUOTE_PRECISION_I128);
+
+        let mut full_weight = MarginCalculation::new(context);
+        full_weight
+            .add_weighted_spot_asset_value(100 * QUOTE_PRECISION_I128, SPOT_WEIGHT_PRECISION)
+            .unwrap();
+        assert_eq!(full_weight.total_collateral, raw.total_collateral);
+    }
+}
+
+mod liquidation_collateral_buffer {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext, total_collateral: i128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.add_total_collateral(total_collateral).unwrap();
+        // 100 requirement + 2% buffer on 1000 liability = 120
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.finalize().unwrap();
+        calculation
+    }
+
+    #[test]
+    fn raises_exit_threshold() {
+        let without_buffer = MarginContext::liquidation(MARGIN_PRECISION / 50);
+        let with_buffer = MarginContext::liquidation(MARGIN_PRECISION / 50)
+            .liquidation_collateral_buffer(MARGIN_PRECISION / 20)
+            .unwrap();
+
+        let calculation_without_buffer = calculation(without_buffer, 125 * QUOTE_PRECISION_I128);
+        assert_eq!(
+            calculation_without_buffer
+                .get_margin_requirement_plus_buffer()
+                .unwrap(),
+            120 * QUOTE_PRECISION
+        );
+        assert!(calculation_without_buffer.can_exit_liquidation().unwrap());
+
+        // 120 + 5% of 125 collateral
+        let calculation_with_buffer = calculation(with_buffer, 125 * QUOTE_PRECISION_I128);
+        assert_eq!(
+            calculation_with_buffer
+                .get_margin_requirement_plus_buffer()
+                .unwrap(),
+            126_250_000
+        );
+        assert!(!calculation_with_buffer.can_exit_liquidation().unwrap());
+
+        // 120 + 5% of 130 collateral
+        let calculation_with_buffer = calculation(with_buffer, 130 * QUOTE_PRECISION_I128);
+        assert!(calculation_with_buffer.can_exit_liquidation().unwrap());
+    }
+
+    #[test]
+    fn no_buffer_on_negative_collateral() {
+        let context = MarginContext::liquidation(MARGIN_PRECISION / 50)
+            .liquidation_collateral_buffer(MARGIN_PRECISION / 20)
+            .unwrap();
+
+        let calculation = calculation(context, -10 * QUOTE_PRECISION_I128);
+        assert_eq!(
+            calculation.get_margin_requirement_plus_buffer().unwrap(),
+            120 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn standard_mode() {
+        assert_eq!(
+            MarginContext::standard(MarginRequirementType::Maintenance)
+                .liquidation_collateral_buffer(MARGIN_PRECISION / 20)
+                .err(),
+            Some(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+}
+
+mod liquidation_fee {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{MARGIN_PRECISION, QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext, total_collateral: i128) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation.add_total_collateral(total_collateral).unwrap();
+        // 100 requirement + 2% buffer on 1000 liability = 120
+        calculation
+            .add_margin_requirement(
+                100 * QUOTE_PRECISION,
+                1000 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation.finalize().unwrap();
+        calculation
+    }
+
+    #[test]
+    fn defaults_to_zero() {
+        let calculation = calculation(
+            MarginContext::liquidation(MARGIN_PRECISION / 50),
+            120 * QUOTE_PRECISION_I128,
+        );
+        assert_eq!(calculation.get_liquidation_fee(), 0);
+        assert_eq!(
+            calculation.get_margin_requirement_plus_buffer().unwrap(),
+            120 * QUOTE_PRECISION
+        );
+        assert!(calculation.can_exit_liquidation().unwrap());
+    }
+
+    #[test]
+    fn raises_exit_threshold() {
+        let context = MarginContext::liquidation(MARGIN_PRECISION / 50)
+            .liquidation_fee(5 * QUOTE_PRECISION)
+            .unwrap();
+
+        let calculation_with_fee = calculation(context, 120 * QUOTE_PRECISION_I128);
+        assert_eq!(
+            calculation_with_fee.get_liquidation_fee(),
+            5 * QUOTE_PRECISION
+        );
+        assert_eq!(
+            calculation_with_fee
+                .get_margin_requirement_plus_buffer()
+                .unwrap(),
+            125 * QUOTE_PRECISION
+        );
+        assert!(!calculation_with_fee.can_exit_liquidation().unwrap());
+
+        let calculation_with_fee = calculation(context, 125 * QUOTE_PRECISION_I128);
+        assert!(calculation_with_fee.can_exit_liquidation().unwrap());
+    }
+
+    #[test]
+    fn standard_mode() {
+        assert_eq!(
+            MarginContext::standard(MarginRequirementType::Maintenance)
+                .liquidation_fee(5 * QUOTE_PRECISION)
+                .err(),
+            Some(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+}
+
+mod get_free_collateral {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    #[test]
+    fn margin_requirement_beyond_i128() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        calculation.total_collateral = 100 * QUOTE_PRECISION_I128;
+        calculation.margin_requirement = i128::MAX as u128 + 1;
+
+        assert_eq!(calculation.get_free_collateral(), Ok(0));
+
+        calculation.margin_requirement = u128::MAX;
+        assert_eq!(calculation.get_free_collateral(), Ok(0));
+    }
+
+    #[test]
+    fn unchanged_for_normal_values() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        calculation.total_collateral = 100 * QUOTE_PRECISION_I128;
+        calculation.margin_requirement = 40 * QUOTE_PRECISION;
+        assert_eq!(calculation.get_free_collateral(), Ok(60 * QUOTE_PRECISION));
+
+        calculation.total_collateral = -100 * QUOTE_PRECISION_I128;
+        assert_eq!(calculation.get_free_collateral(), Ok(0));
+
+        calculation.total_collateral = i128::MIN;
+        assert_eq!(calculation.get_free_collateral(), Ok(0));
+    }
+}
+
+mod collateral_to_reach_health {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(total_collateral: i128, margin_requirement: u128) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.add_total_collateral(total_collateral).unwrap();
+        calculation
+            .add_margin_requirement(margin_requirement, 0, MarketIdentifier::perp(0), None)
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn underwater_to_fifty() {
+        let underwater = calculation(-20 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(underwater.get_health().unwrap(), 0);
+
+        // needs 150 total collateral
+        let needed = underwater.collateral_to_reach_health(50).unwrap();
+        assert_eq!(needed, 170 * QUOTE_PRECISION);
+
+        let after_deposit = calculation(
+            -20 * QUOTE_PRECISION_I128 + needed as i128,
+            100 * QUOTE_PRECISION,
+        );
+        assert_eq!(after_deposit.get_health().unwrap(), 50);
+
+        let short_by_one = calculation(
+            -20 * QUOTE_PRECISION_I128 + needed as i128 - 1,
+            100 * QUOTE_PRECISION,
+        );
+        assert_eq!(short_by_one.get_health().unwrap(), 49);
+    }
+
+    #[test]
+    fn already_healthy() {
+        let healthy = calculation(180 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(healthy.get_health().unwrap(), 80);
+        assert_eq!(healthy.collateral_to_reach_health(50).unwrap(), 0);
+        assert_eq!(healthy.collateral_to_reach_health(80).unwrap(), 0);
+        assert_eq!(
+            healthy.collateral_to_reach_health(100).unwrap(),
+            20 * QUOTE_PRECISION
+        );
+        // capped at 100
+        assert_eq!(
+            healthy.collateral_to_reach_health(u8::MAX).unwrap(),
+            20 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn zero_target_or_requirement() {
+        let underwater = calculation(-20 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(underwater.collateral_to_reach_health(0).unwrap(), 0);
+
+        let no_requirement = calculation(-20 * QUOTE_PRECISION_I128, 0);
+        assert_eq!(no_requirement.collateral_to_reach_health(50).unwrap(), 0);
+    }
+
+    #[test]
+    fn rounds_up() {
+        // 50% of 3 is 1.5 so 2 free collateral is needed
+        let calculation = calculation(0, 3);
+        assert_eq!(calculation.collateral_to_reach_health(50).unwrap(), 5);
+    }
+}
+
+mod update_oracle_valid_for_market {
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+    use anchor_lang::prelude::Pubkey;
+
+    #[test]
+    fn one_record_per_invalid_oracle() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+
+        calculation.update_oracle_valid_for_market(true, MarketIdentifier::spot(0));
+        assert_eq!(calculation.num_invalid_oracles, 0);
+        assert!(calculation.all_oracles_valid);
+
+        calculation.update_oracle_valid_for_market(false, MarketIdentifier::spot(1));
+        calculation.update_oracle_valid_for_market(true, MarketIdentifier::perp(0));
+        calculation.update_oracle_valid_for_market(false, MarketIdentifier::perp(1));
+
+        assert_eq!(calculation.num_invalid_oracles, 2);
+        // valid oracles after an invalid one don't reset the flag
+        assert!(!calculation.all_oracles_valid);
+    }
+
+    #[test]
+    fn records_only_emitted_when_requested() {
+        assert_eq!(
+            MarginContext::standard(MarginRequirementType::Maintenance).emit_oracle_invalid_records,
+            None
+        );
+        assert_eq!(
+            MarginContext::liquidation(0).emit_oracle_invalid_records,
+            None
+        );
+
+        let user_key = Pubkey::new_unique();
+        let mut calculation = MarginCalculation::new(
+            MarginContext::liquidation(0).emit_oracle_invalid_records(user_key, 100),
+        );
+        assert_eq!(
+            calculation.context.emit_oracle_invalid_records,
+            Some((user_key, 100))
+        );
+
+        // invalid oracles are counted either way
+        calculation.update_oracle_valid_for_market(false, MarketIdentifier::spot(1));
+        assert_eq!(calculation.num_invalid_oracles, 1);
+    }
+}
+
+mod with_hypothetical_position_change {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn original_unchanged() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        calculation
+            .add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_margin_requirement(
+                60 * QUOTE_PRECISION,
+                600 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        let after_fill = calculation
+            .with_hypothetical_position_change(-5 * QUOTE_PRECISION_I128, 50 * QUOTE_PRECISION)
+            .unwrap();
+
+        assert_eq!(calculation.total_collateral, 100 * QUOTE_PRECISION_I128);
+        assert_eq!(calculation.margin_requirement, 60 * QUOTE_PRECISION);
+        assert!(calculation.meets_margin_requirement());
+        assert_eq!(calculation.get_free_collateral(), Ok(40 * QUOTE_PRECISION));
+
+        assert_eq!(after_fill.total_collateral, 95 * QUOTE_PRECISION_I128);
+        assert_eq!(after_fill.margin_requirement, 110 * QUOTE_PRECISION);
+        assert!(!after_fill.meets_margin_requirement());
+        assert_eq!(after_fill.get_free_collateral(), Ok(0));
+    }
+
+    #[test]
+    fn margin_requirement_plus_buffer_only_when_tracked() {
+        let untracked =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial))
+                .with_hypothetical_position_change(0, 50 * QUOTE_PRECISION)
+                .unwrap();
+        assert_eq!(untracked.margin_requirement_plus_buffer, 0);
+
+        let tracked = MarginCalculation::new(
+            MarginContext::standard(MarginRequirementType::Initial).margin_buffer(100),
+        )
+        .with_hypothetical_position_change(0, 50 * QUOTE_PRECISION)
+        .unwrap();
+        assert_eq!(tracked.margin_requirement_plus_buffer, 50 * QUOTE_PRECISION);
+
+        let liquidation = MarginCalculation::new(MarginContext::liquidation(100))
+            .with_hypothetical_position_change(0, 50 * QUOTE_PRECISION)
+            .unwrap();
+        assert_eq!(
+            liquidation.margin_requirement_plus_buffer,
+            50 * QUOTE_PRECISION
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        assert!(calculation
+            .with_hypothetical_position_change(0, u128::MAX)
+            .is_ok());
+        assert!(calculation
+            .with_hypothetical_position_change(i128::MAX, 0)
+            .unwrap()
+            .with_hypothetical_position_change(1, 0)
+            .is_err());
+    }
+}
+
+mod max_leverage {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(context: MarginContext) -> MarginCalculation {
+        let mut calculation = MarginCalculation::new(context);
+        calculation
+            .add_total_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        // 10% initial margin ratio on 800 liability, 8x leverage
+        calculation
+            .add_margin_requirement(
+                80 * QUOTE_PRECISION,
+                800 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn unchanged_without_max_leverage() {
+        let calculation = calculation(MarginContext::standard(MarginRequirementType::Initial));
+        assert!(calculation.meets_margin_requirement());
+    }
+
+    #[test]
+    fn fails_tighter_leverage_cap() {
+        let capped_at_5x =
+            calculation(MarginContext::standard(MarginRequirementType::Initial).max_leverage(5));
+        assert!(!capped_at_5x.meets_margin_requirement());
+
+        let capped_at_8x =
+            calculation(MarginContext::standard(MarginRequirementType::Initial).max_leverage(8));
+        assert!(capped_at_8x.meets_margin_requirement());
+    }
+
+    #[test]
+    fn only_initial() {
+        let calculation = calculation(
+            MarginContext::standard(MarginRequirementType::Maintenance).max_leverage(5),
+        );
+        assert!(calculation.meets_margin_requirement());
+    }
+
+    #[test]
+    fn no_collateral() {
+        let calculation = MarginCalculation::new(
+            MarginContext::standard(MarginRequirementType::Initial).max_leverage(5),
+        );
+        assert!(calculation.meets_margin_requirement());
+    }
+}
+
+mod max_withdrawable {
+    use crate::error::ErrorCode;
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    fn calculation(total_collateral: i128, margin_requirement: u128) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        calculation.add_total_collateral(total_collateral).unwrap();
+        calculation
+            .add_margin_requirement(
+                margin_requirement,
+                10 * margin_requirement,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+        calculation
+    }
+
+    #[test]
+    fn fully_collateralized() {
+        let calculation = calculation(100 * QUOTE_PRECISION_I128, 0);
+        assert_eq!(calculation.max_withdrawable(), Ok(100 * QUOTE_PRECISION));
+    }
+
+    #[test]
+    fn near_requirement() {
+        let calculation = calculation(100 * QUOTE_PRECISION_I128, 99 * QUOTE_PRECISION);
+        assert_eq!(calculation.max_withdrawable(), Ok(QUOTE_PRECISION));
+    }
+
+    #[test]
+    fn underwater() {
+        let below_requirement = calculation(100 * QUOTE_PRECISION_I128, 120 * QUOTE_PRECISION);
+        assert_eq!(below_requirement.max_withdrawable(), Ok(0));
+
+        let negative_collateral = calculation(-10 * QUOTE_PRECISION_I128, 0);
+        assert_eq!(negative_collateral.max_withdrawable(), Ok(0));
+    }
+
+    #[test]
+    fn maintenance() {
+        let calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        assert_eq!(
+            calculation.max_withdrawable(),
+            Err(ErrorCode::InvalidMarginCalculation)
+        );
+    }
+}
+
+mod collateral_composition {
+    use crate::math::constants::{QUOTE_PRECISION_I128, SPOT_WEIGHT_PRECISION};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{
+        CollateralComposition, MarginCalculation, MarginContext,
+    };
+
+    #[test]
+    fn components_sum_to_total_collateral() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+
+        calculation
+            .add_quote_collateral(100 * QUOTE_PRECISION_I128)
+            .unwrap();
+        // 80% asset weight
+        calculation
+            .add_weighted_spot_asset_value(
+                50 * QUOTE_PRECISION_I128,
+                SPOT_WEIGHT_PRECISION * 8 / 10,
+            )
+            .unwrap();
+        calculation
+            .add_spot_collateral(10 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_perp_pnl_collateral(-25 * QUOTE_PRECISION_I128)
+            .unwrap();
+        calculation
+            .add_total_collateral(5 * QUOTE_PRECISION_I128)
+            .unwrap();
+
+        let composition = calculation.get_collateral_composition();
+        assert_eq!(
+            composition,
+            CollateralComposition {
+                quote: 100 * QUOTE_PRECISION_I128,
+                spot: 50 * QUOTE_PRECISION_I128,
+                perp_pnl: -25 * QUOTE_PRECISION_I128,
+                other: 5 * QUOTE_PRECISION_I128,
+            }
+        );
+
+        assert_eq!(
+            composition.quote + composition.spot + composition.perp_pnl + composition.other,
+            calculation.total_collateral
+        );
+        assert_eq!(calculation.total_collateral, 130 * QUOTE_PRECISION_I128);
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod add_spot_liability_value_at_price {
+    use crate::error::ErrorCode;
+    use crate::math::constants::PRICE_PRECISION_I64;
+    use crate::math::margin::MarginRequirementType;
+    use crate::math::spot_balance::get_token_value;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext};
+
+    #[test]
+    fn matches_get_token_value() {
+        let context = MarginContext::standard(MarginRequirementType::Initial);
+        let mut at_price = MarginCalculation::new(context);
+        let mut precomputed = MarginCalculation::new(context);
+
+        let price = 21_345_678_901_i64; // $21345.678901
+        for (token_amount, decimals) in [
+            (1_u128, 9_u32),
+            (123_456_789, 9),
+            (5_000_000_000, 9),
+            (999_999, 6),
+            (7, 6),
+        ] {
+            at_price
+                .add_spot_liability_value_at_price(
+                    token_amount,
+                    price as i128,
+                    10_u128.pow(decimals),
+                )
+                .unwrap();
+
+            let token_value = get_token_value(-(token_amount as i128), decimals, price).unwrap();
+            precomputed
+                .add_spot_liability_value_with_interest(token_value.unsigned_abs(), 0)
+                .unwrap();
+
+            assert_eq!(
+                at_price.total_spot_liability_value,
+                precomputed.total_spot_liability_value
+            );
+        }
+
+        assert!(at_price.total_spot_liability_value > 0);
+    }
+
+    #[test]
+    fn negative_price() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Initial));
+        assert_eq!(
+            calculation.add_spot_liability_value_at_price(
+                1_000_000_000,
+                -(PRICE_PRECISION_I64 as i128),
+                10_u128.pow(9)
+            ),
+            Err(ErrorCode::CastingFailure)
+        );
+    }
+}
+
+#[cfg(feature = "drift-rs")]
+mod get_leverage {
+    use crate::math::constants::{QUOTE_PRECISION, QUOTE_PRECISION_I128};
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, UNDERWATER_LEVERAGE};
+
+    fn calculation(net_asset_value: i128, total_liability_value: u128) -> MarginCalculation {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.total_spot_asset_value = net_asset_value;
+        calculation.total_perp_liability_value = total_liability_value;
+        calculation
+    }
+
+    #[test]
+    fn two_x() {
+        let calculation = calculation(100 * QUOTE_PRECISION_I128, 200 * QUOTE_PRECISION);
+        assert_eq!(calculation.get_leverage().unwrap(), 200);
+    }
+
+    #[test]
+    fn five_x() {
+        // net asset value = 250 - 50 - 100 = 100, liabilities = 400 perp + 100 spot
+        let mut calculation = calculation(250 * QUOTE_PRECISION_I128, 400 * QUOTE_PRECISION);
+        calculation.total_perp_pnl = -50 * QUOTE_PRECISION_I128;
+        calculation.total_spot_liability_value = 100 * QUOTE_PRECISION;
+        assert_eq!(calculation.get_leverage().unwrap(), 500);
+    }
+
+    #[test]
+    fn fractional() {
+        let calculation = calculation(300 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(calculation.get_leverage().unwrap(), 33); // 0.33x
+    }
+
+    #[test]
+    fn flat() {
+        let calculation = calculation(100 * QUOTE_PRECISION_I128, 0);
+        assert_eq!(calculation.get_leverage().unwrap(), 0);
+    }
+
+    #[test]
+    fn underwater() {
+        let calculation = calculation(-10 * QUOTE_PRECISION_I128, 100 * QUOTE_PRECISION);
+        assert_eq!(calculation.get_leverage().unwrap(), UNDERWATER_LEVERAGE);
+    }
+}
+
+mod validate_num_perp_liabilities {
+    use crate::error::ErrorCode;
+    use crate::math::constants::QUOTE_PRECISION;
+    use crate::math::margin::MarginRequirementType;
+    use crate::state::margin_calculation::{MarginCalculation, MarginContext, MarketIdentifier};
+
+    #[test]
+    fn perp_liability_without_margin_requirement() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.add_perp_liability().unwrap();
+
+        assert_eq!(
+            calculation.validate_num_perp_liabilities(),
+            Err(ErrorCode::InvalidMarginRatio)
+        );
+
+        calculation
+            .add_margin_requirement(
+                QUOTE_PRECISION,
+                10 * QUOTE_PRECISION,
+                MarketIdentifier::perp(0),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(calculation.validate_num_perp_liabilities(), Ok(()));
+    }
+
+    #[test]
+    fn unsettled_pnl_has_no_margin_requirement() {
+        let mut calculation =
+            MarginCalculation::new(MarginContext::standard(MarginRequirementType::Maintenance));
+        calculation.add_unsettled_pnl_perp_liability().unwrap();
+
+        assert_eq!(calculation.num_perp_liabilities, 1);
+        assert_eq!(calculation.validate_num_perp_liabilities(), Ok(()));
+
+        calculation.add_perp_liability().unwrap();
+        assert_eq!(
+            calculation.validate_num_perp_liabilities(),
+            Err(ErrorCode::InvalidMarginRatio)
+        );
+    }
+}
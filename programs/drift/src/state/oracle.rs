@@ -318,6 +318,7 @@ pub fn get_prelaunch_price(price_oracle: &AccountInfo, slot: u64) -> DriftResult
 pub struct StrictOraclePrice {
     pub current: i64,
     pub twap_5min: Option<i64>,
+    pub confidence: u64,
 }
 
 impl StrictOraclePrice {
@@ -325,19 +326,29 @@ impl StrictOraclePrice {
         Self {
             current: price,
             twap_5min: if enabled { Some(twap_5min) } else { None },
+            confidence: 0,
         }
     }
 
+    /// Widens max and min by the oracle confidence interval, only applies when strict
+    pub fn with_confidence(mut self, confidence: u64) -> Self {
+        self.confidence = confidence;
+        self
+    }
+
     pub fn max(&self) -> i64 {
         match self.twap_5min {
-            Some(twap) => self.current.max(twap),
+            Some(twap) => apply_confidence(self.current.max(twap) as i128, self.confidence, false)
+                .min(i64::MAX as i128) as i64,
             None => self.current,
         }
     }
 
     pub fn min(&self) -> i64 {
         match self.twap_5min {
-            Some(twap) => self.current.min(twap),
+            Some(twap) => {
+                apply_confidence(self.current.min(twap) as i128, self.confidence, true) as i64
+            }
             None => self.current,
         }
     }
@@ -369,10 +380,21 @@ impl StrictOraclePrice {
         Self {
             current: price,
             twap_5min: None,
+            confidence: 0,
         }
     }
 }
 
+/// Conservative edge of the oracle confidence interval, the lower bound for assets and upper bound
+/// for liabilities. Asset prices are floored at 0
+pub fn apply_confidence(price: i128, confidence: u64, is_asset: bool) -> i128 {
+    if is_asset {
+        price.saturating_sub(confidence as i128).max(0)
+    } else {
+        price.saturating_add(confidence as i128)
+    }
+}
+
 #[account(zero_copy(unsafe))]
 #[derive(Eq, PartialEq, Debug)]
 #[repr(C)]
@@ -3,7 +3,7 @@ use std::str::FromStr;
 use solana_program::pubkey::Pubkey;
 
 use crate::create_account_info;
-use crate::state::oracle::{get_oracle_price, OracleSource};
+use crate::state::oracle::{apply_confidence, get_oracle_price, OracleSource, StrictOraclePrice};
 use crate::state::perp_market::AMM;
 use crate::test_utils::*;
 
@@ -58,3 +58,23 @@ fn pyth_1m() {
     let twap = amm.get_oracle_twap(&oracle_account_info, 0).unwrap();
     assert_eq!(twap, Some(839400));
 }
+
+#[test]
+fn apply_confidence_bounds() {
+    assert_eq!(apply_confidence(100, 5, true), 95);
+    assert_eq!(apply_confidence(100, 5, false), 105);
+    // asset price floored at 0
+    assert_eq!(apply_confidence(3, 5, true), 0);
+}
+
+#[test]
+fn strict_oracle_price_with_confidence() {
+    let strict_price = StrictOraclePrice::new(100, 98, true).with_confidence(5);
+    assert_eq!(strict_price.min(), 93);
+    assert_eq!(strict_price.max(), 105);
+    assert_eq!(strict_price.current, 100);
+
+    let not_strict_price = StrictOraclePrice::new(100, 98, false).with_confidence(5);
+    assert_eq!(not_strict_price.min(), 100);
+    assert_eq!(not_strict_price.max(), 100);
+}
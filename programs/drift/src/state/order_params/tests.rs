@@ -855,7 +855,7 @@ mod get_close_perp_params {
     use crate::state::perp_market::{PerpMarket, AMM};
     use crate::{ContractTier, PRICE_PRECISION_U64};
 
-    use crate::state::user::{Order, OrderStatus};
+    use crate::state::user::{CollateralBucket, Order, OrderStatus};
     use crate::test_utils::create_account_info;
     use crate::validation::order::validate_order;
     use crate::{OrderParams, PositionDirection, BASE_PRECISION_U64, PRICE_PRECISION_I64};
@@ -1185,7 +1185,8 @@ mod get_close_perp_params {
             auction_end_price: params.auction_end_price.unwrap_or(0),
             auction_duration: params.auction_duration.unwrap_or(0),
             max_ts: 100,
-            padding: [0; 3],
+            collateral_bucket: CollateralBucket::Cross,
+            padding: [0; 2],
         }
     }
 
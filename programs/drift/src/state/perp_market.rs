@@ -21,7 +21,7 @@ use crate::math::helpers::get_proportion_i128;
 
 use crate::math::margin::{
     calculate_size_discount_asset_weight, calculate_size_premium_liability_weight,
-    MarginRequirementType,
+    resolve_margin_ratio, MarginRequirementType,
 };
 use crate::math::safe_math::SafeMath;
 use crate::math::stats;
@@ -84,6 +84,17 @@ impl MarketStatus {
             Ok(())
         }
     }
+
+    /// settle_pnl is allowed for users without a position, settling with a position also
+    /// requires Active
+    pub fn allows_pnl_settlement(&self) -> bool {
+        matches!(self, MarketStatus::Active | MarketStatus::ReduceOnly)
+    }
+
+    /// Expired positions are settled against the expiry price with settle_expired_position
+    pub fn allows_expired_settlement(&self) -> bool {
+        matches!(self, MarketStatus::Settlement)
+    }
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
@@ -232,7 +243,24 @@ pub struct PerpMarket {
     /// E.g. if this is -50 and the fee is 5bps, the new fee will be 2.5bps
     /// if this is 50 and the fee is 5bps, the new fee will be 7.5bps
     pub fee_adjustment: i16,
-    pub padding: [u8; 46],
+    pub padding1: u16,
+    /// The fraction of positive pnl written off when it's settled, recomputed on every settle from the
+    /// pnl pool balance and the net pnl owed to users. Only non-zero while the market is reduce only
+    /// and socialized_loss_enabled is set
+    /// precision: PERCENTAGE_PRECISION
+    pub socialized_loss_ratio: u32,
+    /// Settling at most this much positive pnl is rejected within min_settle_interval of the position's last
+    /// settlement. 0 applies the cooldown to positive pnl of any size
+    /// precision: QUOTE_PRECISION
+    pub settle_cooldown_pnl_threshold: u64,
+    /// Minimum seconds between pnl settlements for a user's position, at most u8::MAX. 0 disables the cooldown
+    pub min_settle_interval: u32,
+    /// Whether positive pnl is written down by socialized_loss_ratio while the market is reduce only
+    pub socialized_loss_enabled: bool,
+    /// Whether expired positions settle at the oracle twap instead of expiry_price, falling back to
+    /// expiry_price when there's no twap
+    pub use_twap_for_expiry: bool,
+    pub padding: [u8; 26],
 }
 
 impl Default for PerpMarket {
@@ -266,7 +294,13 @@ impl Default for PerpMarket {
             paused_operations: 0,
             quote_spot_market_index: 0,
             fee_adjustment: 0,
-            padding: [0; 46],
+            padding1: 0,
+            socialized_loss_ratio: 0,
+            settle_cooldown_pnl_threshold: 0,
+            min_settle_interval: 0,
+            socialized_loss_enabled: false,
+            use_twap_for_expiry: false,
+            padding: [0; 26],
         }
     }
 }
@@ -293,6 +327,52 @@ impl PerpMarket {
         Ok(self.status == MarketStatus::ReduceOnly)
     }
 
+    pub fn get_socialized_loss(&self, unsettled_pnl: i128) -> DriftResult<i128> {
+        if unsettled_pnl <= 0 || self.socialized_loss_ratio == 0 {
+            return Ok(0);
+        }
+
+        unsettled_pnl
+            .safe_mul(self.socialized_loss_ratio.cast()?)?
+            .safe_div(PERCENTAGE_PRECISION_I128)
+    }
+
+    /// Pnl written off alongside a payout at the current socialized loss ratio, where the payout is
+    /// (1 - ratio) of the pnl settled
+    pub fn get_socialized_loss_for_payout(&self, payout: i128) -> DriftResult<i128> {
+        let socialized_loss_ratio = self.socialized_loss_ratio.cast::<i128>()?;
+        if payout <= 0
+            || socialized_loss_ratio == 0
+            || socialized_loss_ratio >= PERCENTAGE_PRECISION_I128
+        {
+            return Ok(0);
+        }
+
+        payout
+            .safe_mul(socialized_loss_ratio)?
+            .safe_div(PERCENTAGE_PRECISION_I128.safe_sub(socialized_loss_ratio)?)
+    }
+
+    /// Fraction of the net pnl owed to users that the pnl tokens available can't cover, 0 once they
+    /// cover it
+    pub fn calculate_socialized_loss_ratio(
+        &self,
+        net_user_pnl: i128,
+        pnl_tokens_available: u128,
+    ) -> DriftResult<u32> {
+        let pnl_tokens_available = pnl_tokens_available.cast::<i128>()?;
+        if net_user_pnl <= pnl_tokens_available {
+            return Ok(0);
+        }
+
+        net_user_pnl
+            .safe_sub(pnl_tokens_available)?
+            .safe_mul(PERCENTAGE_PRECISION_I128)?
+            .safe_div_ceil(net_user_pnl)?
+            .min(PERCENTAGE_PRECISION_I128)
+            .cast()
+    }
+
     pub fn is_operation_paused(&self, operation: PerpOperation) -> bool {
         PerpOperation::is_operation_paused(self.paused_operations, operation)
     }
@@ -393,15 +473,11 @@ impl PerpMarket {
             return Ok(0); // no liability weight on size
         }
 
-        let default_margin_ratio = match margin_type {
-            MarginRequirementType::Initial => self.margin_ratio_initial,
-            MarginRequirementType::Fill => {
-                self.margin_ratio_initial
-                    .safe_add(self.margin_ratio_maintenance)?
-                    / 2
-            }
-            MarginRequirementType::Maintenance => self.margin_ratio_maintenance,
-        };
+        let default_margin_ratio = resolve_margin_ratio(
+            self.margin_ratio_initial,
+            self.margin_ratio_maintenance,
+            margin_type,
+        );
 
         let size_adj_margin_ratio = calculate_size_premium_liability_weight(
             size,
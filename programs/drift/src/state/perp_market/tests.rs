@@ -40,3 +40,37 @@ mod amm {
         assert_eq!(discount, 10000000); // $1
     }
 }
+
+mod market_status {
+    use crate::state::perp_market::MarketStatus;
+
+    #[test]
+    fn settlement_predicates() {
+        let cases = [
+            (MarketStatus::Initialized, false, false),
+            (MarketStatus::Active, true, false),
+            (MarketStatus::FundingPaused, false, false),
+            (MarketStatus::AmmPaused, false, false),
+            (MarketStatus::FillPaused, false, false),
+            (MarketStatus::WithdrawPaused, false, false),
+            (MarketStatus::ReduceOnly, true, false),
+            (MarketStatus::Settlement, false, true),
+            (MarketStatus::Delisted, false, false),
+        ];
+
+        for (status, allows_pnl_settlement, allows_expired_settlement) in cases {
+            assert_eq!(
+                status.allows_pnl_settlement(),
+                allows_pnl_settlement,
+                "{:?}",
+                status
+            );
+            assert_eq!(
+                status.allows_expired_settlement(),
+                allows_expired_settlement,
+                "{:?}",
+                status
+            );
+        }
+    }
+}
@@ -117,7 +117,12 @@ pub struct User {
     pub open_auctions: u8,
     /// Whether or not user has open order with auction
     pub has_open_auction: bool,
-    pub padding: [u8; 21],
+    /// When each perp position (same index as perp_positions) last settled pnl, stored as seconds
+    /// before last_settle_pnl_ts plus one. 0 if it hasn't settled in the u8::MAX seconds before it
+    pub last_settle_pnl_ts_offsets: [u8; 8],
+    pub padding1: [u8; 5],
+    /// The last time the user settled pnl in any perp market
+    pub last_settle_pnl_ts: i64,
 }
 
 impl User {
@@ -236,10 +241,48 @@ impl User {
         market_index: u16,
     ) -> DriftResult<&mut PerpPosition> {
         let position_index = get_position_index(&self.perp_positions, market_index)
-            .or_else(|_| add_new_position(&mut self.perp_positions, market_index))?;
+            .or_else(|_| self.add_perp_position(market_index))?;
         Ok(&mut self.perp_positions[position_index])
     }
 
+    /// add_new_position that also clears the settle pnl time left by the slot's previous market
+    pub fn add_perp_position(&mut self, market_index: u16) -> DriftResult<usize> {
+        let position_index = add_new_position(&mut self.perp_positions, market_index)?;
+        self.last_settle_pnl_ts_offsets[position_index] = 0;
+        Ok(position_index)
+    }
+
+    /// None if the perp position hasn't settled pnl in the u8::MAX seconds before last_settle_pnl_ts
+    pub fn get_last_settle_pnl_ts(&self, position_index: usize) -> DriftResult<Option<i64>> {
+        let offset = self.last_settle_pnl_ts_offsets[position_index];
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let seconds_before = offset.safe_sub(1)?.cast::<i64>()?;
+        Ok(Some(self.last_settle_pnl_ts.safe_sub(seconds_before)?))
+    }
+
+    pub fn update_last_settle_pnl_ts(&mut self, position_index: usize, now: i64) -> DriftResult {
+        let elapsed = now.safe_sub(self.last_settle_pnl_ts)?.max(0).unsigned_abs();
+
+        for offset in self.last_settle_pnl_ts_offsets.iter_mut() {
+            if *offset != 0 {
+                let seconds_before = offset.safe_sub(1)?.cast::<u64>()?.saturating_add(elapsed);
+                *offset = if seconds_before < u8::MAX as u64 {
+                    seconds_before.safe_add(1)?.cast()?
+                } else {
+                    0
+                };
+            }
+        }
+
+        self.last_settle_pnl_ts_offsets[position_index] = 1;
+        self.last_settle_pnl_ts = now;
+
+        Ok(())
+    }
+
     pub fn get_order_index(&self, order_id: u32) -> DriftResult<usize> {
         self.orders
             .iter()
@@ -1065,7 +1108,10 @@ pub struct Order {
     pub trigger_condition: OrderTriggerCondition,
     /// How many slots the auction lasts
     pub auction_duration: u8,
-    pub padding: [u8; 3],
+    /// Whether fills are margined against the user's cross or isolated collateral
+    /// Orders are placed as Cross until isolated margin is supported
+    pub collateral_bucket: CollateralBucket,
+    pub padding: [u8; 2],
 }
 
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug)]
@@ -1079,6 +1125,11 @@ impl Order {
         (self.max_ts - now).max(0)
     }
 
+    /// orders with max_ts == 0 never expire
+    pub fn has_expired(self, now: i64) -> bool {
+        self.max_ts != 0 && now > self.max_ts
+    }
+
     pub fn has_oracle_price_offset(self) -> bool {
         self.oracle_price_offset != 0
     }
@@ -1322,7 +1373,8 @@ impl Default for Order {
             auction_end_price: 0,
             auction_duration: 0,
             max_ts: 0,
-            padding: [0; 3],
+            collateral_bucket: CollateralBucket::Cross,
+            padding: [0; 2],
         }
     }
 }
@@ -1369,6 +1421,18 @@ impl Default for OrderTriggerCondition {
     }
 }
 
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
+pub enum CollateralBucket {
+    Cross,
+    Isolated,
+}
+
+impl Default for CollateralBucket {
+    fn default() -> Self {
+        CollateralBucket::Cross
+    }
+}
+
 #[derive(Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Debug, Eq)]
 pub enum MarketType {
     Spot,
@@ -626,6 +626,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -651,6 +652,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -676,6 +678,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -723,6 +726,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
 
         let OrderFillSimulation {
@@ -749,6 +753,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
 
         let OrderFillSimulation {
@@ -775,6 +780,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
 
         let OrderFillSimulation {
@@ -823,6 +829,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -848,6 +855,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -873,6 +881,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -920,6 +929,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -945,6 +955,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -970,6 +981,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1017,6 +1029,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1042,6 +1055,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1067,6 +1081,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1114,6 +1129,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1139,6 +1155,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1164,6 +1181,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1211,6 +1229,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1236,6 +1255,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1261,6 +1281,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1308,6 +1329,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: None,
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1333,6 +1355,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(110 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1358,6 +1381,7 @@ mod get_worst_case_fill_simulation {
         let strict_price = StrictOraclePrice {
             current: oracle_price_data.price,
             twap_5min: Some(90 * PRICE_PRECISION_I64),
+            confidence: 0,
         };
         let OrderFillSimulation {
             token_amount: worst_case_token_amount,
@@ -1940,3 +1964,51 @@ mod get_user_stats_age_ts {
         assert_eq!(age, 0);
     }
 }
+
+mod last_settle_pnl_ts {
+    use crate::state::user::{PerpPosition, User};
+
+    #[test]
+    fn tracked_per_position() {
+        let mut user = User::default();
+        assert_eq!(user.get_last_settle_pnl_ts(0).unwrap(), None);
+
+        user.update_last_settle_pnl_ts(0, 100).unwrap();
+        user.update_last_settle_pnl_ts(1, 130).unwrap();
+
+        assert_eq!(user.get_last_settle_pnl_ts(0).unwrap(), Some(100));
+        assert_eq!(user.get_last_settle_pnl_ts(1).unwrap(), Some(130));
+        assert_eq!(user.get_last_settle_pnl_ts(2).unwrap(), None);
+        assert_eq!(user.last_settle_pnl_ts, 130);
+    }
+
+    #[test]
+    fn dropped_after_u8_max_seconds() {
+        let mut user = User::default();
+
+        user.update_last_settle_pnl_ts(0, 100).unwrap();
+        user.update_last_settle_pnl_ts(1, 100 + u8::MAX as i64 - 1)
+            .unwrap();
+        assert_eq!(user.get_last_settle_pnl_ts(0).unwrap(), Some(100));
+
+        user.update_last_settle_pnl_ts(1, 100 + u8::MAX as i64)
+            .unwrap();
+        assert_eq!(user.get_last_settle_pnl_ts(0).unwrap(), None);
+        assert_eq!(
+            user.get_last_settle_pnl_ts(1).unwrap(),
+            Some(100 + u8::MAX as i64)
+        );
+    }
+
+    #[test]
+    fn cleared_for_new_position() {
+        let mut user = User::default();
+
+        user.update_last_settle_pnl_ts(0, 100).unwrap();
+        user.perp_positions[0] = PerpPosition::default();
+
+        let position_index = user.add_perp_position(1).unwrap();
+        assert_eq!(position_index, 0);
+        assert_eq!(user.get_last_settle_pnl_ts(0).unwrap(), None);
+    }
+}
@@ -3127,6 +3127,126 @@ export class AdminClient extends DriftClient {
 		);
 	}
 
+	public async updatePerpMarketSettleCooldown(
+		perpMarketIndex: number,
+		minSettleInterval: number,
+		settleCooldownPnlThreshold: BN
+	): Promise<TransactionSignature> {
+		const updatePerpMarketSettleCooldownIx =
+			await this.getUpdatePerpMarketSettleCooldownIx(
+				perpMarketIndex,
+				minSettleInterval,
+				settleCooldownPnlThreshold
+			);
+
+		const tx = await this.buildTransaction(updatePerpMarketSettleCooldownIx);
+
+		const { txSig } = await this.sendTransaction(tx, [], this.opts);
+
+		return txSig;
+	}
+
+	public async getUpdatePerpMarketSettleCooldownIx(
+		perpMarketIndex: number,
+		minSettleInterval: number,
+		settleCooldownPnlThreshold: BN
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.updatePerpMarketSettleCooldown(
+			minSettleInterval,
+			settleCooldownPnlThreshold,
+			{
+				accounts: {
+					admin: this.isSubscribed
+						? this.getStateAccount().admin
+						: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					perpMarket: await getPerpMarketPublicKey(
+						this.program.programId,
+						perpMarketIndex
+					),
+				},
+			}
+		);
+	}
+
+	public async updatePerpMarketSocializedLossEnabled(
+		perpMarketIndex: number,
+		socializedLossEnabled: boolean
+	): Promise<TransactionSignature> {
+		const updatePerpMarketSocializedLossEnabledIx =
+			await this.getUpdatePerpMarketSocializedLossEnabledIx(
+				perpMarketIndex,
+				socializedLossEnabled
+			);
+
+		const tx = await this.buildTransaction(
+			updatePerpMarketSocializedLossEnabledIx
+		);
+
+		const { txSig } = await this.sendTransaction(tx, [], this.opts);
+
+		return txSig;
+	}
+
+	public async getUpdatePerpMarketSocializedLossEnabledIx(
+		perpMarketIndex: number,
+		socializedLossEnabled: boolean
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.updatePerpMarketSocializedLossEnabled(
+			socializedLossEnabled,
+			{
+				accounts: {
+					admin: this.isSubscribed
+						? this.getStateAccount().admin
+						: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					perpMarket: await getPerpMarketPublicKey(
+						this.program.programId,
+						perpMarketIndex
+					),
+				},
+			}
+		);
+	}
+
+	public async updatePerpMarketUseTwapForExpiry(
+		perpMarketIndex: number,
+		useTwapForExpiry: boolean
+	): Promise<TransactionSignature> {
+		const updatePerpMarketUseTwapForExpiryIx =
+			await this.getUpdatePerpMarketUseTwapForExpiryIx(
+				perpMarketIndex,
+				useTwapForExpiry
+			);
+
+		const tx = await this.buildTransaction(updatePerpMarketUseTwapForExpiryIx);
+
+		const { txSig } = await this.sendTransaction(tx, [], this.opts);
+
+		return txSig;
+	}
+
+	public async getUpdatePerpMarketUseTwapForExpiryIx(
+		perpMarketIndex: number,
+		useTwapForExpiry: boolean
+	): Promise<TransactionInstruction> {
+		return await this.program.instruction.updatePerpMarketUseTwapForExpiry(
+			useTwapForExpiry,
+			{
+				accounts: {
+					admin: this.isSubscribed
+						? this.getStateAccount().admin
+						: this.wallet.publicKey,
+					state: await this.getStatePublicKey(),
+					perpMarket: await getPerpMarketPublicKey(
+						this.program.programId,
+						perpMarketIndex
+					),
+				},
+			}
+		);
+	}
+
 	public async updateSpotMarketFeeAdjustment(
 		perpMarketIndex: number,
 		feeAdjustment: number
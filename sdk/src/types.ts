@@ -198,6 +198,11 @@ export class OrderTriggerCondition {
 	static readonly TRIGGERED_BELOW = { triggeredBelow: {} }; // below condition has been triggered
 }
 
+export class CollateralBucket {
+	static readonly CROSS = { cross: {} };
+	static readonly ISOLATED = { isolated: {} };
+}
+
 export class SpotFulfillmentType {
 	static readonly EXTERNAL = { external: {} };
 	static readonly MATCH = { match: {} };
@@ -509,6 +514,44 @@ export type SettlePnlRecord = {
 	quoteEntryAmount: BN;
 	settlePrice: BN;
 	explanation: SettlePnlExplanation;
+	pnlPoolBalanceAfter: BN;
+	fee: BN;
+	settledPnlAfter: BN;
+	version: number;
+};
+
+export type SocializedLossRecord = {
+	ts: BN;
+	user: PublicKey;
+	marketIndex: number;
+	socializedLoss: BN;
+	socializedLossRatioBefore: number;
+	socializedLossRatioAfter: number;
+};
+
+export type OracleInvalidRecord = {
+	ts: BN;
+	user: PublicKey;
+	marketType: MarketType;
+	marketIndex: number;
+};
+
+export class MarginRequirementType {
+	static readonly INITIAL = { initial: {} };
+	static readonly FILL = { fill: {} };
+	static readonly MAINTENANCE = { maintenance: {} };
+}
+
+export type MarginRequirementRecord = {
+	ts: BN;
+	user: PublicKey;
+	marginRequirementType: MarginRequirementType;
+	totalCollateral: BN;
+	marginRequirement: BN;
+	marginRequirementPlusBuffer: BN;
+	numSpotLiabilities: number;
+	numPerpLiabilities: number;
+	allOraclesValid: boolean;
 };
 
 export type OrderRecord = {
@@ -622,6 +665,11 @@ export type PerpMarketAccount = {
 	quoteSpotMarketIndex: number;
 	feeAdjustment: number;
 	pausedOperations: number;
+	socializedLossRatio: number;
+	settleCooldownPnlThreshold: BN;
+	minSettleInterval: number;
+	socializedLossEnabled: boolean;
+	useTwapForExpiry: boolean;
 };
 
 export type HistoricalOracleData = {
@@ -888,6 +936,8 @@ export type UserAccount = {
 	hasOpenOrder: boolean;
 	openAuctions: number;
 	hasOpenAuction: boolean;
+	lastSettlePnlTsOffsets: number[];
+	lastSettlePnlTs: BN;
 };
 
 export type SpotPosition = {
@@ -925,6 +975,7 @@ export type Order = {
 	auctionStartPrice: BN;
 	auctionEndPrice: BN;
 	maxTs: BN;
+	collateralBucket: CollateralBucket;
 };
 
 export type OrderParams = {